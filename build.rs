@@ -14,7 +14,9 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
-use crate::config::Config;
+use crate::config::{
+    ComboConfig, ComboKeyConfig, Config, MacroConfig, MacroStepConfig, SplitTransport,
+};
 
 #[path = "./config.rs"]
 mod config;
@@ -27,16 +29,57 @@ fn main() {
     let user_config = include_str!("user_config.toml");
     let user_config: Config = toml::from_str(user_config).unwrap();
 
+    // both halves link to a receiver dongle instead of one of them to the hosts
+    let dongle = user_config.ble.split && user_config.ble.dongle;
+    if env::var_os("CARGO_FEATURE_RECEIVER").is_some() && !dongle {
+        panic!("the receiver feature builds the dongle of a split keyboard with `dongle = true`");
+    }
+
     let const_declarations = [
         const_declaration!(pub(crate) NAME = user_config.ble.name),
         const_declaration!(pub(crate) SPLIT = user_config.ble.split),
+        const_declaration!(pub(crate) DONGLE = dongle),
+        const_declaration!(pub(crate) PROFILES = user_config.ble.profiles),
+        const_declaration!(pub(crate) USB_PID = user_config.usb.pid),
         const_declaration!(pub(crate) ROWS = user_config.matrix.rows),
         const_declaration!(pub(crate) COLS = user_config.matrix.cols),
         const_declaration!(pub(crate) KEY_DEBOUNCE = user_config.debounce.key_debounce),
         const_declaration!(pub(crate) LAYERS = user_config.keymap.layers),
+        const_declaration!(pub(crate) TAPPING_TERM = user_config.keymap.tapping_term),
+        const_declaration!(pub(crate) NKRO = user_config.keymap.nkro),
+        key_pos_declaration("PERIPHERAL_KEY", user_config.ble.peripheral_key),
+        key_pos_declaration("CENTRAL_KEY", user_config.ble.central_key),
+        combos_declaration(&user_config.combos),
+        macros_declaration(&user_config.macros),
     ]
     .join("\n");
 
+    // leave the split link out of unibody keyboards, and the unused transports out of split ones
+    println!("cargo:rustc-check-cfg=cfg(split)");
+    println!("cargo:rustc-check-cfg=cfg(split_ble)");
+    println!("cargo:rustc-check-cfg=cfg(split_uart)");
+    println!("cargo:rustc-check-cfg=cfg(split_esb)");
+    println!("cargo:rustc-check-cfg=cfg(dongle)");
+    if user_config.ble.split {
+        println!("cargo:rustc-cfg=split");
+
+        if dongle {
+            // the halves reach the dongle over the ble split link
+            assert!(
+                user_config.ble.split_transport == SplitTransport::Ble,
+                "the halves of a dongle setup link to the dongle over ble"
+            );
+            println!("cargo:rustc-cfg=dongle");
+            println!("cargo:rustc-cfg=split_ble");
+        } else {
+            match user_config.ble.split_transport {
+                SplitTransport::Ble => println!("cargo:rustc-cfg=split_ble"),
+                SplitTransport::Uart => println!("cargo:rustc-cfg=split_uart"),
+                SplitTransport::Esb => println!("cargo:rustc-cfg=split_esb"),
+            }
+        }
+    }
+
     // store it in the destination file
 
     fs::write(&dest_path, const_declarations).unwrap();
@@ -55,8 +98,108 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=user_config.toml");
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 }
+
+/// Generate a matrix position from a `[row, col]` entry
+fn key_pos_declaration(name: &str, [row, col]: [u8; 2]) -> String {
+    format!(
+        "pub(crate) const {name}: crate::matrix::KeyPos = crate::matrix::KeyPos {{ row: {row}, col: {col} }};"
+    )
+}
+
+/// Generate the static combo table from the `[[combos]]` entries
+fn combos_declaration(combos: &[ComboConfig]) -> String {
+    let combos: Vec<String> = combos
+        .iter()
+        .map(|combo| {
+            assert!(
+                combo.keys.len() >= 2,
+                "a combo needs at least two trigger keys"
+            );
+
+            let keys: Vec<String> = combo
+                .keys
+                .iter()
+                .map(|key| match key {
+                    ComboKeyConfig::Code(kc) => {
+                        format!("crate::combo::ComboKey::Code(crate::keycodes::KC::{kc})")
+                    }
+                    ComboKeyConfig::Position([row, col]) => format!(
+                        "crate::combo::ComboKey::Position(crate::matrix::KeyPos {{ row: {row}, col: {col} }})"
+                    ),
+                })
+                .collect();
+
+            let action = match combo.output.as_slice() {
+                [kc] => format!("crate::action::Action::Key(crate::keycodes::KC::{kc})"),
+                kcs => format!(
+                    "crate::action::Action::Keys(&[{}])",
+                    kcs.iter()
+                        .map(|kc| format!("crate::keycodes::KC::{kc}"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            };
+
+            format!(
+                "crate::combo::Combo {{ keys: &[{}], action: {}, timeout: {}, layers: &{:?} }}",
+                keys.join(", "),
+                action,
+                combo.timeout,
+                combo.layers
+            )
+        })
+        .collect();
+
+    format!(
+        "pub(crate) const COMBOS: [crate::combo::Combo; {}] = [{}];",
+        combos.len(),
+        combos.join(", ")
+    )
+}
+
+/// Generate the static macro table from the `[[macros]]` entries
+fn macros_declaration(macros: &[MacroConfig]) -> String {
+    let macros: Vec<String> = macros
+        .iter()
+        .map(|macro_config| {
+            let steps: Vec<String> = macro_config
+                .steps
+                .iter()
+                .map(|step| match step {
+                    MacroStepConfig::Press(kc) => {
+                        format!("crate::macros::MacroStep::Press(crate::keycodes::KC::{kc})")
+                    }
+                    MacroStepConfig::Release(kc) => {
+                        format!("crate::macros::MacroStep::Release(crate::keycodes::KC::{kc})")
+                    }
+                    MacroStepConfig::Tap(kc) => {
+                        format!("crate::macros::MacroStep::Tap(crate::keycodes::KC::{kc})")
+                    }
+                    MacroStepConfig::Delay(ms) => format!("crate::macros::MacroStep::Delay({ms})"),
+                    MacroStepConfig::Text(text) => {
+                        assert!(text.is_ascii(), "macro text must be ASCII: {text:?}");
+                        format!("crate::macros::MacroStep::Text({text:?})")
+                    }
+                })
+                .collect();
+
+            format!(
+                "crate::macros::Macro {{ steps: &[{}], delay: {} }}",
+                steps.join(", "),
+                macro_config.delay
+            )
+        })
+        .collect();
+
+    format!(
+        "pub(crate) const MACROS: [crate::macros::Macro; {}] = [{}];",
+        macros.len(),
+        macros.join(", ")
+    )
+}
@@ -4,6 +4,40 @@ use serde::Deserialize;
 pub struct BleConfig {
     pub name: String,
     pub split: bool,
+    #[serde(default = "default_profiles")]
+    pub profiles: usize,
+    #[serde(default = "default_peripheral_key")]
+    pub peripheral_key: [u8; 2],
+    #[serde(default = "default_central_key")]
+    pub central_key: [u8; 2],
+    #[serde(default)]
+    pub split_transport: SplitTransport,
+    #[serde(default)]
+    pub dongle: bool,
+}
+
+/// Link between the halves of a split keyboard
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitTransport {
+    #[default]
+    Ble,
+    /// Serial link over a TRRS cable
+    Uart,
+    /// Enhanced ShockBurst radio link in timeslots next to the ble host link
+    Esb,
+}
+
+fn default_profiles() -> usize {
+    1
+}
+
+fn default_peripheral_key() -> [u8; 2] {
+    [0, 1]
+}
+
+fn default_central_key() -> [u8; 2] {
+    [0, 2]
 }
 
 #[derive(Deserialize, Debug)]
@@ -22,12 +56,63 @@ pub struct DebounceConfig {
 #[derive(Deserialize, Debug)]
 pub struct KeymapConfig {
     pub layers: usize,
+    pub tapping_term: u64,
+    #[serde(default)]
+    pub nkro: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ComboKeyConfig {
+    Code(String),
+    Position([u8; 2]),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ComboConfig {
+    pub keys: Vec<ComboKeyConfig>,
+    pub output: Vec<String>,
+    pub timeout: u64,
+    #[serde(default)]
+    pub layers: Vec<u8>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MacroStepConfig {
+    Press(String),
+    Release(String),
+    Tap(String),
+    Delay(u64),
+    Text(String),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MacroConfig {
+    pub steps: Vec<MacroStepConfig>,
+    #[serde(default = "default_macro_delay")]
+    pub delay: u64,
+}
+
+fn default_macro_delay() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UsbConfig {
+    /// Product id under the pid.codes vendor id
+    pub pid: u16,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub ble: BleConfig,
+    pub usb: UsbConfig,
     pub matrix: MatrixConfig,
     pub debounce: DebounceConfig,
     pub keymap: KeymapConfig,
+    #[serde(default)]
+    pub combos: Vec<ComboConfig>,
+    #[serde(default)]
+    pub macros: Vec<MacroConfig>,
 }
@@ -0,0 +1,159 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+use crate::keycodes::KC;
+
+/// Action bound to a key position in the keymap
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Action {
+    /// Plain keycode, resolved the moment the key is pressed
+    Key(KC),
+    /// Several keycodes pressed and released together
+    Keys(&'static [KC]),
+    /// Keycode pressed together with the modifier mask `mods`, see `KC::get_modifier`
+    Modified { mods: u8, kc: KC },
+    /// Tap for the `tap` keycode, hold for the `hold` modifier
+    ModTap { hold: KC, tap: KC },
+    /// Tap for the `tap` keycode, hold to enable `layer`
+    LayerTap { layer: u8, tap: KC },
+    /// Enable the layer while the key is held
+    Momentary(u8),
+    /// Toggle the layer on release
+    Toggle(u8),
+    /// Enable the layer and disable every other layer except the default one
+    To(u8),
+    /// Set the default layer
+    DefaultLayer(u8),
+    /// Fall through to the key on the next active layer below
+    Transparent,
+    /// Play the macro with the given index
+    Macro(u8),
+    /// Switch to the host profile with the given index
+    Profile(u8),
+    /// Clear the bond of the current host profile
+    ProfileClear,
+    /// Clear the bonds of every host profile
+    ProfileClearAll,
+    /// Do nothing
+    NoOp,
+}
+
+impl Default for Action {
+    fn default() -> Self {
+        Action::Key(KC::default())
+    }
+}
+
+impl Action {
+    /// Whether the action waits for the tapping term to decide between tap and hold
+    pub fn is_tap_hold(&self) -> bool {
+        matches!(self, Action::ModTap { .. } | Action::LayerTap { .. })
+    }
+}
+
+/// Transparent key, short name to keep the keymap aligned
+pub const TRNS: Action = Action::Transparent;
+
+/// No-op key, short name to keep the keymap aligned
+pub const NO: Action = Action::NoOp;
+
+/// Clear the current host profile
+pub const PF_CLR: Action = Action::ProfileClear;
+
+/// Clear every host profile
+pub const PF_CLR_ALL: Action = Action::ProfileClearAll;
+
+/// Plain keycode
+pub const fn k(kc: KC) -> Action {
+    Action::Key(kc)
+}
+
+/// Keycode wrapped with the modifier mask `mods`, e.g.
+/// `wm(KC::LCtrl.get_modifier() | KC::LShift.get_modifier(), KC::Tab)`
+pub const fn wm(mods: u8, kc: KC) -> Action {
+    Action::Modified { mods, kc }
+}
+
+/// Keycode with left control, `lctl(KC::Cc)`
+pub const fn lctl(kc: KC) -> Action {
+    wm(KC::LCtrl.get_modifier(), kc)
+}
+
+/// Keycode with left shift, `lsft(KC::K9)` for `(`
+pub const fn lsft(kc: KC) -> Action {
+    wm(KC::LShift.get_modifier(), kc)
+}
+
+/// Keycode with left alt
+pub const fn lalt(kc: KC) -> Action {
+    wm(KC::LAlt.get_modifier(), kc)
+}
+
+/// Keycode with left gui
+pub const fn lgui(kc: KC) -> Action {
+    wm(KC::LGUI.get_modifier(), kc)
+}
+
+/// Keycode with right control
+pub const fn rctl(kc: KC) -> Action {
+    wm(KC::RCtrl.get_modifier(), kc)
+}
+
+/// Keycode with right shift
+pub const fn rsft(kc: KC) -> Action {
+    wm(KC::RShift.get_modifier(), kc)
+}
+
+/// Keycode with right alt (AltGr)
+pub const fn ralt(kc: KC) -> Action {
+    wm(KC::RAlt.get_modifier(), kc)
+}
+
+/// Keycode with right gui
+pub const fn rgui(kc: KC) -> Action {
+    wm(KC::RGUI.get_modifier(), kc)
+}
+
+/// Mod-tap: tap for `tap`, hold for `modifier`
+pub const fn mt(modifier: KC, tap: KC) -> Action {
+    Action::ModTap {
+        hold: modifier,
+        tap,
+    }
+}
+
+/// Layer-tap: tap for `tap`, hold for `layer`
+pub const fn lt(layer: u8, tap: KC) -> Action {
+    Action::LayerTap { layer, tap }
+}
+
+/// Macro from `user_config.toml`
+pub const fn mc(index: u8) -> Action {
+    Action::Macro(index)
+}
+
+/// Momentary layer
+pub const fn mo(layer: u8) -> Action {
+    Action::Momentary(layer)
+}
+
+/// Toggle layer
+pub const fn tg(layer: u8) -> Action {
+    Action::Toggle(layer)
+}
+
+/// Switch to layer
+pub const fn to(layer: u8) -> Action {
+    Action::To(layer)
+}
+
+/// Set default layer
+pub const fn df(layer: u8) -> Action {
+    Action::DefaultLayer(layer)
+}
+
+/// Switch host profile
+pub const fn pf(profile: u8) -> Action {
+    Action::Profile(profile)
+}
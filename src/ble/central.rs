@@ -1,45 +1,70 @@
 #[cfg(feature = "defmt")]
-use defmt::{info, warn};
-use embassy_futures::{
-    join::join,
-    select::{select, select3},
-};
-use embassy_nrf::{
-    Peri,
-    peripherals::{P0_04, SAADC},
-};
-use embassy_time::Duration;
+use defmt::{error, info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
 use embedded_storage_async::nor_flash::NorFlash;
 use nrf_sdc::{Error, SoftdeviceController};
-use rand::{CryptoRng, RngCore};
-use static_cell::StaticCell;
 use trouble_host::{
-    Address, Host, HostResources, Stack,
+    Address, Stack,
     gatt::GattClient,
     prelude::{
-        Central, Characteristic, ConnectConfig, ConnectParams, Connection, DefaultPacketPool,
-        ScanConfig, Uuid,
+        AdStructure, AddrKind, BdAddr, Central, Characteristic, ConnectConfig, ConnectParams,
+        Connection, ConnectionEvent, DefaultPacketPool, EventHandler, LeAdvReportsIter, Runner,
+        ScanConfig, Scanner, Uuid,
     },
 };
 
-use crate::{BATTERY_LEVEL, MESSAGE_TO_PERI, battery::Battery};
-
 use crate::{
-    ble::{ble_task, get_device_address},
-    config::PERI_ADDRESS,
+    ble::{
+        BleSplitReceiver, SharedStorage, SplitFrames,
+        services::{SPLIT_SERVICE, SPLIT_TO_CENTRAL_CH, SPLIT_TO_PERIPHERAL_CH},
+        split_bond::{PairingEvent, SplitBond},
+    },
     delay_ms,
+    role::Role,
+    split::{link::SplitSender, protocol::FRAME_MAX},
+    storage::store_split_peer,
+};
+
+// the dongle only shares the connection helpers, the halves of a dongle setup never scan
+#[cfg(not(dongle))]
+use crate::{
+    battery::Battery,
+    ble::{get_device_address, split_bond::SPLIT_SLOT},
+    split::link::central_link,
+    storage::load_split_peer,
+};
+#[cfg(not(dongle))]
+use embassy_futures::join::join;
+#[cfg(not(dongle))]
+use embassy_nrf::{
+    Peri,
+    peripherals::{P0_04, SAADC},
 };
+#[cfg(not(dongle))]
+use embassy_sync::mutex::Mutex;
+#[cfg(not(dongle))]
+use rand::{CryptoRng, RngCore};
+#[cfg(not(dongle))]
+use static_cell::StaticCell;
+#[cfg(not(dongle))]
+use trouble_host::{Host, HostResources, IoCapabilities};
 
+#[cfg(not(dongle))]
 const CONNECTIONS_MAX: usize = 1;
 
+#[cfg(not(dongle))]
 const L2CAP_CHANNELS_MAX: usize = CONNECTIONS_MAX * 4;
 
+#[cfg(not(dongle))]
 type BleHostResources = HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX>;
 
 /// run ble
+#[cfg(not(dongle))]
 pub async fn ble_central_run<RNG, S>(
     sdc: SoftdeviceController<'static>,
-    mut _storage: &mut S,
+    storage: &mut S,
     rng: &mut RNG,
     p_04: Peri<'static, P0_04>,
     saadc: Peri<'static, SAADC>,
@@ -60,57 +85,193 @@ pub async fn ble_central_run<RNG, S>(
         STACK.init(
             trouble_host::new(sdc, resources)
                 .set_random_address(address)
-                .set_random_generator_seed(rng),
+                .set_random_generator_seed(rng)
+                .set_io_capabilities(IoCapabilities::NoInputNoOutput),
         )
     };
 
+    // get the bond information of the split half
+    let mut split_bond = SplitBond::load(storage, stack, SPLIT_SLOT).await;
+
     let Host {
-        mut central,
-        runner,
-        ..
+        central, runner, ..
     } = stack.build();
 
     let mut battery_level_sense = Battery::new(p_04, saadc);
 
-    let _ = join(ble_task(runner), async {
-        while let Ok(conn) = connect(&mut central).await {
-            // TODO: allow bonding
+    let storage: SharedStorage<'_, S> = Mutex::new(storage);
+
+    let _ = join(central_ble_task(runner), async {
+        let mut central = central;
+
+        loop {
+            let stored = load_split_peer(&mut **storage.lock().await, SPLIT_SLOT).await;
+
+            // pairing mode until the other half is known
+            let (target, discovered) = match stored {
+                Some(addr) => (
+                    Address {
+                        kind: AddrKind::RANDOM,
+                        addr,
+                    },
+                    false,
+                ),
+                None => {
+                    // only a peripheral half is taken, not a half of a dongle setup or another
+                    // central
+                    let (scanned, found) =
+                        discover_split_peer(central, |half| half == Some(Role::Peripheral), None)
+                            .await;
+                    central = scanned;
+                    let Some((target, _)) = found else {
+                        continue;
+                    };
+                    (target, true)
+                }
+            };
 
-            #[cfg(feature = "defmt")]
-            info!("[ble_connect] connected to peripheral");
-
-            // create client
-            let client = {
-                static CLIENT: StaticCell<
-                    GattClient<'_, SoftdeviceController<'_>, DefaultPacketPool, 10>,
-                > = StaticCell::new();
-                CLIENT.init(
-                    GattClient::<SoftdeviceController, DefaultPacketPool, 10>::new(stack, &conn)
-                        .await
-                        .expect("[ble_central] error creating client"),
-                )
+            let Ok(conn) = connect(&mut central, &[(target.kind, &target.addr)]).await else {
+                break;
             };
 
-            let _ = select3(
-                client.task(),
-                kb_tasks(client),
-                battery_level_sense.approximate(),
+            // the battery level is sensed while the level can be sent to the peripheral
+            run_split_link(
+                &conn,
+                stack,
+                &storage,
+                &mut split_bond,
+                discovered.then_some(SPLIT_SLOT),
+                async |tx, rx| {
+                    let _ = select(central_link(tx, rx), battery_level_sense.approximate()).await;
+                },
             )
             .await;
-
-            #[cfg(feature = "defmt")]
-            warn!("[ble_connect] peripheral device disconnected");
         }
     })
     .await;
 }
 
-async fn connect<'a, 'b>(
+/// Device advertising the split service and the half it announces, reported while scanning
+/// in pairing mode
+static SPLIT_PEER_FOUND: Signal<CriticalSectionRawMutex, (Address, Option<Role>)> = Signal::new();
+
+/// Advertising report handler of the pairing mode
+struct SplitScanHandler;
+
+impl EventHandler for SplitScanHandler {
+    fn on_adv_reports(&self, mut reports: LeAdvReportsIter<'_>) {
+        while let Some(Ok(report)) = reports.next() {
+            let (split, half) = advertised_split(report.data);
+            if split {
+                SPLIT_PEER_FOUND.signal((
+                    Address {
+                        kind: report.addr_kind,
+                        addr: report.addr,
+                    },
+                    half,
+                ));
+            }
+        }
+    }
+}
+
+/// Whether the split service is advertised, and the half announced in its service data
+fn advertised_split(data: &[u8]) -> (bool, Option<Role>) {
+    let mut split = false;
+    let mut half = None;
+
+    for ad in AdStructure::decode(data).flatten() {
+        match ad {
+            AdStructure::ServiceUuids16(uuids) if uuids.contains(&SPLIT_SERVICE.to_le_bytes()) => {
+                split = true
+            }
+            AdStructure::ServiceData16 { uuid, data } if uuid == SPLIT_SERVICE.to_le_bytes() => {
+                half = data.first().copied().and_then(Role::from_u8)
+            }
+            _ => {}
+        }
+    }
+
+    (split, half)
+}
+
+/// Background ble task, advertising reports are handed to the pairing mode
+pub(crate) async fn central_ble_task(
+    mut runner: Runner<'static, SoftdeviceController<'static>, DefaultPacketPool>,
+) {
+    #[cfg(feature = "defmt")]
+    info!("[ble_task] running runner");
+    loop {
+        if let Err(e) = runner.run_with_handler(&SplitScanHandler).await {
+            panic!("[ble_task] error: {:?}", e);
+        }
+    }
+}
+
+/// Pairing mode, scan for a half advertising the split service till one announcing a
+/// `wanted` half is found or the scan times out
+pub(crate) async fn discover_split_peer<'a, 'b>(
+    central: Central<'a, SoftdeviceController<'b>, DefaultPacketPool>,
+    wanted: impl Fn(Option<Role>) -> bool,
+    timeout: Option<Duration>,
+) -> (
+    Central<'a, SoftdeviceController<'b>, DefaultPacketPool>,
+    Option<(Address, Option<Role>)>,
+) {
+    #[cfg(feature = "defmt")]
+    info!("[split_discovery] scanning for the split half");
+
+    let mut scanner = Scanner::new(central);
+    SPLIT_PEER_FOUND.reset();
+
+    let found = loop {
+        match scanner.scan(&ScanConfig::default()).await {
+            // scanning stops when the session is dropped
+            Ok(_session) => {
+                let found = select(
+                    async {
+                        loop {
+                            let found = SPLIT_PEER_FOUND.wait().await;
+                            if wanted(found.1) {
+                                break found;
+                            }
+                        }
+                    },
+                    async {
+                        match timeout {
+                            Some(timeout) => Timer::after(timeout).await,
+                            None => core::future::pending().await,
+                        }
+                    },
+                )
+                .await;
+
+                match found {
+                    Either::First(found) => break Some(found),
+                    Either::Second(()) => break None,
+                }
+            }
+            Err(_e) => {
+                #[cfg(feature = "defmt")]
+                error!("[split_discovery] error scanning: {:?}", _e);
+                delay_ms(1000).await;
+            }
+        }
+    };
+
+    #[cfg(feature = "defmt")]
+    if let Some((target, _)) = &found {
+        info!("[split_discovery] found split half {}", target);
+    }
+
+    (scanner.into_inner(), found)
+}
+
+/// Connect to the first of the `targets` to advertise
+pub(crate) async fn connect<'a, 'b>(
     central: &mut Central<'a, SoftdeviceController<'b>, DefaultPacketPool>,
+    targets: &[(AddrKind, &BdAddr)],
 ) -> Result<Connection<'a, DefaultPacketPool>, Error> {
-    // address of the target split kb
-    let target = Address::random(PERI_ADDRESS);
-
     let conn_params = ConnectParams {
         min_connection_interval: Duration::from_micros(7500),
         max_connection_interval: Duration::from_micros(7500),
@@ -122,7 +283,7 @@ async fn connect<'a, 'b>(
 
     let config = ConnectConfig {
         scan_config: ScanConfig {
-            filter_accept_list: &[(target.kind, &target.addr)],
+            filter_accept_list: targets,
             ..Default::default()
         },
         connect_params: conn_params,
@@ -130,7 +291,7 @@ async fn connect<'a, 'b>(
 
     #[cfg(feature = "defmt")]
     // Connect to peripheral
-    info!("[ble_connect] connecting to peripheral {}", target);
+    info!("[ble_connect] connecting to the split half");
     loop {
         match central.connect(&config).await {
             Ok(conn) => return Ok(conn),
@@ -144,98 +305,184 @@ async fn connect<'a, 'b>(
     }
 }
 
-/// Keyboard Tasks
-async fn kb_tasks<'a>(client: &'a GattClient<'a, SoftdeviceController<'a>, DefaultPacketPool, 10>) {
+/// Encrypt the link with the split half, the central half starts the pairing
+async fn secure_split_link<S: NorFlash>(
+    conn: &Connection<'_, DefaultPacketPool>,
+    storage: &SharedStorage<'_, S>,
+    split_bond: &mut SplitBond,
+) -> bool {
+    split_bond
+        .secure(conn, storage, true, async || {
+            loop {
+                match conn.next().await {
+                    ConnectionEvent::PairingComplete {
+                        security_level,
+                        bond,
+                    } => break PairingEvent::Complete(security_level, bond),
+                    ConnectionEvent::PairingFailed(err) => break PairingEvent::Failed(err),
+                    ConnectionEvent::Disconnected { .. } => break PairingEvent::Disconnected,
+                    _ => {}
+                }
+            }
+        })
+        .await
+}
+
+/// Characteristics of the split service, the one frames are written to and the one notifying
+/// the frames of the peripheral, none if the peer does not serve them
+async fn split_characteristics(
+    client: &GattClient<'_, SoftdeviceController<'_>, DefaultPacketPool, 10>,
+) -> Option<(
+    Characteristic<[u8; FRAME_MAX]>,
+    Characteristic<[u8; FRAME_MAX]>,
+)> {
     let services = client
-        .services_by_uuid(&Uuid::new_short(0xff11))
+        .services_by_uuid(&Uuid::Uuid16(SPLIT_SERVICE.to_le_bytes()))
         .await
-        .expect("[ble_central] unable to set services");
+        .ok()?;
 
-    let service = services.first().unwrap().clone();
+    let service = services.first()?.clone();
 
-    let keyboard_characteristic: Characteristic<[u8; 6]> = client
-        .characteristic_by_uuid(&service, &Uuid::new_short(0xff22))
+    let to_peripheral_characteristic: Characteristic<[u8; FRAME_MAX]> = client
+        .characteristic_by_uuid(
+            &service,
+            &Uuid::Uuid16(SPLIT_TO_PERIPHERAL_CH.to_le_bytes()),
+        )
         .await
-        .expect("[ble_central] unable to set characteristic");
+        .ok()?;
 
-    let battery_characteristic: Characteristic<u8> = client
-        .characteristic_by_uuid(&service, &Uuid::new_short(0xff33))
+    let to_central_characteristic: Characteristic<[u8; FRAME_MAX]> = client
+        .characteristic_by_uuid(&service, &Uuid::Uuid16(SPLIT_TO_CENTRAL_CH.to_le_bytes()))
         .await
-        .expect("[ble_central] unable to set characteristic");
+        .ok()?;
+
+    Some((to_peripheral_characteristic, to_central_characteristic))
+}
+
+/// Run the split link with a connected split half till it leaves, over an encrypted link and
+/// the split service of the half. The address of a half found in pairing mode is stored in
+/// `new_peer_slot` once the link is encrypted, returns whether it was
+pub(crate) async fn run_split_link<S: NorFlash>(
+    conn: &Connection<'_, DefaultPacketPool>,
+    stack: &Stack<'_, SoftdeviceController<'_>, DefaultPacketPool>,
+    storage: &SharedStorage<'_, S>,
+    split_bond: &mut SplitBond,
+    new_peer_slot: Option<u8>,
+    link: impl AsyncFnOnce(&mut BleSplitSender<'_, '_>, &mut BleSplitReceiver<'_>),
+) -> bool {
+    // the split service is only served over an encrypted link
+    if !secure_split_link(conn, storage, split_bond).await {
+        conn.disconnect();
+        return false;
+    }
+
+    // reconnect to the same half from now on
+    if let Some(slot) = new_peer_slot {
+        store_split_peer(&mut **storage.lock().await, slot, &conn.peer_address())
+            .await
+            .expect("[ble_central] error storing split peer address");
+    }
+
+    #[cfg(feature = "defmt")]
+    info!("[ble_central] connected to the split half");
+
+    // a new client for every connection, the split half may leave and join again
+    let client = GattClient::<SoftdeviceController, DefaultPacketPool, 10>::new(stack, conn)
+        .await
+        .expect("[ble_central] error creating client");
+
+    let _ = select(client.task(), split_tasks(&client, link)).await;
+
+    // the link also ends when the peer lacks the split service
+    conn.disconnect();
+
+    #[cfg(feature = "defmt")]
+    warn!("[ble_central] split half disconnected");
+    true
+}
+
+/// Split link over the split service of the half, its frames are notified
+async fn split_tasks(
+    client: &GattClient<'_, SoftdeviceController<'_>, DefaultPacketPool, 10>,
+    link: impl AsyncFnOnce(&mut BleSplitSender<'_, '_>, &mut BleSplitReceiver<'_>),
+) {
+    // a peer without the split service is no split half, the connection is dropped
+    let Some((to_peripheral_characteristic, to_central_characteristic)) =
+        split_characteristics(client).await
+    else {
+        #[cfg(feature = "defmt")]
+        error!("[ble_central] split service not found on the peer");
+        return;
+    };
+
+    let frames = SplitFrames::new();
+    let mut sender = BleSplitSender {
+        client,
+        characteristic: &to_peripheral_characteristic,
+    };
 
     let _ = select(
-        split_keyboard_task(client, &keyboard_characteristic),
-        split_battery_task(client, &battery_characteristic),
+        link(&mut sender, &mut BleSplitReceiver(&frames)),
+        split_notification_task(client, &to_central_characteristic, &frames),
     )
     .await;
 }
 
-/// Battery service task
-async fn split_battery_task<'a>(
-    client: &'a GattClient<'a, SoftdeviceController<'a>, DefaultPacketPool, 10>,
-    characteristic: &Characteristic<u8>,
-) {
-    #[cfg(feature = "defmt")]
-    info!("[ble_split_battery_task] running split_battery_task");
-
-    let mut battery_percantage_receiver = BATTERY_LEVEL
-        .receiver()
-        .expect("[battery_service_task] failed to create receiver");
+/// Sending side of the ble split transport, frames are written to the peripheral
+pub(crate) struct BleSplitSender<'a, 'b> {
+    pub(crate) client: &'a GattClient<'b, SoftdeviceController<'b>, DefaultPacketPool, 10>,
+    pub(crate) characteristic: &'a Characteristic<[u8; FRAME_MAX]>,
+}
 
-    loop {
-        // wait till the battery percentage is received
-        let battery_level = battery_percantage_receiver.changed().await;
+impl SplitSender for BleSplitSender<'_, '_> {
+    async fn send(&mut self, frame: &[u8]) -> bool {
+        // the characteristic has a fixed size, the rest is padding
+        let mut buf = [0; FRAME_MAX];
+        buf[..frame.len()].copy_from_slice(frame);
 
-        match client
-            .write_characteristic_without_response(characteristic, &[battery_level])
+        match self
+            .client
+            .write_characteristic_without_response(self.characteristic, &buf)
             .await
         {
-            Ok(_) => {
-                #[cfg(feature = "defmt")]
-                info!(
-                    "[notify] battery level notified successfully: {}",
-                    battery_level
-                );
-            }
+            Ok(_) => true,
             Err(_e) => {
                 #[cfg(feature = "defmt")]
-                info!("[notify] battery level error: {}", _e);
-                break;
+                info!("[ble_split_sender] write error: {}", _e);
+                false
             }
-        };
+        }
     }
 }
 
-/// Split Keyboard service task
-async fn split_keyboard_task<'a>(
-    client: &'a GattClient<'a, SoftdeviceController<'a>, DefaultPacketPool, 10>,
-    characteristic: &Characteristic<[u8; 6]>,
+/// Split notification task, hands the frames notified by the peripheral to the split link
+async fn split_notification_task(
+    client: &GattClient<'_, SoftdeviceController<'_>, DefaultPacketPool, 10>,
+    characteristic: &Characteristic<[u8; FRAME_MAX]>,
+    frames: &SplitFrames,
 ) {
     #[cfg(feature = "defmt")]
-    info!("[ble_split_keyboard_task] running split_keyboard_task");
+    info!("[ble_split_notification_task] running split_notification_task");
 
-    let mut message_to_peri = MESSAGE_TO_PERI
-        .receiver()
-        .expect(" [ble_peripheral] maximum number of receivers has been reached");
+    let mut listener = match client.subscribe(characteristic, false).await {
+        Ok(listener) => listener,
+        Err(_e) => {
+            #[cfg(feature = "defmt")]
+            info!("[ble_split_notification_task] subscribe error: {}", _e);
+            return;
+        }
+    };
 
     loop {
-        // wait till new key_report is received from key_provision
-        let message: [u8; 6] = message_to_peri.changed().await;
+        // wait till the peripheral notifies a frame
+        let notification = listener.next().await;
+        let notification = notification.as_ref();
 
-        // write to characteristic
-        match client
-            .write_characteristic_without_response(&characteristic, &message)
-            .await
-        {
-            Ok(_) => {
-                #[cfg(feature = "defmt")]
-                info!("[ble_split_keyboard_task] sent:{:?}", message);
-            }
-            Err(_e) => {
-                #[cfg(feature = "defmt")]
-                info!("[ble_split_keyboard_task] notify error: {}", _e);
-                break;
-            }
-        };
+        let mut frame = [0; FRAME_MAX];
+        let len = notification.len().min(FRAME_MAX);
+        frame[..len].copy_from_slice(&notification[..len]);
+
+        // a full queue drops the frame, the sequence numbers tell the link
+        let _ = frames.try_send(frame);
     }
 }
@@ -0,0 +1,275 @@
+use core::cell::Cell;
+use embassy_futures::join::{join, join4};
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+use nrf_sdc::SoftdeviceController;
+use rand::{CryptoRng, RngCore};
+use static_cell::StaticCell;
+use trouble_host::{
+    Address, Host, HostResources, IoCapabilities, Stack,
+    prelude::{AddrKind, BdAddr, Central, Connection, DefaultPacketPool},
+};
+
+use crate::{
+    PROFILE_COMMAND,
+    ble::{
+        SharedStorage,
+        central::{central_ble_task, connect, discover_split_peer, run_split_link},
+        get_device_address,
+        split_bond::SplitBond,
+    },
+    config::ADVERTISING_TURN,
+    profile::ProfileCommand,
+    role::Role,
+    split::link::{dongle_link, release_half_keys},
+    storage::{factory_reset, load_split_peer},
+};
+
+const CONNECTIONS_MAX: usize = 2;
+
+const L2CAP_CHANNELS_MAX: usize = CONNECTIONS_MAX * 4;
+
+type BleHostResources = HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX>;
+
+/// Both halves, in the order of their storage slots
+const HALVES: [Role; 2] = [Role::Peripheral, Role::Central];
+
+/// Index of a half, its bond and address are stored in the slot of its role
+fn slot(half: Role) -> usize {
+    half.to_u8() as usize
+}
+
+/// run ble of the receiver dongle, a central to both halves
+pub async fn ble_dongle_run<RNG, S>(
+    sdc: SoftdeviceController<'static>,
+    storage: &mut S,
+    rng: &mut RNG,
+) where
+    RNG: RngCore + CryptoRng,
+    S: NorFlash,
+{
+    let address = get_device_address();
+
+    let resources = {
+        static RESOURCES: StaticCell<BleHostResources> = StaticCell::new();
+        RESOURCES.init(BleHostResources::new())
+    };
+
+    let stack = {
+        static STACK: StaticCell<Stack<'_, SoftdeviceController<'_>, DefaultPacketPool>> =
+            StaticCell::new();
+        STACK.init(
+            trouble_host::new(sdc, resources)
+                .set_random_address(address)
+                .set_random_generator_seed(rng)
+                .set_io_capabilities(IoCapabilities::NoInputNoOutput),
+        )
+    };
+
+    // get the bond information and the addresses of both halves
+    let mut peripheral_bond = SplitBond::load(storage, stack, Role::Peripheral.to_u8()).await;
+    let mut central_bond = SplitBond::load(storage, stack, Role::Central.to_u8()).await;
+
+    let halves = Halves::new();
+    for half in HALVES {
+        if let Some(addr) = load_split_peer(storage, half.to_u8()).await {
+            halves.peers[slot(half)].set(Some(Address {
+                kind: AddrKind::RANDOM,
+                addr,
+            }));
+            halves.stored[slot(half)].set(true);
+        }
+    }
+
+    let Host {
+        central, runner, ..
+    } = stack.build();
+
+    // the halves come and go independently of each other
+    let storage: SharedStorage<'_, S> = Mutex::new(storage);
+
+    let _ = join(
+        join4(
+            central_ble_task(runner),
+            connect_halves(central, &halves),
+            half_link_task(
+                Role::Peripheral,
+                &halves,
+                stack,
+                &storage,
+                &mut peripheral_bond,
+            ),
+            half_link_task(Role::Central, &halves, stack, &storage, &mut central_bond),
+        ),
+        factory_reset_task(&storage),
+    )
+    .await;
+}
+
+/// Erase the storage when asked to on the console, the dongle has no host profiles
+async fn factory_reset_task<S: NorFlash>(storage: &SharedStorage<'_, S>) {
+    let mut profile_command = PROFILE_COMMAND
+        .receiver()
+        .expect("[ble_dongle] unable to create profile_command receiver");
+
+    loop {
+        if profile_command.changed().await == ProfileCommand::FactoryReset {
+            factory_reset(&mut **storage.lock().await).await;
+        }
+    }
+}
+
+/// Links of the dongle to the halves, new connections are handed from the connecting task to
+/// the link task of their half, each array is indexed by the slot of the half
+struct Halves<'a> {
+    /// New connection of a half
+    conn: [Signal<NoopRawMutex, Connection<'a, DefaultPacketPool>>; 2],
+    up: [Cell<bool>; 2],
+    /// Address of a half, known from storage or found in pairing mode
+    peers: [Cell<Option<Address>>; 2],
+    /// Whether the address of a half is stored, a half found in pairing mode is stored once
+    /// it has paired
+    stored: [Cell<bool>; 2],
+    /// Signaled when a link goes down so the connecting task looks for the half again
+    down: Signal<NoopRawMutex, ()>,
+}
+
+impl Halves<'_> {
+    fn new() -> Self {
+        Self {
+            conn: [Signal::new(), Signal::new()],
+            up: [Cell::new(false), Cell::new(false)],
+            peers: [Cell::new(None), Cell::new(None)],
+            stored: [Cell::new(false), Cell::new(false)],
+            down: Signal::new(),
+        }
+    }
+
+    fn missing(&self, half: Role) -> bool {
+        !self.up[slot(half)].get()
+    }
+
+    /// Whether a missing half still has to be found in pairing mode
+    fn unknown(&self, half: Role) -> bool {
+        self.missing(half) && self.peers[slot(half)].get().is_none()
+    }
+
+    fn lost(&self, half: Role) {
+        self.up[slot(half)].set(false);
+        self.down.signal(());
+    }
+}
+
+/// Connect the missing halves, the pairing mode for unknown halves and the connection to the
+/// known ones take turns
+async fn connect_halves<'a>(
+    mut central: Central<'a, SoftdeviceController<'static>, DefaultPacketPool>,
+    halves: &Halves<'a>,
+) {
+    let mut pairing_turn = false;
+
+    loop {
+        let unknown = HALVES.into_iter().any(|half| halves.unknown(half));
+        let known: Vec<Address, 2> = HALVES
+            .into_iter()
+            .filter(|&half| halves.missing(half))
+            .filter_map(|half| halves.peers[slot(half)].get())
+            .collect();
+
+        if !unknown && known.is_empty() {
+            halves.down.wait().await;
+            continue;
+        }
+
+        pairing_turn = unknown && (known.is_empty() || !pairing_turn);
+
+        if pairing_turn {
+            // only a half announcing the role of an unknown one is taken
+            let (scanned, found) = discover_split_peer(
+                central,
+                |half| half.is_some_and(|half| halves.unknown(half)),
+                Some(Duration::from_millis(ADVERTISING_TURN)),
+            )
+            .await;
+            central = scanned;
+
+            if let Some((address, Some(half))) = found {
+                halves.peers[slot(half)].set(Some(address));
+            }
+            continue;
+        }
+
+        let targets: Vec<(AddrKind, &BdAddr), 2> = known
+            .iter()
+            .map(|address| (address.kind, &address.addr))
+            .collect();
+
+        // the pairing mode gets its turn while a half is unknown
+        let connected = select(connect(&mut central, &targets), async {
+            if unknown {
+                Timer::after_millis(ADVERTISING_TURN).await;
+            } else {
+                core::future::pending::<()>().await;
+            }
+        })
+        .await;
+
+        if let Either::First(Ok(conn)) = connected {
+            let peer = conn.peer_address();
+            let half = HALVES.into_iter().find(|&half| {
+                halves.peers[slot(half)]
+                    .get()
+                    .is_some_and(|address| address.addr == peer)
+            });
+
+            match half {
+                Some(half) => {
+                    halves.up[slot(half)].set(true);
+                    halves.conn[slot(half)].signal(conn);
+                }
+                None => conn.disconnect(),
+            }
+        }
+    }
+}
+
+/// Serve the link to a half, the keys held on it are released when it leaves
+async fn half_link_task<S: NorFlash>(
+    half: Role,
+    halves: &Halves<'_>,
+    stack: &Stack<'_, SoftdeviceController<'_>, DefaultPacketPool>,
+    storage: &SharedStorage<'_, S>,
+    split_bond: &mut SplitBond,
+) {
+    loop {
+        let conn = halves.conn[slot(half)].wait().await;
+
+        // a half found in pairing mode is stored in the slot of its role once it paired
+        let stored = halves.stored[slot(half)].get();
+        let secured = run_split_link(
+            &conn,
+            stack,
+            storage,
+            split_bond,
+            (!stored).then_some(half.to_u8()),
+            async |tx, rx| dongle_link(tx, rx, half).await,
+        )
+        .await;
+
+        if secured {
+            halves.stored[slot(half)].set(true);
+        } else if !stored {
+            // a device found in pairing mode which does not pair is forgotten
+            halves.peers[slot(half)].set(None);
+        }
+
+        // the link may end with the connection, before the split link releases the keys
+        release_half_keys(half);
+        halves.lost(half);
+    }
+}
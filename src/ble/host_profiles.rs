@@ -0,0 +1,149 @@
+#[cfg(feature = "defmt")]
+use defmt::{info, warn};
+use embedded_storage_async::nor_flash::NorFlash;
+use nrf_sdc::SoftdeviceController;
+use trouble_host::prelude::{BdAddr, DefaultPacketPool};
+use trouble_host::{BondInformation, Stack};
+
+use crate::ble::is_bonded_peer;
+use crate::profile::{HostBonds, ProfileCommand};
+use crate::storage::{
+    factory_reset, load_active_profile, load_bonding_info, remove_bonding_info,
+    store_active_profile, store_bonding_info,
+};
+use crate::{HOST_BONDS, PROFILES};
+
+/// Host profiles, each one bonded to its own host
+pub(crate) struct HostProfiles {
+    active: u8,
+    bonds: [Option<BondInformation>; PROFILES],
+}
+
+impl HostProfiles {
+    /// Load the stored bonds and the last used profile, the bonds are handed to the stack
+    pub(crate) async fn load<S: NorFlash>(
+        storage: &mut S,
+        stack: &Stack<'_, SoftdeviceController<'_>, DefaultPacketPool>,
+    ) -> Self {
+        let mut bonds = [const { None }; PROFILES];
+
+        for (profile, bond) in bonds.iter_mut().enumerate() {
+            *bond = load_bonding_info(storage, profile as u8).await;
+
+            if let Some(bond_info) = bond {
+                stack
+                    .add_bond_information(bond_info.clone())
+                    .expect("[profiles] error adding bond information");
+
+                #[cfg(feature = "defmt")]
+                info!("[profiles] loaded bond of profile {}", profile);
+            }
+        }
+
+        let active = load_active_profile(storage)
+            .await
+            .filter(|profile| (*profile as usize) < PROFILES)
+            .unwrap_or(0);
+
+        #[cfg(feature = "defmt")]
+        info!("[profiles] active profile: {}", active);
+
+        let profiles = Self { active, bonds };
+        profiles.publish();
+        profiles
+    }
+
+    /// Share the hosts bonded to the profiles, for the console
+    fn publish(&self) {
+        HOST_BONDS.sender().send(HostBonds {
+            active: self.active,
+            hosts: core::array::from_fn(|profile| {
+                self.bonds[profile]
+                    .as_ref()
+                    .map(|bond| bond.identity.bd_addr)
+            }),
+        });
+    }
+
+    pub(crate) fn active(&self) -> u8 {
+        self.active
+    }
+
+    /// Whether the active profile is bonded to a host
+    pub(crate) fn is_bonded(&self) -> bool {
+        self.bonds[self.active as usize].is_some()
+    }
+
+    /// Whether the peer may use the active profile: the bonded host of the profile,
+    /// or while the profile is free any host which is not bonded to another profile
+    pub(crate) fn accepts(&self, peer: &BdAddr) -> bool {
+        match &self.bonds[self.active as usize] {
+            Some(bond) => is_bonded_peer(bond, peer),
+            None => !self
+                .bonds
+                .iter()
+                .flatten()
+                .any(|bond| is_bonded_peer(bond, peer)),
+        }
+    }
+
+    /// Store the bond of a newly paired host in the active profile
+    pub(crate) async fn store_bond<S: NorFlash>(&mut self, storage: &mut S, bond: BondInformation) {
+        store_bonding_info(storage, self.active, &bond)
+            .await
+            .expect("[profiles] error storing bond info");
+
+        self.bonds[self.active as usize] = Some(bond);
+        self.publish();
+    }
+
+    /// Apply a profile command from the keymap
+    pub(crate) async fn apply<S: NorFlash>(
+        &mut self,
+        command: ProfileCommand,
+        storage: &mut S,
+        stack: &Stack<'_, SoftdeviceController<'_>, DefaultPacketPool>,
+    ) {
+        match command {
+            ProfileCommand::Switch(profile) => {
+                if profile as usize >= PROFILES {
+                    #[cfg(feature = "defmt")]
+                    warn!("[profiles] profile {} is not defined", profile);
+                    return;
+                }
+
+                self.active = profile;
+                store_active_profile(storage, profile)
+                    .await
+                    .expect("[profiles] error storing active profile");
+            }
+            ProfileCommand::Clear => {
+                if let Some(bond) = self.bonds[self.active as usize].take() {
+                    let _ = stack.remove_bond_information(bond.identity);
+                    remove_bonding_info(storage, self.active)
+                        .await
+                        .expect("[profiles] error removing bond info");
+                }
+            }
+            ProfileCommand::ClearAll => {
+                // the bond with the split half is kept
+                for (profile, bond) in self.bonds.iter_mut().enumerate() {
+                    if let Some(bond) = bond.take() {
+                        let _ = stack.remove_bond_information(bond.identity);
+                        remove_bonding_info(storage, profile as u8)
+                            .await
+                            .expect("[profiles] error removing bond info");
+                    }
+                }
+            }
+            ProfileCommand::FactoryReset => factory_reset(storage).await,
+        }
+        self.publish();
+
+        #[cfg(feature = "defmt")]
+        info!(
+            "[profiles] applied {:?}, active profile: {}",
+            command, self.active
+        );
+    }
+}
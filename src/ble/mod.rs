@@ -23,16 +23,50 @@ use nrf_sdc::{
 use rand::SeedableRng;
 use rand_chacha::ChaCha12Rng;
 use static_cell::StaticCell;
-use trouble_host::Address;
-use trouble_host::prelude::{DefaultPacketPool, Runner};
+use trouble_host::prelude::{BdAddr, DefaultPacketPool, Runner};
+use trouble_host::{Address, BondInformation};
 
 use crate::peripherals::BlePeri;
+use crate::role::Role;
+#[cfg(not(feature = "receiver"))]
+use crate::role::select_role;
+#[cfg(split_ble)]
+use crate::split::{
+    link::SplitReceiver,
+    protocol::{FRAME_MAX, Frame, ProtocolError},
+};
+#[cfg(not(feature = "receiver"))]
+use crate::storage::forget_split_peer;
+use crate::storage::migrate_storage;
+use crate::{DONGLE, ROLE};
+#[cfg(not(feature = "receiver"))]
+use crate::{SPLIT, ble::split_bond::SPLIT_SLOT};
+#[cfg(split_esb)]
+use crate::{
+    battery::Battery,
+    split::esb::{esb_bond, run_esb_link, start_esb_radio},
+};
+#[cfg(split_uart)]
+use crate::{battery::Battery, split::uart::run_uart_link};
+#[cfg(any(split_uart, split_esb))]
+use embassy_futures::join::join;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+#[cfg(split_ble)]
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
 
-#[cfg(feature = "central")]
+#[cfg(any(all(split_ble, not(dongle)), feature = "receiver"))]
 mod central;
-#[cfg(feature = "peripheral")]
+#[cfg(feature = "receiver")]
+mod dongle;
+#[cfg(not(feature = "receiver"))]
+mod host_profiles;
+#[cfg(not(feature = "receiver"))]
 mod peripheral;
-mod services;
+// the receiver dongle only uses the report map and the split service uuids
+#[cfg_attr(feature = "receiver", allow(dead_code))]
+pub(crate) mod services;
+pub(crate) mod split_bond;
 
 bind_interrupts!(pub struct Irqs {
     RNG => rng::InterruptHandler<RNG>;
@@ -54,11 +88,33 @@ const L2CAP_RXQ: u8 = 3;
 /// Size of L2CAP packets
 const L2CAP_MTU: usize = 251;
 
-#[cfg(feature = "central")]
-/// Default memory allocation for softdevice controller in bytes.
-const SDC_MEMORY_SIZE: usize = 2816; // bytes
-#[cfg(feature = "peripheral")]
-/// Default memory allocation for softdevice controller in bytes.
+/// Storage shared by the link tasks
+pub(crate) type SharedStorage<'a, S> = Mutex<NoopRawMutex, &'a mut S>;
+
+/// Frames received over the ble split link queued for the split link task
+#[cfg(split_ble)]
+const SPLIT_FRAMES_QUEUE: usize = 8;
+
+/// Frames received by the gatt tasks, waiting for the split link
+#[cfg(split_ble)]
+pub(crate) type SplitFrames = Channel<NoopRawMutex, [u8; FRAME_MAX], SPLIT_FRAMES_QUEUE>;
+
+/// Receiving side of the ble split transport, the link ends with the gatt task feeding it
+#[cfg(split_ble)]
+pub(crate) struct BleSplitReceiver<'a>(pub(crate) &'a SplitFrames);
+
+#[cfg(split_ble)]
+impl SplitReceiver for BleSplitReceiver<'_> {
+    // a lost connection ends the gatt tasks
+    const KEEPALIVE: bool = false;
+
+    async fn receive(&mut self) -> Option<Result<Frame, ProtocolError>> {
+        Some(Frame::decode(&self.0.receive().await))
+    }
+}
+
+/// Default memory allocation for softdevice controller in bytes,
+/// sized for the peripheral role which needs the most (the central needs 2816)
 const SDC_MEMORY_SIZE: usize = 5080; // bytes
 
 #[embassy_executor::task]
@@ -87,20 +143,22 @@ const LFCLK_CFG: mpsl_clock_lfclk_cfg_t = mpsl_clock_lfclk_cfg_t {
     skip_wait_lfclk_started: MPSL_DEFAULT_SKIP_WAIT_LFCLK_STARTED != 0,
 };
 
-/// Build SoftDevice
+/// Build SoftDevice, as a central linking to the halves or as a peripheral
 fn build_sdc<'a, const N: usize>(
+    central: bool,
     p: nrf_sdc::Peripherals<'a>,
     rng: &'a mut rng::Rng<Async>,
     mpsl: &'a MultiprotocolServiceLayer,
     mem: &'a mut sdc::Mem<N>,
 ) -> Result<SoftdeviceController<'a>, nrf_sdc::Error> {
-    if cfg!(feature = "central") {
+    if central {
         sdc::Builder::new()?
             .support_scan()?
             .support_central()?
             .support_le_2m_phy()?
             .support_phy_update_central()?
-            .central_count(1)?
+            // the dongle links to both halves
+            .central_count(if cfg!(feature = "receiver") { 2 } else { 1 })?
             .buffer_cfg(L2CAP_MTU as u16, L2CAP_MTU as u16, L2CAP_TXQ, L2CAP_RXQ)?
             .build(p, rng, mpsl, mem)
     } else {
@@ -155,9 +213,36 @@ pub async fn ble_init_run(ble_peri: BlePeri, spawner: Spawner) {
         )
     };
 
+    // run the mpsl task, the flash is accessed through it
+    spawner.must_spawn(mpsl_task(mpsl));
+
     // Use internal Flash as storage
     let mut storage = Flash::take(mpsl, ble_peri.nvmc);
 
+    // bring older storage formats to the current one first
+    migrate_storage(&mut storage)
+        .await
+        .expect("[ble] error migrating storage");
+
+    // the role decides which half connects to the hosts, the dongle runs the keymap of both
+    // halves like a peripheral half
+    #[cfg(not(feature = "receiver"))]
+    let role = {
+        let (role, picked) = select_role(&mut storage).await;
+
+        // picking the role pairs the halves anew, the other half and the bond with it are
+        // forgotten and the central scans for a half again, the dongle keeps the bond of a half
+        if picked && SPLIT && !DONGLE {
+            forget_split_peer(&mut storage, SPLIT_SLOT)
+                .await
+                .expect("[ble] error forgetting the split half");
+        }
+        role
+    };
+    #[cfg(feature = "receiver")]
+    let role = Role::Peripheral;
+    ROLE.sender().send(role);
+
     let mut sdc_rng = {
         static SDC_RNG: StaticCell<rng::Rng<'static, Async>> = StaticCell::new();
         SDC_RNG.init(rng::Rng::new(ble_peri.rng, Irqs))
@@ -170,29 +255,73 @@ pub async fn ble_init_run(ble_peri: BlePeri, spawner: Spawner) {
 
     let mut rng = ChaCha12Rng::from_rng(&mut sdc_rng).unwrap();
 
-    let sdc = build_sdc(sdc_p, sdc_rng, mpsl, sdc_mem).expect("[ble] Error building SDC");
+    // the halves of a dongle setup are both linked to by the dongle
+    let central = cfg!(feature = "receiver") || (role == Role::Central && !DONGLE);
+    let sdc = build_sdc(central, sdc_p, sdc_rng, mpsl, sdc_mem).expect("[ble] Error building SDC");
 
-    // run the mpsl task
-    spawner.must_spawn(mpsl_task(mpsl));
+    #[cfg(feature = "receiver")]
+    crate::ble::dongle::ble_dongle_run(sdc, &mut storage, &mut rng).await;
 
-    #[cfg(feature = "central")]
-    crate::ble::central::ble_central_run(
-        sdc,
-        &mut storage,
-        &mut rng,
-        ble_peri.p_04,
-        ble_peri.saadc,
-    )
-    .await;
-    #[cfg(feature = "peripheral")]
-    crate::ble::peripheral::ble_peripheral_run(
-        sdc,
-        &mut storage,
-        &mut rng,
-        ble_peri.p_04,
-        ble_peri.saadc,
-    )
-    .await;
+    // the wired and the esb split links run next to whatever the half does over ble
+    #[cfg(split_uart)]
+    let split_link = run_uart_link(role, ble_peri.split_peri);
+    // the halves pair over the radio before anything else when they never did
+    #[cfg(split_esb)]
+    let split_link = {
+        start_esb_radio(spawner, role, &mut rng);
+        let bond = esb_bond(role, &mut storage, &mut rng).await;
+        run_esb_link(role, bond)
+    };
+
+    #[cfg(not(feature = "receiver"))]
+    match role {
+        #[cfg(all(split_ble, not(dongle)))]
+        Role::Central => {
+            crate::ble::central::ble_central_run(
+                sdc,
+                &mut storage,
+                &mut rng,
+                ble_peri.p_04,
+                ble_peri.saadc,
+            )
+            .await
+        }
+        // the central half of a wired or an esb split keyboard only talks to the peripheral half
+        #[cfg(any(split_uart, split_esb))]
+        Role::Central => {
+            let mut battery_level_sense = Battery::new(ble_peri.p_04, ble_peri.saadc);
+
+            join(battery_level_sense.approximate(), split_link).await;
+        }
+        // both halves of a dongle setup link to the dongle like a peripheral half
+        #[cfg(dongle)]
+        Role::Central => {
+            crate::ble::peripheral::ble_peripheral_run(
+                sdc,
+                &mut storage,
+                &mut rng,
+                ble_peri.p_04,
+                ble_peri.saadc,
+            )
+            .await
+        }
+        #[cfg(not(split))]
+        Role::Central => unreachable!("[ble] unibody keyboards only run as peripheral"),
+        Role::Peripheral => {
+            let peripheral = crate::ble::peripheral::ble_peripheral_run(
+                sdc,
+                &mut storage,
+                &mut rng,
+                ble_peri.p_04,
+                ble_peri.saadc,
+            );
+
+            #[cfg(any(split_uart, split_esb))]
+            join(peripheral, split_link).await;
+            #[cfg(not(any(split_uart, split_esb)))]
+            peripheral.await;
+        }
+    }
 }
 
 pub fn get_device_address() -> Address {
@@ -209,3 +338,14 @@ pub fn get_device_address() -> Address {
             .expect("[addr] issue getting ble address"),
     )
 }
+
+/// Whether the peer is the bonded device, devices rotating resolvable private
+/// addresses are recognised through the stored irk
+pub(crate) fn is_bonded_peer(bond: &BondInformation, peer: &BdAddr) -> bool {
+    bond.identity.bd_addr == *peer
+        || bond
+            .identity
+            .irk
+            .as_ref()
+            .is_some_and(|irk| irk.resolve_address(peer))
+}
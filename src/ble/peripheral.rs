@@ -1,12 +1,18 @@
+use core::cell::Cell;
 #[cfg(feature = "defmt")]
 use defmt::{error, info, warn};
-use embassy_futures::join::join;
-use embassy_futures::select::{select, select4};
+use embassy_futures::join::{join, join4};
+use embassy_futures::select::{Either, select, select3};
 
 use embassy_nrf::{
     Peri,
     peripherals::{P0_04, SAADC},
 };
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::Receiver;
+use embassy_time::Timer;
 use embedded_storage_async::nor_flash::NorFlash;
 use nrf_sdc::Error;
 use nrf_sdc::SoftdeviceController;
@@ -26,21 +32,44 @@ use trouble_host::{Address, BleHostError, Host, Stack};
 use trouble_host::{HostResources, IoCapabilities};
 
 use crate::battery::Battery;
-use crate::ble::ble_task;
 use crate::ble::get_device_address;
-use crate::ble::services::SPLIT_SERVICE;
-use crate::config::MATRIX_KEYS_BUFFER;
-use crate::matrix::KeyPos;
-use crate::storage::{load_bonding_info, store_bonding_info};
-use crate::{BATTERY_LEVEL, MATRIX_KEYS_SPLIT};
-use crate::{COLS, NAME, SPLIT};
+use crate::ble::host_profiles::HostProfiles;
+use crate::ble::{SharedStorage, ble_task};
+use crate::config::ADVERTISING_TURN;
+use crate::leds::KeyboardLeds;
+use crate::profile::ProfileCommand;
+use crate::{BATTERY_LEVEL, KEYBOARD_LEDS, PROFILE_COMMAND};
+use crate::{DONGLE, NAME};
 
 use ssmarshal::{self, serialize};
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::ble::services::{PROTOCOL_MODE_BOOT, PROTOCOL_MODE_REPORT, Server};
+use crate::mouse::MouseReport;
+use crate::nkro::NkroReport;
+use crate::output::{Output, active_output};
+use crate::{CONSUMER_REPORT, KEY_REPORT, MOUSE_REPORT, NKRO, NKRO_ENABLED, NKRO_REPORT, delay_ms};
+
+// the split link only exists on split keyboards
+#[cfg(dongle)]
+use crate::split::link::central_link;
+#[cfg(all(split_ble, not(dongle)))]
+use crate::split::link::peripheral_link;
+#[cfg(split_ble)]
+use crate::{
+    MATRIX_KEYS_SPLIT, ROLE,
+    ble::{
+        BleSplitReceiver, SplitFrames,
+        services::SPLIT_SERVICE,
+        split_bond::{PairingEvent, SPLIT_SLOT, SplitBond},
+    },
+    config::MATRIX_KEYS_BUFFER,
+    matrix::KeyPos,
+    role::Role,
+    split::{link::SplitSender, protocol::FRAME_MAX},
+};
 
-use crate::ble::services::Server;
-use crate::{KEY_REPORT, delay_ms};
-
-const CONNECTIONS_MAX: usize = SPLIT as usize + 2;
+const CONNECTIONS_MAX: usize = cfg!(split_ble) as usize + 2;
 
 const L2CAP_CHANNELS_MAX: usize = CONNECTIONS_MAX * 4;
 
@@ -50,7 +79,7 @@ type BleHostResources = HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_
 pub async fn ble_peripheral_run<RNG, S>(
     sdc: SoftdeviceController<'static>,
     // mpsl: &'static MultiprotocolServiceLayer<'static>,
-    mut storage: &mut S,
+    storage: &mut S,
     rng: &mut RNG,
     p_04: Peri<'static, P0_04>,
     saadc: Peri<'static, SAADC>,
@@ -79,17 +108,13 @@ pub async fn ble_peripheral_run<RNG, S>(
         )
     };
 
-    // get the bond information
-    let mut bond_stored = if let Some(bond_info) = load_bonding_info(storage).await {
-        stack.add_bond_information(bond_info).unwrap();
-        #[cfg(feature = "defmt")]
-        info!("[ble] loaded bond information");
-        true
-    } else {
-        #[cfg(feature = "defmt")]
-        info!("[ble] no bond information found");
-        false
-    };
+    // get the bond information of the host profiles and of the split half
+    let mut profiles = HostProfiles::load(storage, stack).await;
+    #[cfg(split_ble)]
+    let mut split_bond = SplitBond::load(storage, stack, SPLIT_SLOT).await;
+    let mut profile_command = PROFILE_COMMAND
+        .receiver()
+        .expect("[ble] unable to create profile_command receiver");
 
     let Host {
         mut peripheral,
@@ -106,65 +131,262 @@ pub async fn ble_peripheral_run<RNG, S>(
 
     let mut battery_level_sense = Battery::new(p_04, saadc);
 
-    let _ = join(
+    // the host and the split links come and go independently of each other
+    let storage: SharedStorage<'_, S> = Mutex::new(storage);
+    let links = Links::new();
+
+    let _ = join4(
         // backgroun task
         ble_task(runner),
+        battery_level_sense.approximate(),
         // advertiser
-        async {
-            loop {
-                match advertise_split(&mut peripheral, &server).await {
-                    Ok(conn_1) => {
-                        #[cfg(feature = "defmt")]
-                        info!("[split_adv] Connected! Running service tasks");
-
-                        let _ = select(gatt_split_events_handler(&conn_1, &server), async {
-                            loop {
-                                // advertise to connect second central
-                                match advertise_hid(&mut peripheral, &server).await {
-                                    Ok(conn_2) => {
-                                        // set bondable
-                                        conn_2
-                                            .raw()
-                                            .set_bondable(!bond_stored)
-                                            .expect("[ble] error setting bondable");
-
-                                        let _ = select4(
-                                            battery_level_sense.approximate(),
-                                            gatt_hid_events_handler(
-                                                &conn_2,
-                                                &server,
-                                                &mut storage,
-                                                &mut bond_stored,
-                                            ),
-                                            battery_service_task(&conn_2, &server),
-                                            hid_kb_service_task(&conn_2, &server),
-                                        )
-                                        .await;
-                                    }
-                                    Err(_e) => {
-                                        #[cfg(feature = "defmt")]
-                                        error!("{}", _e);
-                                        delay_ms(1000).await;
-                                    }
-                                }
-                            }
-                        })
-                        .await;
+        advertise_links(&mut peripheral, &server, &links),
+        join(
+            host_link_task(
+                &links,
+                &server,
+                &storage,
+                stack,
+                &mut profiles,
+                &mut profile_command,
+            ),
+            async {
+                #[cfg(split_ble)]
+                split_link_task(&links, &server, &storage, &mut split_bond).await;
+            },
+        ),
+    )
+    .await;
+}
 
-                        #[cfg(feature = "defmt")]
-                        warn!("[split_adv] task ended");
-                    }
-                    Err(_e) => {
-                        #[cfg(feature = "defmt")]
-                        error!("{}", _e);
-                    }
+/// Links of the peripheral, new connections are handed from the advertiser to the link tasks
+struct Links<'a, 'b> {
+    /// New host connection
+    host: Signal<NoopRawMutex, GattConnection<'a, 'b, DefaultPacketPool>>,
+    host_up: Cell<bool>,
+    /// New split half connection
+    #[cfg(split_ble)]
+    split: Signal<NoopRawMutex, GattConnection<'a, 'b, DefaultPacketPool>>,
+    #[cfg(split_ble)]
+    split_up: Cell<bool>,
+    /// Signaled when a link goes down so the advertiser resumes advertising it
+    down: Signal<NoopRawMutex, ()>,
+}
+
+impl Links<'_, '_> {
+    fn new() -> Self {
+        Self {
+            host: Signal::new(),
+            host_up: Cell::new(false),
+            #[cfg(split_ble)]
+            split: Signal::new(),
+            #[cfg(split_ble)]
+            split_up: Cell::new(false),
+            down: Signal::new(),
+        }
+    }
+
+    /// Whether the split half still has to connect, never without the ble split link
+    fn split_missing(&self) -> bool {
+        #[cfg(split_ble)]
+        {
+            !self.split_up.get()
+        }
+        #[cfg(not(split_ble))]
+        {
+            false
+        }
+    }
+
+    fn host_lost(&self) {
+        self.host_up.set(false);
+        self.down.signal(());
+    }
+
+    #[cfg(split_ble)]
+    fn split_lost(&self) {
+        self.split_up.set(false);
+        self.down.signal(());
+    }
+}
+
+/// Advertise the missing links, the split and the host advertising take turns while both
+/// are missing
+async fn advertise_links<'a, 'b>(
+    peripheral: &mut Peripheral<'a, SoftdeviceController<'static>, DefaultPacketPool>,
+    server: &'b Server<'_>,
+    links: &Links<'a, 'b>,
+) {
+    let mut split_turn = false;
+
+    loop {
+        let split_missing = links.split_missing();
+        // a half of a dongle setup only links to the dongle
+        let host_missing = !DONGLE && !links.host_up.get();
+
+        if !split_missing && !host_missing {
+            links.down.wait().await;
+            continue;
+        }
+
+        split_turn = split_missing && (!host_missing || !split_turn);
+
+        #[cfg(split_ble)]
+        if split_turn {
+            let advertised = select(
+                advertise_split(peripheral, server),
+                Timer::after_millis(ADVERTISING_TURN),
+            )
+            .await;
+
+            match advertised {
+                Either::First(Ok(conn_1)) => {
+                    links.split_up.set(true);
+                    links.split.signal(conn_1);
+                }
+                Either::First(Err(_e)) => {
+                    #[cfg(feature = "defmt")]
+                    error!("{}", _e);
+                    delay_ms(1000).await;
                 }
+                Either::Second(()) => {}
             }
-        },
-    )
-    .await;
+            continue;
+        }
+
+        let advertised = select(
+            advertise_hid(peripheral, server),
+            Timer::after_millis(ADVERTISING_TURN),
+        )
+        .await;
+
+        match advertised {
+            Either::First(Ok(conn_2)) => {
+                links.host_up.set(true);
+                links.host.signal(conn_2);
+            }
+            Either::First(Err(_e)) => {
+                #[cfg(feature = "defmt")]
+                error!("{}", _e);
+                delay_ms(1000).await;
+            }
+            Either::Second(()) => {}
+        }
+    }
+}
+
+/// Serve the connected host, a profile command ends the connection so the host
+/// reconnects through the new profile
+async fn host_link_task<'b, S: NorFlash>(
+    links: &Links<'_, 'b>,
+    server: &'b Server<'_>,
+    storage: &SharedStorage<'_, S>,
+    stack: &Stack<'_, SoftdeviceController<'_>, DefaultPacketPool>,
+    profiles: &mut HostProfiles,
+    profile_command: &mut Receiver<'_, CriticalSectionRawMutex, ProfileCommand, 1>,
+) {
+    loop {
+        let conn_2 = match select(links.host.wait(), profile_command.changed()).await {
+            Either::First(conn_2) => conn_2,
+            Either::Second(command) => {
+                profiles
+                    .apply(command, &mut **storage.lock().await, stack)
+                    .await;
+                continue;
+            }
+        };
+
+        // hosts bonded to another profile are not allowed
+        if !profiles.accepts(&conn_2.raw().peer_address()) {
+            #[cfg(feature = "defmt")]
+            warn!("[ble] host rejected by profile {}", profiles.active());
+            conn_2.raw().disconnect();
+            links.host_lost();
+            continue;
+        }
+
+        // only a free profile accepts a new bond
+        conn_2
+            .raw()
+            .set_bondable(!profiles.is_bonded())
+            .expect("[ble] error setting bondable");
+
+        let connected = select(
+            select3(
+                gatt_hid_events_handler(&conn_2, server, storage, profiles),
+                battery_service_task(&conn_2, server),
+                select3(
+                    hid_kb_service_task(&conn_2, server),
+                    hid_consumer_service_task(&conn_2, server),
+                    hid_mouse_service_task(&conn_2, server),
+                ),
+            ),
+            profile_command.changed(),
+        )
+        .await;
+
+        if let Either::Second(command) = connected {
+            profiles
+                .apply(command, &mut **storage.lock().await, stack)
+                .await;
+
+            // the host reconnects through the new profile
+            conn_2.raw().disconnect();
+        }
+
+        #[cfg(feature = "defmt")]
+        warn!("[ble] host link ended");
+
+        links.host_lost();
+    }
+}
+
+#[cfg(split_ble)]
+/// Serve the connected split half, the keys held on it are released when it leaves
+async fn split_link_task<'b, S: NorFlash>(
+    links: &Links<'_, 'b>,
+    server: &'b Server<'_>,
+    storage: &SharedStorage<'_, S>,
+    split_bond: &mut SplitBond,
+) {
+    loop {
+        let conn_1 = links.split.wait().await;
+
+        // the split service is only served over an encrypted link
+        if secure_split_link(&conn_1, storage, split_bond).await {
+            #[cfg(feature = "defmt")]
+            info!("[split_adv] Connected! Running service tasks");
+
+            let frames = SplitFrames::new();
+            let mut notifier = BleSplitNotifier {
+                conn: &conn_1,
+                server,
+            };
+            let mut receiver = BleSplitReceiver(&frames);
+
+            // the dongle runs the keymap, a half of a dongle setup sends its keys to it
+            #[cfg(not(dongle))]
+            let link = peripheral_link(&mut notifier, &mut receiver);
+            #[cfg(dongle)]
+            let link = central_link(&mut notifier, &mut receiver);
+
+            let _ = select(gatt_split_events_handler(&conn_1, server, &frames), link).await;
+
+            #[cfg(feature = "defmt")]
+            warn!("[split_adv] task ended");
+        } else {
+            conn_1.raw().disconnect();
+        }
+
+        // the link may end with the connection, before the split link releases the keys
+        MATRIX_KEYS_SPLIT
+            .sender()
+            .send([KeyPos::default(); MATRIX_KEYS_BUFFER]);
+        links.split_lost();
+    }
 }
 
+#[cfg(split_ble)]
 /// Advertiser task
 async fn advertise_split<'a, 'b>(
     peripheral: &mut Peripheral<'a, SoftdeviceController<'static>, DefaultPacketPool>,
@@ -172,6 +394,13 @@ async fn advertise_split<'a, 'b>(
 ) -> Result<GattConnection<'a, 'b, DefaultPacketPool>, BleHostError<Error>> {
     let mut advertiser_data = [0; 31];
 
+    // the dongle tells the halves apart by the role they announce
+    let half = [ROLE
+        .anon_receiver()
+        .try_get()
+        .unwrap_or(Role::Peripheral)
+        .to_u8()];
+
     #[cfg(feature = "defmt")]
     info!("[split_adv] creating adStructure");
 
@@ -179,6 +408,10 @@ async fn advertise_split<'a, 'b>(
         &[
             AdStructure::Flags(BR_EDR_NOT_SUPPORTED),
             AdStructure::ServiceUuids16(&[SPLIT_SERVICE.to_le_bytes()]),
+            AdStructure::ServiceData16 {
+                uuid: SPLIT_SERVICE.to_le_bytes(),
+                data: &half,
+            },
         ],
         &mut advertiser_data[..],
     )?;
@@ -225,10 +458,10 @@ async fn advertise_hid<'a, 'b>(
     AdStructure::encode_slice(
         &[
             AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            // the split service is left out so only a half waiting for its central is discovered
             AdStructure::ServiceUuids16(&[
                 BATTERY.to_le_bytes(),
                 HUMAN_INTERFACE_DEVICE.to_le_bytes(),
-                SPLIT_SERVICE.to_le_bytes(),
             ]),
             AdStructure::CompleteLocalName(NAME.as_bytes()),
             AdStructure::Unknown {
@@ -271,17 +504,44 @@ async fn advertise_hid<'a, 'b>(
     Ok(gatt_conn)
 }
 
-/// Gatt event handelr task
+#[cfg(split_ble)]
+/// Encrypt the link with the split half, the central half starts the pairing and nothing is
+/// served till the link is encrypted
+async fn secure_split_link<S: NorFlash>(
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    storage: &SharedStorage<'_, S>,
+    split_bond: &mut SplitBond,
+) -> bool {
+    split_bond
+        .secure(conn.raw(), storage, false, async || {
+            loop {
+                match conn.next().await {
+                    GattConnectionEvent::PairingComplete {
+                        security_level,
+                        bond,
+                    } => break PairingEvent::Complete(security_level, bond),
+                    GattConnectionEvent::PairingFailed(err) => break PairingEvent::Failed(err),
+                    GattConnectionEvent::Disconnected { .. } => break PairingEvent::Disconnected,
+                    GattConnectionEvent::Gatt { event } => {
+                        if let Ok(reply) = event.reject(AttErrorCode::INSUFFICIENT_ENCRYPTION) {
+                            reply.send().await;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await
+}
+
+#[cfg(split_ble)]
+/// Gatt event handelr task, receives the split protocol frames of the central half
 async fn gatt_split_events_handler<'stack, 'server>(
     conn: &GattConnection<'stack, 'server, DefaultPacketPool>,
     server: &'server Server<'_>,
+    frames: &SplitFrames,
 ) -> Result<(), Error> {
-    let split_service_registered_keys = server.split_service.registered_keys;
-    let split_service_battery_level = server.split_service.level;
-
-    let matrix_keys_split_sender = MATRIX_KEYS_SPLIT.sender();
-    let mut matrix_keys_split_local = [KeyPos::default(); MATRIX_KEYS_BUFFER];
-    let battery_level_sender = BATTERY_LEVEL.sender();
+    let split_service_to_peripheral = server.split_service.to_peripheral;
 
     let _reason = loop {
         match conn.next().await {
@@ -300,83 +560,40 @@ async fn gatt_split_events_handler<'stack, 'server>(
                 error!("[gatt] pairing error: {:?}", _err);
             }
             GattConnectionEvent::Gatt { event } => {
-                match &event {
+                let result = match &event {
                     GattEvent::Read(_event) => {
-                        if conn
-                            .raw()
-                            .security_level()
-                            .expect("[gatt] error getting security level")
-                            .encrypted()
-                        {
+                        if encrypted(conn) {
                             None
                         } else {
                             Some(AttErrorCode::INSUFFICIENT_ENCRYPTION)
                         }
                     }
-                    GattEvent::Write(event) => {
-                        if event.handle() == split_service_registered_keys.handle {
-                            // central message to peripheral
-                            let central_data = event.data();
-
-                            // store the central keys in matrix keys
-                            for (index, combined_key) in central_data.iter().enumerate() {
-                                if *combined_key != 255u8 {
-                                    let col = (combined_key & 0x0f) + COLS as u8;
-                                    let row = combined_key >> 4;
-
-                                    matrix_keys_split_local[index] = KeyPos { row, col };
-                                } else {
-                                    matrix_keys_split_local[index] = KeyPos::default();
-                                }
-                            }
-                            // send the new matrix_keys
-                            matrix_keys_split_sender.send(matrix_keys_split_local);
-
-                            #[cfg(feature = "defmt")]
-                            info!(
-                                "[split_registered_keys] received: {:?}",
-                                matrix_keys_split_local
-                            );
-                        }
-
-                        // split battery level information
-                        if event.handle() == split_service_battery_level.handle {
-                            let split_battery_level = event.data();
-
-                            for split_b_level in split_battery_level {
-                                if let Some(b_level) = battery_level_sender.try_get() {
-                                    // send only the lower value (either peripheral or central battery level)
-                                    if *split_b_level < b_level {
-                                        battery_level_sender.send(*split_b_level);
-                                    }
-
-                                    #[cfg(feature = "defmt")]
-                                    info!(
-                                        "[split_battery_level] central bat lvl: {:?}; peri bat lvl: {:?}",
-                                        split_b_level, b_level
-                                    );
-                                }
-                            }
-                            #[cfg(feature = "defmt")]
-                            info!("[split_battery_level] central bat lvl rcvd",);
-                        }
-
-                        if conn
-                            .raw()
-                            .security_level()
-                            .expect("[gatt] error getting security level")
-                            .encrypted()
-                        {
-                            None
-                        } else {
-                            Some(AttErrorCode::INSUFFICIENT_ENCRYPTION)
-                        }
+                    GattEvent::Write(_) if !encrypted(conn) => {
+                        Some(AttErrorCode::INSUFFICIENT_ENCRYPTION)
+                    }
+                    GattEvent::Write(event)
+                        if event.handle() == split_service_to_peripheral.handle =>
+                    {
+                        let data = event.data();
+                        let mut frame = [0; FRAME_MAX];
+                        let len = data.len().min(FRAME_MAX);
+                        frame[..len].copy_from_slice(&data[..len]);
+
+                        // a full queue drops the frame, the sequence numbers tell the link
+                        let _ = frames.try_send(frame);
+
+                        None
                     }
 
                     _ => None, // OTHER
                 };
 
-                match event.accept() {
+                let reply = match result {
+                    Some(err) => event.reject(err),
+                    None => event.accept(),
+                };
+
+                match reply {
                     Ok(reply) => reply.send().await,
                     Err(_e) => {
                         #[cfg(feature = "defmt")]
@@ -397,11 +614,20 @@ async fn gatt_split_events_handler<'stack, 'server>(
 async fn gatt_hid_events_handler<'stack, 'server, S: NorFlash>(
     conn: &GattConnection<'stack, 'server, DefaultPacketPool>,
     server: &'server Server<'_>,
-    storage: &mut S,
-    bond_stored: &mut bool,
+    storage: &SharedStorage<'_, S>,
+    profiles: &mut HostProfiles,
 ) -> Result<(), Error> {
     let hid_service_report_map = server.hid_service.report_map;
+    let hid_service_protocol_mode = server.hid_service.protocol_mode;
+    let hid_service_output_keyboard = server.hid_service.output_keyboard;
+    let hid_service_boot_keyboard_output = server.hid_service.boot_keyboard_output;
+    let keyboard_leds_sender = KEYBOARD_LEDS.sender();
     let battery_service_level = server.battery_service.level;
+    #[cfg(split_ble)]
+    let split_handles = [
+        server.split_service.to_peripheral.handle,
+        server.split_service.to_central.handle,
+    ];
 
     let _reason = loop {
         match conn.next().await {
@@ -414,12 +640,14 @@ async fn gatt_hid_events_handler<'stack, 'server, S: NorFlash>(
                 info!("[gatt] pairing complete: {:?}", _security_level);
 
                 if let Some(bond_info) = bond {
-                    store_bonding_info(storage, &bond_info)
-                        .await
-                        .expect("[gatt] error storing bond info");
-                    *bond_stored = true;
+                    profiles
+                        .store_bond(&mut **storage.lock().await, bond_info)
+                        .await;
                     #[cfg(feature = "defmt")]
-                    info!("[gatt] bond information stored");
+                    info!(
+                        "[gatt] bond information stored in profile {}",
+                        profiles.active()
+                    );
                 }
             }
             GattConnectionEvent::PairingFailed(_err) => {
@@ -427,7 +655,16 @@ async fn gatt_hid_events_handler<'stack, 'server, S: NorFlash>(
                 error!("[gatt] pairing error: {:?}", _err);
             }
             GattConnectionEvent::Gatt { event } => {
-                match &event {
+                #[cfg(split_ble)]
+                let split_access = match &event {
+                    GattEvent::Read(event) => split_handles.contains(&event.handle()),
+                    GattEvent::Write(event) => split_handles.contains(&event.handle()),
+                    _ => false,
+                };
+                #[cfg(not(split_ble))]
+                let split_access = false;
+
+                let result = match &event {
                     GattEvent::Read(event) => {
                         if event.handle() == hid_service_report_map.handle {
                             let _value = server.get(&hid_service_report_map);
@@ -439,17 +676,16 @@ async fn gatt_hid_events_handler<'stack, 'server, S: NorFlash>(
                             info!("[gatt] Read Event to Level Characteristic: {:?}", _value);
                         }
 
-                        if conn
-                            .raw()
-                            .security_level()
-                            .expect("[gatt] error getting security level")
-                            .encrypted()
-                        {
+                        if encrypted(conn) {
                             None
                         } else {
                             Some(AttErrorCode::INSUFFICIENT_ENCRYPTION)
                         }
                     }
+                    // the host lock leds are only taken from a bonded host
+                    GattEvent::Write(_) if !encrypted(conn) => {
+                        Some(AttErrorCode::INSUFFICIENT_ENCRYPTION)
+                    }
                     GattEvent::Write(event) => {
                         if event.handle() == hid_service_report_map.handle {
                             #[cfg(feature = "defmt")]
@@ -463,24 +699,42 @@ async fn gatt_hid_events_handler<'stack, 'server, S: NorFlash>(
                                 "[gatt] Write Event to Level Characteristic {:?}",
                                 event.data()
                             );
-                        }
-
-                        if conn
-                            .raw()
-                            .security_level()
-                            .expect("[gatt] error getting security level")
-                            .encrypted()
+                        } else if event.handle() == hid_service_output_keyboard.handle
+                            || event.handle() == hid_service_boot_keyboard_output.handle
                         {
-                            None
-                        } else {
-                            Some(AttErrorCode::INSUFFICIENT_ENCRYPTION)
+                            // lock LED state of the host
+                            if let Some(leds) = event.data().first() {
+                                let leds = KeyboardLeds(*leds);
+                                if keyboard_leds_sender.try_get() != Some(leds) {
+                                    keyboard_leds_sender.send(leds);
+                                }
+
+                                #[cfg(feature = "defmt")]
+                                info!("[gatt] keyboard leds: {:?}", leds);
+                            }
+                        } else if event.handle() == hid_service_protocol_mode.handle {
+                            // applied to the next keyboard report by hid_kb_service_task
+                            #[cfg(feature = "defmt")]
+                            info!(
+                                "[gatt] protocol mode (0 boot, 1 report): {:?}",
+                                event.data()
+                            );
                         }
+
+                        None
                     }
 
                     _ => None, // OTHER
                 };
 
-                match event.accept() {
+                // the split service only answers the other half
+                let reply = match result {
+                    _ if split_access => event.reject(AttErrorCode::INSUFFICIENT_AUTHORIZATION),
+                    Some(err) => event.reject(err),
+                    None => event.accept(),
+                };
+
+                match reply {
                     Ok(reply) => reply.send().await,
                     Err(_e) => {
                         #[cfg(feature = "defmt")]
@@ -497,6 +751,14 @@ async fn gatt_hid_events_handler<'stack, 'server, S: NorFlash>(
     Ok(())
 }
 
+/// Whether the link is encrypted
+fn encrypted(conn: &GattConnection<'_, '_, DefaultPacketPool>) -> bool {
+    conn.raw()
+        .security_level()
+        .expect("[gatt] error getting security level")
+        .encrypted()
+}
+
 /// Battery service task
 async fn battery_service_task<'stack, 'server>(
     conn: &GattConnection<'stack, 'server, DefaultPacketPool>,
@@ -532,24 +794,154 @@ async fn battery_service_task<'stack, 'server>(
     }
 }
 
+#[cfg(split_ble)]
+/// Sending side of the ble split transport, frames are notified to the central half
+struct BleSplitNotifier<'a, 'stack, 'server, 'values> {
+    conn: &'a GattConnection<'stack, 'server, DefaultPacketPool>,
+    server: &'a Server<'values>,
+}
+
+#[cfg(split_ble)]
+impl SplitSender for BleSplitNotifier<'_, '_, '_, '_> {
+    async fn send(&mut self, frame: &[u8]) -> bool {
+        // the characteristic has a fixed size, the rest is padding
+        let mut buf = [0; FRAME_MAX];
+        buf[..frame.len()].copy_from_slice(frame);
+
+        match self
+            .server
+            .split_service
+            .to_central
+            .notify(self.conn, &buf)
+            .await
+        {
+            Ok(_) => true,
+            Err(_e) => {
+                #[cfg(feature = "defmt")]
+                info!("[notify] split frame error: {}", _e);
+                false
+            }
+        }
+    }
+}
+
+/// Characteristic the keyboard reports are notified through
+#[derive(PartialEq, Clone, Copy)]
+enum KeyboardChannel {
+    /// Boot keyboard input report, the host selected the boot protocol
+    Boot,
+    /// 6 keys report of the report protocol
+    Report,
+    /// N-key rollover report of the report protocol
+    Nkro,
+}
+
+/// Notify the keyboard report through the given characteristic
+async fn notify_keyboard<'stack, 'server>(
+    conn: &GattConnection<'stack, 'server, DefaultPacketPool>,
+    server: &'server Server<'_>,
+    channel: KeyboardChannel,
+    key_report: &KeyboardReport,
+    nkro_report: &NkroReport,
+) -> Result<(), trouble_host::Error> {
+    let mut buff = [0u8; 8];
+
+    match channel {
+        KeyboardChannel::Nkro => {
+            server
+                .hid_service
+                .nkro_report
+                .notify(conn, &nkro_report.to_bytes())
+                .await
+        }
+        KeyboardChannel::Report => {
+            let _n = serialize(&mut buff, key_report).unwrap();
+            server.hid_service.report.notify(conn, &buff).await
+        }
+        KeyboardChannel::Boot => {
+            let _n = serialize(&mut buff, key_report).unwrap();
+            server
+                .hid_service
+                .boot_keyboard_input
+                .notify(conn, &buff)
+                .await
+        }
+    }
+}
+
 /// Keyboard serivce task
 async fn hid_kb_service_task<'stack, 'server>(
     conn: &GattConnection<'stack, 'server, DefaultPacketPool>,
     server: &'server Server<'_>,
 ) {
-    let mut buff = [0u8; 8];
-
     let mut key_report = KEY_REPORT
         .receiver()
         .expect(" [ble_peripheral] maximum number of receivers has been reached");
+    let mut nkro_report = NKRO_REPORT
+        .receiver()
+        .expect(" [ble_peripheral] maximum number of receivers has been reached");
+    let mut nkro_enabled = NKRO_ENABLED
+        .receiver()
+        .expect(" [ble_peripheral] maximum number of receivers has been reached");
+
+    // every connection starts in report protocol mode
+    let _ = server.set(&server.hid_service.protocol_mode, &PROTOCOL_MODE_REPORT);
+
+    let mut channel_used = KeyboardChannel::Report;
+    let mut ble_used = true;
 
     loop {
         // wait till new key_report is received from key_provision
         let key_report = key_report.changed().await;
 
-        let _n = serialize(&mut buff, &key_report).unwrap();
+        // the reports go out over usb, the keys held over ble are released once
+        if active_output() != Output::Ble {
+            if ble_used {
+                let _ = notify_keyboard(
+                    conn,
+                    server,
+                    channel_used,
+                    &KeyboardReport::default(),
+                    &NkroReport::default(),
+                )
+                .await;
+                ble_used = false;
+            }
+            continue;
+        }
+        ble_used = true;
+
+        let nkro_report = nkro_report.try_get().unwrap_or_default();
+
+        // the host selects the protocol, the n-key rollover report only exists in report mode
+        let channel = if matches!(
+            server.get(&server.hid_service.protocol_mode),
+            Ok(PROTOCOL_MODE_BOOT)
+        ) {
+            KeyboardChannel::Boot
+        } else if nkro_enabled.try_get().unwrap_or(NKRO) {
+            KeyboardChannel::Nkro
+        } else {
+            KeyboardChannel::Report
+        };
+
+        // release the keys held in the characteristic which is no longer used
+        if channel != channel_used {
+            #[cfg(feature = "defmt")]
+            info!("[notify] keyboard report channel changed");
+
+            let _ = notify_keyboard(
+                conn,
+                server,
+                channel_used,
+                &KeyboardReport::default(),
+                &NkroReport::default(),
+            )
+            .await;
+            channel_used = channel;
+        }
 
-        match server.hid_service.report.notify(conn, &buff).await {
+        match notify_keyboard(conn, server, channel, &key_report, &nkro_report).await {
             Ok(_) => {
                 #[cfg(feature = "defmt")]
                 info!("[notify] input keyboard notified successfully")
@@ -562,3 +954,91 @@ async fn hid_kb_service_task<'stack, 'server>(
         }
     }
 }
+
+/// Consumer control (media keys) service task
+async fn hid_consumer_service_task<'stack, 'server>(
+    conn: &GattConnection<'stack, 'server, DefaultPacketPool>,
+    server: &'server Server<'_>,
+) {
+    let mut consumer_report = CONSUMER_REPORT
+        .receiver()
+        .expect(" [ble_peripheral] maximum number of receivers has been reached");
+
+    let mut ble_used = true;
+
+    loop {
+        // wait till new consumer usage is received from key_provision
+        let mut usage = consumer_report.changed().await;
+
+        // the reports go out over usb, the media key held over ble is released once
+        let ble = active_output() == Output::Ble;
+        if !ble {
+            if !ble_used {
+                continue;
+            }
+            usage = 0;
+        }
+        ble_used = ble;
+
+        match server
+            .hid_service
+            .consumer_report
+            .notify(conn, &usage.to_le_bytes())
+            .await
+        {
+            Ok(_) => {
+                #[cfg(feature = "defmt")]
+                info!("[notify] input consumer notified successfully")
+            }
+            Err(_e) => {
+                #[cfg(feature = "defmt")]
+                info!("[notify] input consumer error: {}", _e);
+                break;
+            }
+        }
+    }
+}
+
+/// Mouse keys service task
+async fn hid_mouse_service_task<'stack, 'server>(
+    conn: &GattConnection<'stack, 'server, DefaultPacketPool>,
+    server: &'server Server<'_>,
+) {
+    let mut mouse_report = MOUSE_REPORT
+        .receiver()
+        .expect(" [ble_peripheral] maximum number of receivers has been reached");
+
+    let mut ble_used = true;
+
+    loop {
+        // wait till new mouse report is received from the mouse keys task
+        let mut report = mouse_report.changed().await;
+
+        // the reports go out over usb, the buttons held over ble are released once
+        let ble = active_output() == Output::Ble;
+        if !ble {
+            if !ble_used {
+                continue;
+            }
+            report = MouseReport::default();
+        }
+        ble_used = ble;
+
+        match server
+            .hid_service
+            .mouse_report
+            .notify(conn, &report.to_bytes())
+            .await
+        {
+            Ok(_) => {
+                #[cfg(feature = "defmt")]
+                info!("[notify] input mouse notified successfully")
+            }
+            Err(_e) => {
+                #[cfg(feature = "defmt")]
+                info!("[notify] input mouse error: {}", _e);
+                break;
+            }
+        }
+    }
+}
@@ -1,23 +1,155 @@
+use crate::nkro::NKRO_REPORT_SIZE;
+#[cfg(split_ble)]
+use crate::split::protocol::FRAME_MAX;
 use trouble_host::prelude::{
     characteristic::{BATTERY_LEVEL, BATTERY_LEVEL_STATUS},
     *,
 };
-use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
 
 /// Custom service for the split device
 pub const SPLIT_SERVICE: BluetoothUuid16 = BluetoothUuid16::new(0xff11);
 
-/// Custom characteristics for the split device
-pub const SPLIT_REPORT_CH: BluetoothUuid16 = BluetoothUuid16::new(0xff22);
-pub const SPLIT_BATTERY_CH: BluetoothUuid16 = BluetoothUuid16::new(0xff33);
+/// Custom characteristics for the split device, carrying split protocol frames each way
+pub const SPLIT_TO_PERIPHERAL_CH: BluetoothUuid16 = BluetoothUuid16::new(0xff55);
+pub const SPLIT_TO_CENTRAL_CH: BluetoothUuid16 = BluetoothUuid16::new(0xff66);
 
-#[gatt_server(cccd_table_size = 8, connections_max = 2)]
+/// Protocol mode value of the boot protocol, reports go through the boot characteristics
+pub const PROTOCOL_MODE_BOOT: u8 = 0;
+
+/// Protocol mode value of the report protocol, the default on every connection
+pub const PROTOCOL_MODE_REPORT: u8 = 1;
+
+/// Report ID of the keyboard input and LED output reports
+pub const KEYBOARD_REPORT_ID: u8 = 1;
+
+/// Report ID of the consumer control (media keys) input report
+pub const CONSUMER_REPORT_ID: u8 = 2;
+
+/// Report ID of the mouse input report
+pub const MOUSE_REPORT_ID: u8 = 3;
+
+/// Report ID of the n-key rollover keyboard input report
+pub const NKRO_REPORT_ID: u8 = 4;
+
+/// HID report map: boot compatible keyboard, consumer control, mouse and n-key rollover keyboard
+#[rustfmt::skip]
+pub const REPORT_MAP: [u8; 188] = [
+    // keyboard
+    0x05, 0x01,               // Usage Page (Generic Desktop)
+    0x09, 0x06,               // Usage (Keyboard)
+    0xA1, 0x01,               // Collection (Application)
+    0x85, KEYBOARD_REPORT_ID, //   Report ID
+    0x05, 0x07,               //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,               //   Usage Minimum (Left Control)
+    0x29, 0xE7,               //   Usage Maximum (Right GUI)
+    0x15, 0x00,               //   Logical Minimum (0)
+    0x25, 0x01,               //   Logical Maximum (1)
+    0x75, 0x01,               //   Report Size (1)
+    0x95, 0x08,               //   Report Count (8)
+    0x81, 0x02,               //   Input (Data, Variable, Absolute): modifiers
+    0x75, 0x08,               //   Report Size (8)
+    0x95, 0x01,               //   Report Count (1)
+    0x81, 0x01,               //   Input (Constant): reserved
+    0x05, 0x08,               //   Usage Page (LEDs)
+    0x19, 0x01,               //   Usage Minimum (Num Lock)
+    0x29, 0x05,               //   Usage Maximum (Kana)
+    0x75, 0x01,               //   Report Size (1)
+    0x95, 0x05,               //   Report Count (5)
+    0x91, 0x02,               //   Output (Data, Variable, Absolute): leds
+    0x75, 0x03,               //   Report Size (3)
+    0x95, 0x01,               //   Report Count (1)
+    0x91, 0x01,               //   Output (Constant): padding
+    0x05, 0x07,               //   Usage Page (Keyboard/Keypad)
+    0x19, 0x00,               //   Usage Minimum (0)
+    0x2A, 0xFF, 0x00,         //   Usage Maximum (255)
+    0x15, 0x00,               //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,         //   Logical Maximum (255)
+    0x75, 0x08,               //   Report Size (8)
+    0x95, 0x06,               //   Report Count (6)
+    0x81, 0x00,               //   Input (Data, Array, Absolute): keycodes
+    0xC0,                     // End Collection
+    // consumer control
+    0x05, 0x0C,               // Usage Page (Consumer)
+    0x09, 0x01,               // Usage (Consumer Control)
+    0xA1, 0x01,               // Collection (Application)
+    0x85, CONSUMER_REPORT_ID, //   Report ID
+    0x19, 0x00,               //   Usage Minimum (0)
+    0x2A, 0xFF, 0x03,         //   Usage Maximum (0x3FF)
+    0x15, 0x00,               //   Logical Minimum (0)
+    0x26, 0xFF, 0x03,         //   Logical Maximum (0x3FF)
+    0x75, 0x10,               //   Report Size (16)
+    0x95, 0x01,               //   Report Count (1)
+    0x81, 0x00,               //   Input (Data, Array, Absolute): usage
+    0xC0,                     // End Collection
+    // mouse
+    0x05, 0x01,               // Usage Page (Generic Desktop)
+    0x09, 0x02,               // Usage (Mouse)
+    0xA1, 0x01,               // Collection (Application)
+    0x85, MOUSE_REPORT_ID,    //   Report ID
+    0x09, 0x01,               //   Usage (Pointer)
+    0xA1, 0x00,               //   Collection (Physical)
+    0x05, 0x09,               //     Usage Page (Buttons)
+    0x19, 0x01,               //     Usage Minimum (Button 1)
+    0x29, 0x03,               //     Usage Maximum (Button 3)
+    0x15, 0x00,               //     Logical Minimum (0)
+    0x25, 0x01,               //     Logical Maximum (1)
+    0x75, 0x01,               //     Report Size (1)
+    0x95, 0x03,               //     Report Count (3)
+    0x81, 0x02,               //     Input (Data, Variable, Absolute): buttons
+    0x75, 0x05,               //     Report Size (5)
+    0x95, 0x01,               //     Report Count (1)
+    0x81, 0x01,               //     Input (Constant): padding
+    0x05, 0x01,               //     Usage Page (Generic Desktop)
+    0x09, 0x30,               //     Usage (X)
+    0x09, 0x31,               //     Usage (Y)
+    0x09, 0x38,               //     Usage (Wheel)
+    0x15, 0x81,               //     Logical Minimum (-127)
+    0x25, 0x7F,               //     Logical Maximum (127)
+    0x75, 0x08,               //     Report Size (8)
+    0x95, 0x03,               //     Report Count (3)
+    0x81, 0x06,               //     Input (Data, Variable, Relative): x, y, wheel
+    0x05, 0x0C,               //     Usage Page (Consumer)
+    0x0A, 0x38, 0x02,         //     Usage (AC Pan)
+    0x95, 0x01,               //     Report Count (1)
+    0x81, 0x06,               //     Input (Data, Variable, Relative): pan
+    0xC0,                     //   End Collection
+    0xC0,                     // End Collection
+    // n-key rollover keyboard
+    0x05, 0x01,               // Usage Page (Generic Desktop)
+    0x09, 0x06,               // Usage (Keyboard)
+    0xA1, 0x01,               // Collection (Application)
+    0x85, NKRO_REPORT_ID,     //   Report ID
+    0x05, 0x07,               //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,               //   Usage Minimum (Left Control)
+    0x29, 0xE7,               //   Usage Maximum (Right GUI)
+    0x15, 0x00,               //   Logical Minimum (0)
+    0x25, 0x01,               //   Logical Maximum (1)
+    0x75, 0x01,               //   Report Size (1)
+    0x95, 0x08,               //   Report Count (8)
+    0x81, 0x02,               //   Input (Data, Variable, Absolute): modifiers
+    0x19, 0x00,               //   Usage Minimum (0)
+    0x29, 0xDF,               //   Usage Maximum (0xDF)
+    0x95, 0xE0,               //   Report Count (224)
+    0x81, 0x02,               //   Input (Data, Variable, Absolute): keycode bitmap
+    0xC0,                     // End Collection
+];
+
+#[cfg(split_ble)]
+#[gatt_server(cccd_table_size = 10, connections_max = 2)]
 pub(crate) struct Server {
     pub(crate) battery_service: BatteryService,
     pub(crate) hid_service: HidService,
     pub(crate) split_service: SplitService,
 }
 
+/// Unibody keyboards and wired split keyboards leave the split service out
+#[cfg(not(split_ble))]
+#[gatt_server(cccd_table_size = 9, connections_max = 1)]
+pub(crate) struct Server {
+    pub(crate) battery_service: BatteryService,
+    pub(crate) hid_service: HidService,
+}
+
 #[gatt_service(uuid = service::BATTERY)]
 pub(crate) struct BatteryService {
     #[descriptor(uuid = descriptors::VALID_RANGE, read, value = [0, 100])]
@@ -31,26 +163,40 @@ pub(crate) struct BatteryService {
 pub(crate) struct HidService {
     #[characteristic(uuid = "2a4a", read, value = [0x01, 0x01, 0x00, 0x03])]
     pub(crate) hid_info: [u8; 4],
-    #[characteristic(uuid = "2a4b", read, value = KeyboardReport::desc().try_into().expect("Failed to convert KeyboardReport to [u8; 69]"))]
-    pub(crate) report_map: [u8; 69],
+    #[characteristic(uuid = "2a4b", read, value = REPORT_MAP)]
+    pub(crate) report_map: [u8; REPORT_MAP.len()],
     #[characteristic(uuid = "2a4c", write_without_response)]
     pub(crate) hid_control_point: u8,
-    #[characteristic(uuid = "2a4e", read, write_without_response, value = 1)]
+    #[characteristic(uuid = "2a4e", read, write_without_response, value = PROTOCOL_MODE_REPORT)]
     pub(crate) protocol_mode: u8,
-    #[descriptor(uuid = "2908", read, value = [0u8, 1u8])]
+    #[characteristic(uuid = "2a22", read, notify)]
+    pub(crate) boot_keyboard_input: [u8; 8],
+    #[characteristic(uuid = "2a32", read, write, write_without_response)]
+    pub(crate) boot_keyboard_output: [u8; 1],
+    #[descriptor(uuid = "2908", read, value = [KEYBOARD_REPORT_ID, 1u8])]
     #[characteristic(uuid = "2a4d", read, notify)]
     pub(crate) report: [u8; 8],
-    #[descriptor(uuid = "2908", read, value = [0u8, 2u8])]
+    #[descriptor(uuid = "2908", read, value = [KEYBOARD_REPORT_ID, 2u8])]
     #[characteristic(uuid = "2a4d", read, write, write_without_response)]
     pub(crate) output_keyboard: [u8; 1],
+    #[descriptor(uuid = "2908", read, value = [CONSUMER_REPORT_ID, 1u8])]
+    #[characteristic(uuid = "2a4d", read, notify)]
+    pub(crate) consumer_report: [u8; 2],
+    #[descriptor(uuid = "2908", read, value = [MOUSE_REPORT_ID, 1u8])]
+    #[characteristic(uuid = "2a4d", read, notify)]
+    pub(crate) mouse_report: [u8; 5],
+    #[descriptor(uuid = "2908", read, value = [NKRO_REPORT_ID, 1u8])]
+    #[characteristic(uuid = "2a4d", read, notify)]
+    pub(crate) nkro_report: [u8; NKRO_REPORT_SIZE],
 }
 
+#[cfg(split_ble)]
 #[gatt_service(uuid = SPLIT_SERVICE)]
 pub(crate) struct SplitService {
-    #[characteristic(uuid = SPLIT_REPORT_CH, read, notify)]
-    pub(crate) registered_keys: [u8; 6],
-    #[descriptor(uuid = descriptors::VALID_RANGE, read, value = [0, 100])]
-    #[descriptor(uuid = descriptors::MEASUREMENT_DESCRIPTION, name = "battery_level", read, value = "Battery Level")]
-    #[characteristic(uuid = SPLIT_BATTERY_CH, read, notify, value = 0)]
-    pub(crate) level: u8,
+    // frames from the central half, written without response
+    #[characteristic(uuid = SPLIT_TO_PERIPHERAL_CH, read, write_without_response)]
+    pub(crate) to_peripheral: [u8; FRAME_MAX],
+    // frames to the central half, notified
+    #[characteristic(uuid = SPLIT_TO_CENTRAL_CH, read, notify)]
+    pub(crate) to_central: [u8; FRAME_MAX],
 }
@@ -0,0 +1,141 @@
+#[cfg(feature = "defmt")]
+use defmt::info;
+#[cfg(all(feature = "defmt", any(split_ble, feature = "receiver")))]
+use defmt::{error, warn};
+#[cfg(any(split_ble, feature = "receiver"))]
+use embassy_futures::select::{Either, select};
+#[cfg(any(split_ble, feature = "receiver"))]
+use embassy_time::Timer;
+use embedded_storage_async::nor_flash::NorFlash;
+use nrf_sdc::SoftdeviceController;
+use trouble_host::prelude::{BdAddr, DefaultPacketPool};
+#[cfg(any(split_ble, feature = "receiver"))]
+use trouble_host::prelude::{Connection, SecurityLevel};
+use trouble_host::{BondInformation, Stack};
+
+use crate::ble::is_bonded_peer;
+use crate::storage::{load_split_bond, store_split_bond};
+#[cfg(any(split_ble, feature = "receiver"))]
+use crate::{ble::SharedStorage, config::SPLIT_SECURITY_TIMEOUT};
+
+/// Storage slot of the bond with the other half, and of the bond of a half with the dongle,
+/// the dongle stores the bond of each half in the slot of its role
+#[cfg(not(feature = "receiver"))]
+pub(crate) const SPLIT_SLOT: u8 = 0;
+
+/// Pairing events of the link with a split half
+#[cfg(any(split_ble, feature = "receiver"))]
+pub(crate) enum PairingEvent {
+    /// The link is encrypted, with the keys of the new bond when the halves just bonded
+    Complete(SecurityLevel, Option<BondInformation>),
+    Failed(trouble_host::Error),
+    Disconnected,
+}
+
+/// Bond with the other half of the split keyboard
+pub(crate) struct SplitBond {
+    /// Storage slot of the bond, the dongle keeps one per half
+    slot: u8,
+    bond: Option<BondInformation>,
+}
+
+impl SplitBond {
+    /// Load the stored bond, it is handed to the stack so the link is encrypted on reconnect
+    pub(crate) async fn load<S: NorFlash>(
+        storage: &mut S,
+        stack: &Stack<'_, SoftdeviceController<'_>, DefaultPacketPool>,
+        slot: u8,
+    ) -> Self {
+        let bond = load_split_bond(storage, slot).await;
+
+        if let Some(bond_info) = &bond {
+            stack
+                .add_bond_information(bond_info.clone())
+                .expect("[split_bond] error adding bond information");
+
+            #[cfg(feature = "defmt")]
+            info!("[split_bond] loaded bond of the split half");
+        }
+
+        Self { slot, bond }
+    }
+
+    pub(crate) fn is_bonded(&self) -> bool {
+        self.bond.is_some()
+    }
+
+    /// Whether the peer may use the split link: the bonded half,
+    /// or any device until the halves have been bonded
+    pub(crate) fn accepts(&self, peer: &BdAddr) -> bool {
+        self.bond
+            .as_ref()
+            .is_none_or(|bond| is_bonded_peer(bond, peer))
+    }
+
+    /// Store the bond of the newly paired half
+    pub(crate) async fn store<S: NorFlash>(&mut self, storage: &mut S, bond: BondInformation) {
+        store_split_bond(storage, self.slot, &bond)
+            .await
+            .expect("[split_bond] error storing bond info");
+
+        self.bond = Some(bond);
+    }
+
+    /// Encrypt the link with the split half, the halves bond on their first connection and
+    /// reconnect with the stored keys afterwards, any other device is turned away once they are
+    /// bonded. The central half starts the pairing, `pairing_event` waits for the next pairing
+    /// event of the link and handles whatever else comes meanwhile
+    #[cfg(any(split_ble, feature = "receiver"))]
+    pub(crate) async fn secure<S: NorFlash>(
+        &mut self,
+        conn: &Connection<'_, DefaultPacketPool>,
+        storage: &SharedStorage<'_, S>,
+        central: bool,
+        mut pairing_event: impl AsyncFnMut() -> PairingEvent,
+    ) -> bool {
+        if !self.accepts(&conn.peer_address()) {
+            #[cfg(feature = "defmt")]
+            warn!("[split] unknown device rejected");
+            return false;
+        }
+
+        // only a new split half may bond, a bonded one encrypts with the stored keys
+        conn.set_bondable(!self.is_bonded())
+            .expect("[split] error setting bondable");
+
+        if central && let Err(_e) = conn.request_security() {
+            #[cfg(feature = "defmt")]
+            error!("[split] error requesting security: {:?}", _e);
+            return false;
+        }
+
+        let secured = select(pairing_event(), Timer::after_millis(SPLIT_SECURITY_TIMEOUT)).await;
+
+        match secured {
+            Either::First(PairingEvent::Complete(_security_level, bond)) => {
+                #[cfg(feature = "defmt")]
+                info!("[split] link encrypted: {:?}", _security_level);
+
+                if let Some(bond_info) = bond {
+                    self.store(&mut **storage.lock().await, bond_info).await;
+                }
+                true
+            }
+            Either::First(PairingEvent::Failed(_err)) => {
+                #[cfg(feature = "defmt")]
+                error!("[split] pairing error: {:?}", _err);
+                false
+            }
+            Either::First(PairingEvent::Disconnected) => {
+                #[cfg(feature = "defmt")]
+                warn!("[split] disconnected before encryption");
+                false
+            }
+            Either::Second(()) => {
+                #[cfg(feature = "defmt")]
+                warn!("[split] link not encrypted in time");
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+use crate::COMBOS;
+use crate::action::Action;
+use crate::keycodes::KC;
+use crate::matrix::{Key, KeyPos};
+
+/// Trigger key of a combo
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ComboKey {
+    /// Physical matrix position
+    Position(KeyPos),
+    /// Position whose keymap resolves to this keycode when pressed
+    Code(KC),
+}
+
+impl ComboKey {
+    pub fn matches(&self, key: &Key) -> bool {
+        match self {
+            ComboKey::Position(position) => key.position == *position,
+            ComboKey::Code(kc) => key.action == Action::Key(*kc),
+        }
+    }
+}
+
+/// Combo generated from `user_config.toml` by the build script
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, Clone, Copy)]
+pub struct Combo {
+    /// Keys to press together
+    pub keys: &'static [ComboKey],
+    /// Action emitted instead of the trigger keys
+    pub action: Action,
+    /// Window in ms in which all the trigger keys must be pressed
+    pub timeout: u64,
+    /// Layers the combo is enabled on, all layers if empty
+    pub layers: &'static [u8],
+}
+
+impl Combo {
+    /// Whether the combo is enabled on the given layer
+    pub fn enabled_on(&self, layer: u8) -> bool {
+        self.layers.is_empty() || self.layers.contains(&layer)
+    }
+
+    /// Whether the key is one of the trigger keys
+    pub fn contains(&self, key: &Key) -> bool {
+        self.keys.iter().any(|combo_key| combo_key.matches(key))
+    }
+}
+
+/// Whether the key can start a combo on the given layer
+pub fn is_combo_key(key: &Key, layer: u8) -> bool {
+    COMBOS
+        .iter()
+        .any(|combo| combo.enabled_on(layer) && combo.contains(key))
+}
+
+/// Longest window in ms a key waits for the other keys of its combos
+pub fn combo_timeout(key: &Key) -> u64 {
+    COMBOS
+        .iter()
+        .filter(|combo| combo.contains(key))
+        .map(|combo| combo.timeout)
+        .max()
+        .unwrap_or(0)
+}
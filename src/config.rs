@@ -1,11 +1,109 @@
-/// Peripheral address for connecting central to peripheral
-pub const PERI_ADDRESS: [u8; 6] = [0x0c, 0x4d, 0x2e, 0xb4, 0x1d, 0xfb];
+use crate::{COLS, ROWS, SPLIT};
 
-/// Size of the registered matrix keys array
-pub const MATRIX_KEYS_BUFFER: usize = 6;
+/// Time after power-up in which a held role key selects the role of the half in ms
+pub const ROLE_KEY_WINDOW: u64 = 500;
+
+/// Time the split and the host advertising each get while both links are missing in ms
+pub const ADVERTISING_TURN: u64 = 1000;
+
+/// Idle time after which a split link without connection events, the serial and the esb
+/// ones, sends a ping in ms
+pub const SPLIT_PING_INTERVAL: u64 = 100;
+
+/// Time without any frame after which such a split link is taken as gone in ms, shorter than
+/// the silence of a restarting half so both sides start over with a full state
+pub const SPLIT_LINK_TIMEOUT: u64 = 300;
+
+/// Time given to the halves to encrypt the split link after connecting in ms
+pub const SPLIT_SECURITY_TIMEOUT: u64 = 10000;
+
+/// Radio channel the halves pair on, 2400 MHz + channel, the paired halves move to a channel
+/// and address of their own
+pub const ESB_PAIRING_CHANNEL: u8 = 40;
+
+/// Base address the halves pair on
+pub const ESB_PAIRING_BASE_ADDRESS: u32 = 0x5b7a_1c3d;
+
+/// Address prefix the halves pair on
+pub const ESB_PAIRING_PREFIX: u8 = 0xa7;
+
+/// Time the halves wait for each other on their own pipe after exchanging the pairing
+/// packets, before they pair again in ms
+pub const ESB_PAIRING_TIMEOUT: u64 = 1000;
+
+/// Interval between the timeslots of the central half in us, a queued frame is sent in the
+/// next one and the peripheral half listens in step with them
+pub const ESB_INTERVAL_US: u32 = 1000;
+
+/// Length of a timeslot of the central half in us
+pub const ESB_PTX_SLOT_US: u32 = 800;
+
+/// Length of a listening timeslot of the peripheral half while it looks for the central half
+/// in us
+pub const ESB_PRX_SLOT_US: u32 = 10000;
+
+/// Length of a listening timeslot of the peripheral half in step with the central half in us,
+/// shorter than the interval
+pub const ESB_PRX_WINDOW_US: u32 = 900;
+
+/// Time after which the central half without a frame to send polls the peripheral half in
+/// us, the poll carries the frames of the peripheral half and keeps it in step
+pub const ESB_KEEPALIVE_US: u32 = 20000;
+
+/// Time the central half waits for the acknowledgement of a packet in us
+pub const ESB_ACK_TIMEOUT_US: u32 = 400;
+
+/// Timeslots in which an unacknowledged packet is sent again before it is dropped
+pub const ESB_RETRANSMITS: u8 = 10;
+
+/// Size of the registered matrix keys array, every key of a half can be held at once
+pub const MATRIX_KEYS_BUFFER: usize = ROWS * COLS;
 
 /// Size of the registered matrix keys array for both halfs
-pub const MATRIX_KEYS_COMB_BUFFER: usize = MATRIX_KEYS_BUFFER * 2;
+pub const MATRIX_KEYS_COMB_BUFFER: usize = MATRIX_KEYS_BUFFER * if SPLIT { 2 } else { 1 };
+
+/// Width of the keymap, the columns of the split half follow the local ones
+pub const KEYMAP_COLS: usize = if SPLIT { COLS * 2 } else { COLS };
+
+/// Delay between the press and release reports of a tapped key in ms
+pub const TAP_DELAY: u64 = 10;
 
 /// Wait for a given time before entering sleep in ms
 pub const ENTER_SLEEP_DEBOUNCE: u64 = 600000;
+
+/// Interval between mouse reports while mouse movement or scroll keys are held in ms
+pub const MOUSE_INTERVAL: u64 = 16;
+
+/// Cursor movement per report when the movement starts
+pub const MOUSE_MOVE_MIN: u8 = 2;
+
+/// Cursor movement per report at full speed, at most 127
+pub const MOUSE_MOVE_MAX: u8 = 24;
+
+/// Time to reach the full cursor speed in ms
+pub const MOUSE_MOVE_TIME_TO_MAX: u64 = 1000;
+
+/// Scroll steps per wheel report when scrolling starts
+pub const MOUSE_WHEEL_MIN: u8 = 1;
+
+/// Scroll steps per wheel report at full speed, at most 127
+pub const MOUSE_WHEEL_MAX: u8 = 4;
+
+/// Time to reach the full scroll speed in ms
+pub const MOUSE_WHEEL_TIME_TO_MAX: u64 = 2000;
+
+/// Interval between wheel reports while scroll keys are held in ms
+pub const MOUSE_WHEEL_INTERVAL: u64 = 80;
+
+/// Usb vendor id, the pid.codes vendor id for open source hardware, the product id is set in
+/// `user_config.toml`
+pub const USB_VID: u16 = 0x1209;
+
+/// Interval between the usb power checks in ms, the usb output is picked once a host is found
+pub const USB_VBUS_POLL_INTERVAL: u64 = 200;
+
+/// Longest line typed on or printed to the usb serial console
+pub const CONSOLE_LINE_MAX: usize = 96;
+
+/// Interval between the checks of the keyboard state logged to the usb serial console in ms
+pub const CONSOLE_LOG_INTERVAL: u64 = 100;
@@ -0,0 +1,290 @@
+use core::fmt::{self, Write};
+
+#[cfg(feature = "defmt")]
+use defmt::info;
+use embassy_futures::join::join3;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_time::Timer;
+use embassy_usb::Builder;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, Receiver, Sender, State};
+use heapless::String;
+use static_cell::StaticCell;
+
+use crate::config::{CONSOLE_LINE_MAX, CONSOLE_LOG_INTERVAL, MATRIX_KEYS_BUFFER};
+use crate::matrix::KeyPos;
+use crate::output::OutputMode;
+use crate::profile::ProfileCommand;
+use crate::usb::UsbDriver;
+use crate::{
+    ACTIVE_LAYER, BATTERY_LEVEL, HOST_BONDS, KEYBOARD_LEDS, MATRIX_KEYS_LOCAL, MATRIX_KEYS_SPLIT,
+    OUTPUT_MODE, PROFILE_COMMAND, SPLIT, enter_bootloader,
+};
+
+/// Size of the usb serial packets
+const CONSOLE_PACKET_SIZE: usize = 64;
+
+/// Lines waiting to go out to the console, dropped while nobody reads the console
+static CONSOLE_OUT: Pipe<CriticalSectionRawMutex, 512> = Pipe::new();
+
+const HELP: &str = "commands:\r
+  battery        battery level\r
+  bonds          hosts bonded to the host profiles\r
+  clear-bonds    forget the hosts of every profile\r
+  layer          highest active layer\r
+  matrix         keys held on the matrix\r
+  bootloader     restart into the uf2 bootloader\r
+  factory-reset  erase the storage and restart\r
+";
+
+/// Add the usb serial console to the usb device
+pub(crate) fn console_class(
+    builder: &mut Builder<'static, UsbDriver>,
+) -> CdcAcmClass<'static, UsbDriver> {
+    let state = {
+        static STATE: StaticCell<State> = StaticCell::new();
+        STATE.init(State::new())
+    };
+    CdcAcmClass::new(builder, state, CONSOLE_PACKET_SIZE as u16)
+}
+
+/// Run the usb serial console: log lines of the keyboard state and a command shell
+pub(crate) async fn console_run(class: CdcAcmClass<'static, UsbDriver>) {
+    let (mut sender, mut receiver) = class.split();
+
+    join3(
+        console_write_task(&mut sender),
+        console_read_task(&mut receiver),
+        console_log_task(),
+    )
+    .await;
+}
+
+/// Print a line on the console, the line is cut to the console line size
+fn print(args: fmt::Arguments) {
+    let mut line: String<CONSOLE_LINE_MAX> = String::new();
+    let _ = line.write_fmt(args);
+
+    let mut out: String<{ CONSOLE_LINE_MAX + 2 }> = String::new();
+    let _ = out.push_str(&line);
+    let _ = out.push_str("\r\n");
+    console_write(out.as_bytes());
+}
+
+/// Queue bytes for the console in one piece, they are dropped when the rest does not fit so
+/// no line goes out cut or without its line end
+fn console_write(bytes: &[u8]) {
+    if CONSOLE_OUT.free_capacity() >= bytes.len() {
+        let _ = CONSOLE_OUT.try_write(bytes);
+    }
+}
+
+/// Send the printed lines to the host
+async fn console_write_task(sender: &mut Sender<'static, UsbDriver>) {
+    let mut buff = [0u8; CONSOLE_PACKET_SIZE];
+
+    loop {
+        sender.wait_connection().await;
+
+        let n = CONSOLE_OUT.read(&mut buff).await;
+        // the lines are dropped while no terminal is open
+        if sender.dtr() {
+            let _ = sender.write_packet(&buff[..n]).await;
+        }
+    }
+}
+
+/// Read the commands typed on the console, echoing them back
+async fn console_read_task(receiver: &mut Receiver<'static, UsbDriver>) {
+    let mut buff = [0u8; CONSOLE_PACKET_SIZE];
+    let mut line: String<CONSOLE_LINE_MAX> = String::new();
+
+    loop {
+        receiver.wait_connection().await;
+
+        let Ok(n) = receiver.read_packet(&mut buff).await else {
+            continue;
+        };
+
+        for &byte in &buff[..n] {
+            match byte {
+                b'\r' | b'\n' => {
+                    console_write(b"\r\n");
+                    if !line.is_empty() {
+                        run_command(line.trim()).await;
+                        line.clear();
+                    }
+                }
+                // backspace and delete
+                0x08 | 0x7f => {
+                    if line.pop().is_some() {
+                        console_write(b"\x08 \x08");
+                    }
+                }
+                byte if byte.is_ascii_graphic() || byte == b' ' => {
+                    if line.push(byte as char).is_ok() {
+                        console_write(&[byte]);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Run a command of the shell
+async fn run_command(command: &str) {
+    #[cfg(feature = "defmt")]
+    info!("[console] command: {}", command);
+
+    match command {
+        "help" => {
+            console_write(HELP.as_bytes());
+        }
+        "battery" => match BATTERY_LEVEL.anon_receiver().try_get() {
+            Some(level) => print(format_args!("battery: {}%", level)),
+            None => print(format_args!("battery: not measured yet")),
+        },
+        "bonds" => match HOST_BONDS.anon_receiver().try_get() {
+            Some(bonds) => {
+                for (profile, host) in bonds.hosts.iter().enumerate() {
+                    let active = if profile == bonds.active as usize {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    match host {
+                        Some(addr) => {
+                            // the address is shown most significant byte first
+                            let mut address: String<17> = String::new();
+                            for (index, byte) in addr.raw().iter().rev().enumerate() {
+                                let separator = if index == 0 { "" } else { ":" };
+                                let _ = write!(address, "{}{:02x}", separator, byte);
+                            }
+                            print(format_args!("{}profile {}: {}", active, profile, address));
+                        }
+                        None => print(format_args!("{}profile {}: free", active, profile)),
+                    }
+                }
+            }
+            None => print(format_args!("no host profiles")),
+        },
+        "clear-bonds" => {
+            PROFILE_COMMAND.sender().send(ProfileCommand::ClearAll);
+            print(format_args!("host bonds cleared"));
+        }
+        "layer" => print(format_args!(
+            "layer: {}",
+            ACTIVE_LAYER.anon_receiver().try_get().unwrap_or(0)
+        )),
+        "matrix" => {
+            if SPLIT {
+                print_keys(
+                    "peripheral half",
+                    MATRIX_KEYS_LOCAL.anon_receiver().try_get(),
+                );
+                print_keys("central half", MATRIX_KEYS_SPLIT.anon_receiver().try_get());
+            } else {
+                print_keys("matrix", MATRIX_KEYS_LOCAL.anon_receiver().try_get());
+            }
+        }
+        "bootloader" => {
+            print(format_args!("restarting into the bootloader"));
+            // give the line time to reach the host
+            Timer::after_millis(100).await;
+            enter_bootloader();
+        }
+        "factory-reset" => {
+            print(format_args!("erasing the storage and restarting"));
+            Timer::after_millis(100).await;
+            PROFILE_COMMAND.sender().send(ProfileCommand::FactoryReset);
+        }
+        _ => print(format_args!("unknown command, type help")),
+    }
+}
+
+/// Print the held keys of a matrix
+fn print_keys(name: &str, keys: Option<[KeyPos; MATRIX_KEYS_BUFFER]>) {
+    let mut line: String<CONSOLE_LINE_MAX> = String::new();
+
+    for key in keys
+        .iter()
+        .flatten()
+        .filter(|key| **key != KeyPos::default())
+    {
+        let _ = write!(line, " r{}c{}", key.row, key.col);
+    }
+
+    if line.is_empty() {
+        print(format_args!("{}: no keys held", name));
+    } else {
+        print(format_args!("{}:{}", name, line));
+    }
+}
+
+/// Log the changes of the keyboard state
+async fn console_log_task() {
+    let mut battery_level = BATTERY_LEVEL.anon_receiver();
+    let mut active_layer = ACTIVE_LAYER.anon_receiver();
+    let mut output_mode = OUTPUT_MODE.anon_receiver();
+    let mut keyboard_leds = KEYBOARD_LEDS.anon_receiver();
+    let mut host_bonds = HOST_BONDS.anon_receiver();
+
+    let mut battery_level_old = None;
+    let mut active_layer_old = None;
+    let mut output_mode_old = None;
+    let mut keyboard_leds_old = None;
+    let mut active_profile_old = None;
+
+    loop {
+        let level = battery_level.try_get();
+        if level != battery_level_old {
+            if let Some(level) = level {
+                print(format_args!("[log] battery: {}%", level));
+            }
+            battery_level_old = level;
+        }
+
+        let layer = active_layer.try_get();
+        if layer != active_layer_old {
+            if let Some(layer) = layer {
+                print(format_args!("[log] layer: {}", layer));
+            }
+            active_layer_old = layer;
+        }
+
+        let mode = output_mode.try_get();
+        if mode != output_mode_old {
+            match mode {
+                Some(OutputMode::Auto) => print(format_args!("[log] output: auto")),
+                Some(OutputMode::Usb) => print(format_args!("[log] output: usb")),
+                Some(OutputMode::Ble) => print(format_args!("[log] output: ble")),
+                None => {}
+            }
+            output_mode_old = mode;
+        }
+
+        let leds = keyboard_leds.try_get();
+        if leds != keyboard_leds_old {
+            if let Some(leds) = leds {
+                print(format_args!(
+                    "[log] leds: num lock {}, caps lock {}, scroll lock {}",
+                    leds.num_lock(),
+                    leds.caps_lock(),
+                    leds.scroll_lock()
+                ));
+            }
+            keyboard_leds_old = leds;
+        }
+
+        let profile = host_bonds.try_get().map(|bonds| bonds.active);
+        if profile != active_profile_old {
+            if let Some(profile) = profile {
+                print(format_args!("[log] host profile: {}", profile));
+            }
+            active_profile_old = profile;
+        }
+
+        Timer::after_millis(CONSOLE_LOG_INTERVAL).await;
+    }
+}
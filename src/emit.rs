@@ -0,0 +1,240 @@
+use heapless::Vec;
+
+use crate::action::Action;
+use crate::config::KEYMAP_COLS;
+use crate::layer::LayerState;
+use crate::matrix::{Key, KeyPos, KeyState};
+use crate::{LAYERS, ROWS};
+
+/// Actions of every layer, row and col
+pub type Keymap = [[[Action; KEYMAP_COLS]; ROWS]; LAYERS];
+
+/// Change of an action held on the host, the key provision applies them in order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Emit {
+    Press(Action),
+    Release(Action),
+}
+
+/// Changes asked by a single key
+pub type Emits = Vec<Emit, 2>;
+
+/// Look up the action of a position, falling through transparent keys to the layers below
+pub fn resolve_action(keymap: &Keymap, layers: &LayerState, key_pos: &KeyPos) -> Action {
+    layers
+        .active_layers()
+        .map(|layer| keymap[layer as usize][key_pos.row as usize][key_pos.col as usize])
+        .find(|action| *action != Action::Transparent)
+        .unwrap_or(Action::NoOp)
+}
+
+/// Emit the action of a held key, replacing the previously emitted one if it changed
+pub fn press_key(key: &mut Key) -> Emits {
+    let mut emits = Emits::new();
+
+    // undecided tap-hold and combo keys emit nothing yet
+    if key.action.is_tap_hold() || key.pending_combo || key.emitted == Some(key.action) {
+        return emits;
+    }
+
+    if let Some(emitted) = key.emitted {
+        let _ = emits.push(Emit::Release(emitted));
+    }
+
+    let _ = emits.push(Emit::Press(key.action));
+    key.emitted = Some(key.action);
+    emits
+}
+
+/// Undo the action emitted by the press of a released key
+pub fn release_key(key: &mut Key) -> Emits {
+    let mut emits = Emits::new();
+
+    // released before its action was emitted (tap-hold resolved as tap or
+    // combo key let go), press it first so the host sees the tap
+    if key.emitted.is_none() && !key.action.is_tap_hold() {
+        let _ = emits.push(Emit::Press(key.action));
+        key.emitted = Some(key.action);
+    }
+
+    if let Some(emitted) = key.emitted.take() {
+        let _ = emits.push(Emit::Release(emitted));
+    }
+    emits
+}
+
+/// Undo the combo of a released trigger key, the trigger keys still held emit nothing more
+pub fn release_combo(keys: &mut [Key], index: usize) -> Emits {
+    let mut emits = Emits::new();
+
+    let Some(carrier) = keys[index].combo else {
+        return emits;
+    };
+    if keys[index].state != KeyState::Released {
+        return emits;
+    }
+
+    for key in keys.iter_mut().filter(|key| key.combo == Some(carrier)) {
+        if key.position == carrier {
+            emits = release_key(key);
+        }
+
+        key.action = Action::NoOp;
+        key.emitted = Some(Action::NoOp);
+        key.combo = None;
+    }
+    emits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycodes::KC;
+
+    fn pressed(action: Action, row: u8, col: u8) -> Key {
+        Key {
+            action,
+            position: KeyPos { row, col },
+            state: KeyState::Pressed,
+            ..Key::default()
+        }
+    }
+
+    #[test]
+    fn resolve_falls_through_transparent_keys() {
+        let mut keymap = [[[Action::Transparent; KEYMAP_COLS]; ROWS]; LAYERS];
+        keymap[0][0][0] = Action::Key(KC::A);
+        let mut layers = LayerState::new();
+        layers.on(1);
+
+        let key_pos = KeyPos { row: 0, col: 0 };
+        assert_eq!(
+            resolve_action(&keymap, &layers, &key_pos),
+            Action::Key(KC::A)
+        );
+
+        let key_pos = KeyPos { row: 0, col: 1 };
+        assert_eq!(resolve_action(&keymap, &layers, &key_pos), Action::NoOp);
+    }
+
+    #[test]
+    fn layer_switch_while_held_releases_the_pressed_keycode() {
+        let mut keymap = [[[Action::NoOp; KEYMAP_COLS]; ROWS]; LAYERS];
+        keymap[0][0][0] = Action::Key(KC::A);
+        keymap[1][0][0] = Action::Key(KC::B);
+        let mut layers = LayerState::new();
+        let key_pos = KeyPos { row: 0, col: 0 };
+
+        layers.on(1);
+        let mut key = pressed(resolve_action(&keymap, &layers, &key_pos), 0, 0);
+        assert_eq!(
+            press_key(&mut key).as_slice(),
+            [Emit::Press(Action::Key(KC::B))]
+        );
+
+        // held across the layer change, nothing new is emitted
+        layers.off(1);
+        assert!(press_key(&mut key).is_empty());
+
+        key.state = KeyState::Released;
+        assert_eq!(
+            release_key(&mut key).as_slice(),
+            [Emit::Release(Action::Key(KC::B))]
+        );
+    }
+
+    #[test]
+    fn changed_action_replaces_the_emitted_one() {
+        let mut key = pressed(Action::Key(KC::A), 0, 0);
+        press_key(&mut key);
+
+        key.action = Action::Key(KC::B);
+        assert_eq!(
+            press_key(&mut key).as_slice(),
+            [
+                Emit::Release(Action::Key(KC::A)),
+                Emit::Press(Action::Key(KC::B))
+            ]
+        );
+    }
+
+    #[test]
+    fn undecided_keys_emit_nothing() {
+        let mut tap_hold = pressed(
+            Action::LayerTap {
+                layer: 1,
+                tap: KC::A,
+            },
+            0,
+            0,
+        );
+        assert!(press_key(&mut tap_hold).is_empty());
+
+        let mut combo = Key {
+            pending_combo: true,
+            ..pressed(Action::Key(KC::A), 0, 1)
+        };
+        assert!(press_key(&mut combo).is_empty());
+    }
+
+    #[test]
+    fn key_released_before_emitted_is_tapped() {
+        let mut key = pressed(Action::Key(KC::A), 0, 0);
+        key.state = KeyState::Released;
+
+        assert_eq!(
+            release_key(&mut key).as_slice(),
+            [
+                Emit::Press(Action::Key(KC::A)),
+                Emit::Release(Action::Key(KC::A))
+            ]
+        );
+    }
+
+    /// Two trigger keys of a combo, the first one carries the combo action
+    fn combo_keys() -> [Key; 2] {
+        let carrier = KeyPos { row: 0, col: 0 };
+        let mut keys = [
+            Key {
+                combo: Some(carrier),
+                ..pressed(Action::Key(KC::Escape), 0, 0)
+            },
+            Key {
+                combo: Some(carrier),
+                ..pressed(Action::NoOp, 0, 1)
+            },
+        ];
+        for key in keys.iter_mut() {
+            press_key(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn releasing_any_combo_key_undoes_the_combo() {
+        for released in 0..2 {
+            let mut keys = combo_keys();
+            keys[released].state = KeyState::Released;
+
+            assert_eq!(
+                release_combo(&mut keys, released).as_slice(),
+                [Emit::Release(Action::Key(KC::Escape))]
+            );
+
+            // the combo is not undone twice, the other trigger key releases nothing
+            let other = 1 - released;
+            keys[other].state = KeyState::Released;
+            assert!(release_combo(&mut keys, other).is_empty());
+            for key in keys.iter_mut() {
+                assert_eq!(release_key(key).as_slice(), [Emit::Release(Action::NoOp)]);
+            }
+        }
+    }
+
+    #[test]
+    fn held_combo_key_is_not_released() {
+        let mut keys = combo_keys();
+        assert!(release_combo(&mut keys, 0).is_empty());
+        assert!(release_combo(&mut keys, 1).is_empty());
+    }
+}
@@ -1,81 +1,95 @@
 #[cfg(feature = "defmt")]
-use defmt::info;
-#[cfg(feature = "peripheral")]
-use embassy_futures::select::{Either, select};
-#[cfg(feature = "peripheral")]
+use defmt::{info, warn};
+use embassy_futures::select::{Either3, select3};
+use embassy_time::Timer;
 use usbd_hid::descriptor::KeyboardReport;
 
-#[cfg(feature = "peripheral")]
 use crate::{
-    KEY_REPORT, MATRIX_KEYS_SPLIT,
+    ACTIVE_LAYER, CENTRAL_KEY, COMBOS, CONSUMER_REPORT, KEY_REPORT, MACROS, MATRIX_KEYS_SPLIT,
+    MOUSE_KEYS, NKRO, NKRO_ENABLED, NKRO_REPORT, OUTPUT_MODE, PERIPHERAL_KEY, PROFILE_COMMAND,
+    SPLIT, TAPPING_TERM,
+    combo::{combo_timeout, is_combo_key},
+    config::TAP_DELAY,
+    delay_ms,
+    emit::{Emit, Keymap, press_key, release_combo, release_key, resolve_action},
+    enter_bootloader,
     keycodes::KeyType,
     keymap::provide_keymap,
-    {COLS, LAYERS, ROWS},
+    layer::LayerState,
+    macros::{MacroStep, ascii_to_kc},
+    mouse::MouseKeys,
+    nkro::NkroReport,
+    output::OutputMode,
+    profile::ProfileCommand,
 };
 
-#[cfg(feature = "central")]
-use crate::MESSAGE_TO_PERI;
-
 use crate::{
-    MATRIX_KEYS_LOCAL,
+    MATRIX_KEYS_LOCAL, MESSAGE_TO_PERI, ROLE,
+    action::Action,
     config::{MATRIX_KEYS_BUFFER, MATRIX_KEYS_COMB_BUFFER},
     keycodes::KC,
     matrix::{Key, KeyPos, KeyState},
+    split::protocol::{apply_key, sync_split_keys},
 };
 use embassy_time::{Duration, Instant};
 use heapless::Vec;
 
 pub struct KeyProvision {
-    #[cfg(feature = "peripheral")]
-    layer: u8,
-    #[cfg(feature = "peripheral")]
-    keymap: [[[KC; COLS * 2]; ROWS]; LAYERS],
-    #[cfg(feature = "peripheral")]
+    /// Whether the half runs the keymap, only known once ble has picked the role: the peripheral
+    /// half and the receiver dongle do, the other halves only forward their keys
+    runs_keymap: bool,
+    /// Role keys held at power-up, ignored till they are released
+    masked_keys: Vec<KeyPos, 2>,
+    layers: LayerState,
+    keymap: Keymap,
     keyreport_local: KeyboardReport,
-    #[cfg(feature = "central")]
-    message_to_peri_local: [u8; 6],
-    #[cfg(feature = "central")]
-    message_to_peri_local_old: [u8; 6],
+    nkro_report_local: NkroReport,
+    nkro: bool,
+    output_mode: OutputMode,
+    modifier_holds: [u8; 8],
+    consumer_report_local: u16,
+    mouse_keys_local: MouseKeys,
+    message_to_peri_local: [KeyPos; MATRIX_KEYS_BUFFER],
+    message_to_peri_local_old: [KeyPos; MATRIX_KEYS_BUFFER],
 }
 
 impl KeyProvision {
     pub fn init() -> Self {
         Self {
-            #[cfg(feature = "peripheral")]
-            layer: 0,
-            #[cfg(feature = "peripheral")]
+            runs_keymap: true,
+            masked_keys: Vec::new(),
+            layers: LayerState::new(),
             keymap: provide_keymap(),
-            #[cfg(feature = "peripheral")]
             keyreport_local: KeyboardReport::default(),
-
-            #[cfg(feature = "central")]
-            message_to_peri_local: [255; 6],
-            #[cfg(feature = "central")]
-            message_to_peri_local_old: [255; 6],
+            nkro_report_local: NkroReport::default(),
+            nkro: NKRO,
+            output_mode: OutputMode::Auto,
+            modifier_holds: [0; 8],
+            consumer_report_local: 0,
+            mouse_keys_local: MouseKeys::default(),
+            message_to_peri_local: [KeyPos::default(); MATRIX_KEYS_BUFFER],
+            message_to_peri_local_old: [KeyPos::default(); MATRIX_KEYS_BUFFER],
         }
     }
-    #[cfg(feature = "peripheral")]
     pub async fn provision_pressed_keys(&mut self, kc: &KC) {
         // get the key type
         match KeyType::check_type(kc) {
-            // KeyType::Macro => {
-            //     let macro_valid_keys = Kc::get_macro_sequence(hid_key);
-            //     for valid_key in macro_valid_keys.iter() {
-            //         add_keys_master(keyboard_key_report, mouse_key_report, valid_key, layer);
-            //     }
-            // }
-            KeyType::Layer => {
-                // check and set the layer
-                self.layer = kc.get_layer();
-            }
             KeyType::Modifier => {
-                self.keyreport_local.modifier |= kc.get_modifier();
+                self.press_modifiers(kc.get_modifier());
             }
-            // KeyType::Mouse => {
-            //     // set the mouse command to the mouse ble characteristic
-            //     mouse_key_report.set_command(hid_key);
-            // }
+            KeyType::Consumer => {
+                // the consumer report holds a single usage
+                self.consumer_report_local = kc.get_consumer();
+            }
+            KeyType::Mouse => {
+                // the mouse keys task turns the held keys into reports
+                self.mouse_keys_local.press(*kc);
+            }
+            KeyType::Function => self.provision_function(kc),
             KeyType::Key => {
+                // every key fits in the n-key rollover report
+                self.nkro_report_local.press(*kc as u8);
+
                 // check if the key count is less than 6
                 if !self.keyreport_local.keycodes.contains(&(*kc as u8)) {
                     // find the first key slot in the array that is free
@@ -95,29 +109,25 @@ impl KeyProvision {
         }
     }
 
-    #[cfg(feature = "peripheral")]
     async fn provision_released_keys(&mut self, kc: &KC) {
         // get the key type
         match KeyType::check_type(kc) {
-            //     KeyType::Macro => {
-            //         let macro_valid_keys = Kc::get_macro_sequence(hid_key);
-            //         for valid_key in macro_valid_keys.iter() {
-            //             remove_keys_master(keyboard_key_report, mouse_key_report, valid_key, layer);
-            //         }
-            //     }
-            KeyType::Layer => {
-                // set previous layer
-                self.layer -= 1;
-            }
             KeyType::Modifier => {
                 // remove the modifier
-                self.keyreport_local.modifier &= !kc.get_modifier();
+                self.release_modifiers(kc.get_modifier());
+            }
+            KeyType::Consumer => {
+                // remove the usage only if no other media key replaced it
+                if self.consumer_report_local == kc.get_consumer() {
+                    self.consumer_report_local = 0;
+                }
+            }
+            KeyType::Mouse => {
+                self.mouse_keys_local.release(*kc);
             }
-            // KeyType::Mouse => {
-            //     // remove the mouse command from the mouse ble characteristic
-            //     mouse_key_report.reset_keypress(hid_key);
-            // }
             KeyType::Key => {
+                self.nkro_report_local.release(*kc as u8);
+
                 // find the key index of the released key
                 if let Some(index) = self
                     .keyreport_local
@@ -133,53 +143,253 @@ impl KeyProvision {
         }
     }
 
-    async fn matrix_to_hid_local(
-        &self,
-        matrix_keys_local: &mut [Key; MATRIX_KEYS_COMB_BUFFER],
-        matrix_keys_received: &[KeyPos; MATRIX_KEYS_BUFFER],
-    ) {
-        for (index_received, key_pos_received) in matrix_keys_received.iter().enumerate() {
-            if *key_pos_received != KeyPos::default() {
+    /// Hold the modifiers of the mask
+    fn press_modifiers(&mut self, mods: u8) {
+        for (bit, holds) in self.modifier_holds.iter_mut().enumerate() {
+            if mods & (1 << bit) != 0 {
+                *holds = holds.saturating_add(1);
+            }
+        }
+        self.keyreport_local.modifier |= mods;
+    }
+
+    /// Release the modifiers of the mask, a modifier stays set while another key still holds it
+    fn release_modifiers(&mut self, mods: u8) {
+        for (bit, holds) in self.modifier_holds.iter_mut().enumerate() {
+            if mods & (1 << bit) != 0 {
+                *holds = holds.saturating_sub(1);
+                if *holds == 0 {
+                    self.keyreport_local.modifier &= !(1 << bit);
+                }
+            }
+        }
+    }
+
+    /// Apply the keyboard function keycodes
+    fn provision_function(&mut self, kc: &KC) {
+        match *kc {
+            KC::NkroToggle => {
+                self.nkro = !self.nkro;
+
                 #[cfg(feature = "defmt")]
-                info!(
-                    "[matrix_to_hid] matrix_keys_received: r{} c{}",
-                    key_pos_received.row, key_pos_received.col
-                );
+                info!("[key_provision] nkro: {}", self.nkro);
+            }
+            KC::OutAuto => self.output_mode = OutputMode::Auto,
+            KC::OutUsb => self.output_mode = OutputMode::Usb,
+            KC::OutBle => self.output_mode = OutputMode::Ble,
+            _ => {}
+        }
+    }
 
-                // if new key is not contained, add it
-                if !matrix_keys_local
-                    .iter()
-                    .any(|key| key.position == *key_pos_received)
-                {
-                    let key = Key {
-                        #[cfg(feature = "peripheral")]
-                        code: self.keymap[self.layer as usize][key_pos_received.row as usize]
-                            [key_pos_received.col as usize],
-
-                        #[cfg(feature = "central")]
-                        code: KC::Reserved,
-                        position: *key_pos_received,
-                        state: KeyState::Pressed,
-                        time: Instant::now(),
-                    };
+    /// Apply the press of an action
+    async fn provision_pressed_action(&mut self, action: &Action) {
+        match *action {
+            Action::Key(kc) => self.provision_pressed_keys(&kc).await,
+            Action::Keys(kcs) => {
+                for kc in kcs {
+                    self.provision_pressed_keys(kc).await;
+                }
+            }
+            Action::Modified { mods, kc } => {
+                self.press_modifiers(mods);
+                self.provision_pressed_keys(&kc).await;
+            }
+            Action::Momentary(layer) => self.layers.on(layer),
+            Action::To(layer) => self.layers.to(layer),
+            Action::DefaultLayer(layer) => self.layers.set_default(layer),
+            Action::Macro(index) => self.play_macro(index).await,
+            Action::Profile(profile) => PROFILE_COMMAND
+                .sender()
+                .send(ProfileCommand::Switch(profile)),
+            Action::ProfileClear => PROFILE_COMMAND.sender().send(ProfileCommand::Clear),
+            Action::ProfileClearAll => PROFILE_COMMAND.sender().send(ProfileCommand::ClearAll),
+            // toggle acts on release, tap-hold keys are resolved before being emitted
+            _ => {}
+        }
+    }
 
-                    // set the new key in an empty slot
-                    matrix_keys_local[index_received] = key;
+    /// Apply the release of an action
+    async fn provision_released_action(&mut self, action: &Action) {
+        match *action {
+            Action::Key(kc) => self.provision_released_keys(&kc).await,
+            Action::Keys(kcs) => {
+                for kc in kcs {
+                    self.provision_released_keys(kc).await;
                 }
-            } else if matrix_keys_local[index_received].position != KeyPos::default() {
-                matrix_keys_local[index_received].state = KeyState::Released;
             }
+            Action::Modified { mods, kc } => {
+                self.provision_released_keys(&kc).await;
+                self.release_modifiers(mods);
+            }
+            Action::Momentary(layer) => self.layers.off(layer),
+            Action::Toggle(layer) => self.layers.toggle(layer),
+            _ => {}
         }
     }
 
-    #[cfg(feature = "peripheral")]
-    async fn matrix_to_hid_split(
-        &self,
+    /// Send the keyboard reports, and the consumer report and mouse keys if they changed
+    fn send_reports(&self) {
+        let nkro_enabled_sender = NKRO_ENABLED.sender();
+        if nkro_enabled_sender.try_get() != Some(self.nkro) {
+            nkro_enabled_sender.send(self.nkro);
+        }
+
+        let active_layer_sender = ACTIVE_LAYER.sender();
+        if active_layer_sender.try_get() != Some(self.layers.highest()) {
+            active_layer_sender.send(self.layers.highest());
+        }
+
+        // the output is picked before the reports go out through it
+        let output_mode_sender = OUTPUT_MODE.sender();
+        if output_mode_sender.try_get() != Some(self.output_mode) {
+            output_mode_sender.send(self.output_mode);
+        }
+
+        // the keyboard report wakes the ble task, the n-key rollover report goes first
+        // and shares the modifier byte of the keyboard report
+        NKRO_REPORT.sender().send(NkroReport {
+            modifier: self.keyreport_local.modifier,
+            ..self.nkro_report_local
+        });
+        KEY_REPORT.sender().send(self.keyreport_local);
+
+        let consumer_report_sender = CONSUMER_REPORT.sender();
+        if consumer_report_sender.try_get() != Some(self.consumer_report_local) {
+            consumer_report_sender.send(self.consumer_report_local);
+        }
+
+        let mouse_keys_sender = MOUSE_KEYS.sender();
+        if mouse_keys_sender.try_get() != Some(self.mouse_keys_local) {
+            mouse_keys_sender.send(self.mouse_keys_local);
+        }
+    }
+
+    /// Send the current report and give the host time to see it
+    async fn send_macro_report(&self, delay: u64) {
+        self.send_reports();
+        delay_ms(delay).await;
+    }
+
+    /// Tap a keycode as part of a macro, with shift held if requested
+    async fn tap_macro_key(&mut self, kc: &KC, shift: bool, delay: u64) {
+        let modifier = self.keyreport_local.modifier;
+
+        if shift {
+            self.keyreport_local.modifier |= KC::LShift.get_modifier();
+        }
+        self.provision_pressed_keys(kc).await;
+        self.send_macro_report(delay).await;
+
+        self.provision_released_keys(kc).await;
+        self.keyreport_local.modifier = modifier;
+        self.send_macro_report(delay).await;
+    }
+
+    /// Play a macro as a series of reports, each one held for the macro delay
+    async fn play_macro(&mut self, index: u8) {
+        let Some(macro_keys) = MACROS.get(index as usize) else {
+            #[cfg(feature = "defmt")]
+            warn!("[key_provision] macro {} is not defined", index);
+            return;
+        };
+
+        for step in macro_keys.steps {
+            match *step {
+                MacroStep::Press(kc) => {
+                    self.provision_pressed_keys(&kc).await;
+                    self.send_macro_report(macro_keys.delay).await;
+                }
+                MacroStep::Release(kc) => {
+                    self.provision_released_keys(&kc).await;
+                    self.send_macro_report(macro_keys.delay).await;
+                }
+                MacroStep::Tap(kc) => {
+                    self.tap_macro_key(&kc, false, macro_keys.delay).await;
+                }
+                MacroStep::Delay(ms) => delay_ms(ms).await,
+                MacroStep::Text(text) => {
+                    for c in text.chars() {
+                        if let Some((kc, shift)) = ascii_to_kc(c) {
+                            self.tap_macro_key(&kc, shift, macro_keys.delay).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Emit the action of a held key, replacing the previously emitted one if it changed
+    async fn provision_pressed_key(&mut self, key: &mut Key) {
+        for emit in press_key(key) {
+            self.provision_emit(emit).await;
+        }
+    }
+
+    /// Undo the action emitted by the press of a released key
+    async fn provision_released_key(&mut self, key: &mut Key) {
+        for emit in release_key(key) {
+            self.provision_emit(emit).await;
+
+            // released before its action was emitted (tap-hold resolved as tap or
+            // combo key let go), the press goes out first so the host sees the tap
+            if let Emit::Press(Action::Key(_) | Action::Keys(_) | Action::Modified { .. }) = emit {
+                self.send_reports();
+
+                // give the report time to reach the host before releasing
+                delay_ms(TAP_DELAY).await;
+            }
+        }
+    }
+
+    /// Apply the press or release of an action
+    async fn provision_emit(&mut self, emit: Emit) {
+        match emit {
+            Emit::Press(action) => self.provision_pressed_action(&action).await,
+            Emit::Release(action) => self.provision_released_action(&action).await,
+        }
+    }
+
+    /// Create a newly pressed key from its matrix position
+    fn press_key(&mut self, key_pos: &KeyPos) -> Key {
+        let action = if self.runs_keymap {
+            // resolved once, later layer changes do not affect a held key
+            resolve_action(&self.keymap, &self.layers, key_pos)
+        } else {
+            // the keys are only forwarded, the keymap runs on the other side
+            Action::Key(KC::Reserved)
+        };
+
+        let key = Key {
+            action,
+            emitted: None,
+            pending_combo: false,
+            combo: None,
+            position: *key_pos,
+            state: KeyState::Pressed,
+            time: Instant::now(),
+        };
+
+        // hold back the keys which may start a combo
+        Key {
+            pending_combo: self.runs_keymap && is_combo_key(&key, self.layers.highest()),
+            ..key
+        }
+    }
+
+    async fn matrix_to_hid_local(
+        &mut self,
         matrix_keys_local: &mut [Key; MATRIX_KEYS_COMB_BUFFER],
         matrix_keys_received: &[KeyPos; MATRIX_KEYS_BUFFER],
     ) {
+        // a masked role key is typed again once released
+        self.masked_keys
+            .retain(|key_pos| matrix_keys_received.contains(key_pos));
+
         for (index_received, key_pos_received) in matrix_keys_received.iter().enumerate() {
-            let index_received = index_received + MATRIX_KEYS_BUFFER;
+            if self.masked_keys.contains(key_pos_received) {
+                continue;
+            }
+
             if *key_pos_received != KeyPos::default() {
                 #[cfg(feature = "defmt")]
                 info!(
@@ -192,16 +402,8 @@ impl KeyProvision {
                     .iter()
                     .any(|key| key.position == *key_pos_received)
                 {
-                    let key = Key {
-                        code: self.keymap[self.layer as usize][key_pos_received.row as usize]
-                            [key_pos_received.col as usize],
-                        position: *key_pos_received,
-                        state: KeyState::Pressed,
-                        time: Instant::now(),
-                    };
-
                     // set the new key in an empty slot
-                    matrix_keys_local[index_received] = key;
+                    matrix_keys_local[index_received] = self.press_key(key_pos_received);
                 }
             } else if matrix_keys_local[index_received].position != KeyPos::default() {
                 matrix_keys_local[index_received].state = KeyState::Released;
@@ -209,56 +411,258 @@ impl KeyProvision {
         }
     }
 
+    async fn matrix_to_hid_split(
+        &mut self,
+        matrix_keys_local: &mut [Key; MATRIX_KEYS_COMB_BUFFER],
+        matrix_keys_received: &[KeyPos; MATRIX_KEYS_BUFFER],
+    ) {
+        #[cfg(feature = "defmt")]
+        for key_pos_received in matrix_keys_received
+            .iter()
+            .filter(|pos| **pos != KeyPos::default())
+        {
+            info!(
+                "[matrix_to_hid] matrix_keys_received: r{} c{}",
+                key_pos_received.row, key_pos_received.col
+            );
+        }
+
+        // the keys of the other half sit in the upper part of the buffer, matched by position
+        // since the sender may move a held key to another slot
+        sync_split_keys(
+            &mut matrix_keys_local[MATRIX_KEYS_BUFFER..],
+            matrix_keys_received,
+            |pos| self.press_key(pos),
+        );
+    }
+
     /// Evaluate if condition is met to enter bootloader
     async fn evaluate_enter_bootloader(&self, key: &Key) {
         if Instant::now() >= key.time + Duration::from_secs(5) {
             let key_pos_enter_bl = KeyPos { row: 0, col: 0 };
             if key.position == key_pos_enter_bl {
-                // write to register to boot into BL
-                embassy_nrf::pac::POWER
-                    .gpregret()
-                    .write_value(embassy_nrf::pac::power::regs::Gpregret(0x57));
+                enter_bootloader();
+            }
+        }
+    }
+
+    /// Resolve the undecided tap-hold keys into their tap or hold action
+    async fn provision_tap_hold(&mut self, matrix_keys_local: &mut [Key; MATRIX_KEYS_COMB_BUFFER]) {
+        let now = Instant::now();
+
+        for index in 0..matrix_keys_local.len() {
+            let key = matrix_keys_local[index];
+
+            // resolved keys carry the action they resolved into
+            let (hold, tap) = match key.action {
+                Action::ModTap { hold, tap } => (Action::Key(hold), tap),
+                Action::LayerTap { layer, tap } => (Action::Momentary(layer), tap),
+                _ => continue,
+            };
+
+            // skip empty slots
+            if key.position == KeyPos::default() {
+                continue;
+            }
+
+            let tapping_term_elapsed = now >= key.time + Duration::from_millis(TAPPING_TERM);
+
+            match key.state {
+                KeyState::Pressed => {
+                    // another key pressed while this one is held makes it a hold
+                    let other_key_pressed = matrix_keys_local.iter().any(|other| {
+                        other.position != KeyPos::default()
+                            && other.position != key.position
+                            && other.state == KeyState::Pressed
+                            && other.time > key.time
+                    });
+
+                    if other_key_pressed || tapping_term_elapsed {
+                        matrix_keys_local[index].action = hold;
+
+                        if let Action::Momentary(_) = hold {
+                            self.hold_layer_tap(matrix_keys_local, index).await;
+                        }
+                    }
+                }
+                KeyState::Released => {
+                    if !tapping_term_elapsed {
+                        // released within the tapping term, the release path taps it
+                        matrix_keys_local[index].action = Action::Key(tap);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Turn on the layer of a layer-tap key resolved as hold, the keys pressed while holding it
+    /// and not emitted yet are resolved again on the new layer
+    async fn hold_layer_tap(
+        &mut self,
+        matrix_keys_local: &mut [Key; MATRIX_KEYS_COMB_BUFFER],
+        index: usize,
+    ) {
+        let time = matrix_keys_local[index].time;
+        let interrupted = |key: &Key| {
+            key.position != KeyPos::default() && key.emitted.is_none() && key.time > time
+        };
+
+        // the actions the interrupting keys got on press, combo keys carry their combo action
+        let mut pressed_actions = [Action::NoOp; MATRIX_KEYS_COMB_BUFFER];
+        for (action, key) in pressed_actions.iter_mut().zip(matrix_keys_local.iter()) {
+            if interrupted(key) {
+                *action = resolve_action(&self.keymap, &self.layers, &key.position);
+            }
+        }
 
-                // reboot into bl
-                cortex_m::peripheral::SCB::sys_reset();
+        self.provision_pressed_key(&mut matrix_keys_local[index])
+            .await;
+
+        for (action, key) in pressed_actions.iter().zip(matrix_keys_local.iter_mut()) {
+            if interrupted(key) && key.action == *action {
+                key.action = resolve_action(&self.keymap, &self.layers, &key.position);
             }
         }
     }
 
-    #[cfg(feature = "peripheral")]
+    /// Earliest instant at which an undecided tap-hold or combo key has to be resolved
+    fn next_deadline(&self, matrix_keys_local: &[Key; MATRIX_KEYS_COMB_BUFFER]) -> Instant {
+        let tap_hold_deadline = matrix_keys_local
+            .iter()
+            .filter(|key| {
+                key.action.is_tap_hold()
+                    && key.position != KeyPos::default()
+                    && key.state == KeyState::Pressed
+            })
+            .map(|key| key.time + Duration::from_millis(TAPPING_TERM));
+
+        let combo_deadline = matrix_keys_local
+            .iter()
+            .filter(|key| key.pending_combo && key.position != KeyPos::default())
+            .map(|key| key.time + Duration::from_millis(combo_timeout(key)));
+
+        tap_hold_deadline
+            .chain(combo_deadline)
+            .min()
+            .unwrap_or(Instant::MAX)
+    }
+
     /// Provision combo keys
     async fn provision_combos(&mut self, matrix_keys_local: &mut [Key; MATRIX_KEYS_COMB_BUFFER]) {
-        let keys_to_remove: Vec<KC, { MATRIX_KEYS_COMB_BUFFER }> = Vec::from([KC::LCtrl, KC::Dd]);
-        let keys_to_add: Vec<KC, { MATRIX_KEYS_COMB_BUFFER }> =
-            Vec::from([KC::LCtrl, KC::Backspace]);
+        let now = Instant::now();
+        let layer = self.layers.highest();
+
+        for combo in COMBOS.iter().filter(|combo| combo.enabled_on(layer)) {
+            // find a distinct pending key for every trigger key
+            let mut indexes: Vec<usize, { MATRIX_KEYS_COMB_BUFFER }> = Vec::new();
+            for combo_key in combo.keys {
+                match matrix_keys_local
+                    .iter()
+                    .enumerate()
+                    .position(|(index, key)| {
+                        key.pending_combo
+                            && key.state == KeyState::Pressed
+                            && combo_key.matches(key)
+                            && !indexes.contains(&index)
+                    }) {
+                    Some(index) => {
+                        if indexes.push(index).is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+
+            if indexes.len() != combo.keys.len() {
+                continue;
+            }
 
-        // check if all combo keys to remove are contained in the matrix keys
-        if keys_to_remove.iter().all(|remove_k| {
-            matrix_keys_local
+            // all trigger keys must be pressed within the combo window
+            let first = indexes
                 .iter()
-                .any(|contained_k| *remove_k == contained_k.code)
-        }) {
-            for (index_rm, kc_rm) in keys_to_remove.iter().enumerate() {
-                if let Some(index) = matrix_keys_local.iter().position(|k| k.code == *kc_rm) {
-                    matrix_keys_local[index].code = keys_to_add[index_rm];
-                }
+                .map(|index| matrix_keys_local[*index].time)
+                .min();
+            let last = indexes
+                .iter()
+                .map(|index| matrix_keys_local[*index].time)
+                .max();
+            if let (Some(first), Some(last)) = (first, last)
+                && last - first > Duration::from_millis(combo.timeout)
+            {
+                continue;
+            }
+
+            #[cfg(feature = "defmt")]
+            info!("[key_provision] combo triggered: {:?}", combo.action);
+
+            // the first trigger key carries the combo action, the others are consumed
+            let carrier = matrix_keys_local[indexes[0]].position;
+            for (count, index) in indexes.iter().enumerate() {
+                let key = &mut matrix_keys_local[*index];
+                key.pending_combo = false;
+                key.combo = Some(carrier);
+                key.action = if count == 0 {
+                    combo.action
+                } else {
+                    Action::NoOp
+                };
+            }
+        }
+
+        // let go the keys that can no longer complete a combo
+        for index in 0..matrix_keys_local.len() {
+            let key = matrix_keys_local[index];
+
+            if !key.pending_combo {
+                continue;
+            }
+
+            let timed_out = now >= key.time + Duration::from_millis(combo_timeout(&key));
+
+            // a newer key outside of the combos interrupts it
+            let other_key_pressed = matrix_keys_local.iter().any(|other| {
+                other.position != KeyPos::default()
+                    && !other.pending_combo
+                    && other.state == KeyState::Pressed
+                    && other.time > key.time
+            });
+
+            if key.state == KeyState::Released || timed_out || other_key_pressed {
+                matrix_keys_local[index].pending_combo = false;
             }
         }
     }
 
     /// Main provision loop
     pub async fn run(&mut self) {
+        // wait for ble to pick the role of the half
+        let role = ROLE
+            .receiver()
+            .expect("[key_provision] unable to create role receiver")
+            .get()
+            .await;
+
+        // the dongle runs the keymap, both halves of a dongle setup only send their keys
+        self.runs_keymap = role.runs_keymap();
+
         let mut matrix_keys_receiver = MATRIX_KEYS_LOCAL
             .receiver()
             .expect("[key_provision] unable to create matrix_key_local_receiver");
-        #[cfg(feature = "peripheral")]
+
+        // the role key held at power-up picked the role, it is not typed
+        if SPLIT && let Some(held) = matrix_keys_receiver.try_get() {
+            for role_key in [PERIPHERAL_KEY, CENTRAL_KEY] {
+                if held.contains(&role_key) {
+                    let _ = self.masked_keys.push(role_key);
+                }
+            }
+        }
+        // the split keys only ever arrive on the peripheral
         let mut matrix_keys_split_receiver = MATRIX_KEYS_SPLIT
             .receiver()
             .expect("[key_provision] unable to create matrix_key_split_receiver");
 
-        #[cfg(feature = "peripheral")]
-        let key_report_sender = KEY_REPORT.sender();
-        #[cfg(feature = "central")]
         let message_to_peri = MESSAGE_TO_PERI.sender();
 
         let mut matrix_keys_local = [Key::default(); MATRIX_KEYS_COMB_BUFFER];
@@ -266,35 +670,35 @@ impl KeyProvision {
         let mut keys_to_remove: Vec<Key, { MATRIX_KEYS_COMB_BUFFER }> = Vec::new();
 
         loop {
-            #[cfg(feature = "peripheral")]
-            match select(
+            match select3(
                 matrix_keys_receiver.changed(),
                 matrix_keys_split_receiver.changed(),
+                Timer::at(self.next_deadline(&matrix_keys_local)),
             )
             .await
             {
-                Either::First(matrix_keys_received) => {
+                Either3::First(matrix_keys_received) => {
                     // transform the received local matrix keys
                     self.matrix_to_hid_local(&mut matrix_keys_local, &matrix_keys_received)
                         .await;
                 }
-                Either::Second(matrix_keys_split_received) => {
+                Either3::Second(matrix_keys_split_received) => {
                     // transform the received split matrix keys
                     self.matrix_to_hid_split(&mut matrix_keys_local, &matrix_keys_split_received)
                         .await;
                 }
+                Either3::Third(()) => {
+                    // tapping term or combo window elapsed, resolved below
+                }
             }
 
-            #[cfg(feature = "central")]
-            {
-                let matrix_keys_received = matrix_keys_receiver.changed().await;
-                self.matrix_to_hid_local(&mut matrix_keys_local, &matrix_keys_received)
-                    .await;
-            }
+            if self.runs_keymap {
+                // provision combos
+                self.provision_combos(&mut matrix_keys_local).await;
 
-            // provision combos
-            #[cfg(feature = "peripheral")]
-            self.provision_combos(&mut matrix_keys_local).await;
+                // resolve tap-hold keys
+                self.provision_tap_hold(&mut matrix_keys_local).await;
+            }
 
             #[cfg(feature = "defmt")]
             info!(
@@ -302,53 +706,34 @@ impl KeyProvision {
                 matrix_keys_local
             );
 
-            // process the non default keys to keyreport
+            // releasing any trigger key of a combo releases the combo
+            for index in 0..matrix_keys_local.len() {
+                for emit in release_combo(&mut matrix_keys_local, index) {
+                    self.provision_emit(emit).await;
+                }
+            }
+
+            // process the registered keys to keyreport
             for key in matrix_keys_local
                 .iter_mut()
-                .filter(|key| key.code != KC::default())
+                .filter(|key| key.position != KeyPos::default())
             {
                 match key.state {
                     KeyState::Pressed => {
-                        #[cfg(feature = "peripheral")]
-                        // get the keycode
-                        self.provision_pressed_keys(&key.code).await;
-
-                        #[cfg(feature = "central")]
-                        {
-                            // set the row and shift 4 bits to left
-                            // append the col
-                            // row and col must be lower than 16 (fit in 4 bits)
-                            let key_to_send = (key.position.row << 4) | key.position.col;
-
-                            if !self.message_to_peri_local.contains(&key_to_send)
-                                && let Some(index) = self
-                                    .message_to_peri_local
-                                    .iter_mut()
-                                    .position(|key| *key == 255)
-                            {
-                                self.message_to_peri_local[index] = key_to_send;
-                            }
+                        if self.runs_keymap {
+                            // emit the action of the key
+                            self.provision_pressed_key(key).await;
+                        } else {
+                            // the held keys are sent to the side running the keymap
+                            apply_key(&mut self.message_to_peri_local, key.position, true);
                         }
                     }
                     KeyState::Released => {
-                        #[cfg(feature = "peripheral")]
-                        // remove the kc from keyreport_local
-                        self.provision_released_keys(&key.code).await;
-
-                        #[cfg(feature = "central")]
-                        {
-                            // set the row and shift 4 bits to left
-                            // append the col
-                            // row and col must be lower than 16 (fit in 4 bits)
-                            let key_to_rm = (key.position.row << 4) | key.position.col;
-
-                            if let Some(index) = self
-                                .message_to_peri_local
-                                .iter_mut()
-                                .position(|key| *key == key_to_rm)
-                            {
-                                self.message_to_peri_local[index] = 255;
-                            }
+                        if self.runs_keymap {
+                            // undo the action emitted on press
+                            self.provision_released_key(key).await;
+                        } else {
+                            apply_key(&mut self.message_to_peri_local, key.position, false);
                         }
 
                         // evaluate enter_bootloader
@@ -365,7 +750,7 @@ impl KeyProvision {
             // remove the released keys
             while let Some(key) = keys_to_remove.pop() {
                 #[cfg(feature = "defmt")]
-                info!("[key_provision] keys_to_remove key: {}", key.code as u8);
+                info!("[key_provision] keys_to_remove key: {:?}", key.position);
                 if let Some(position) = matrix_keys_local
                     .iter()
                     .position(|k| k.position == key.position)
@@ -375,29 +760,24 @@ impl KeyProvision {
             }
 
             // send report
-            #[cfg(feature = "peripheral")]
-            {
-                key_report_sender.send(self.keyreport_local);
+            if self.runs_keymap {
+                self.send_reports();
 
                 #[cfg(feature = "defmt")]
                 info!(
-                    "[key_provision] keyreport_local.keycodes: {:?}",
-                    self.keyreport_local.keycodes
+                    "[key_provision] keyreport_local.keycodes: {:?}, consumer: {:#x}",
+                    self.keyreport_local.keycodes, self.consumer_report_local
+                );
+            } else if self.message_to_peri_local != self.message_to_peri_local_old {
+                #[cfg(feature = "defmt")]
+                info!(
+                    "[key_provision] message_to_peri_local: {:?}",
+                    self.message_to_peri_local
                 );
-            }
-            #[cfg(feature = "central")]
-            {
-                if self.message_to_peri_local != self.message_to_peri_local_old {
-                    #[cfg(feature = "defmt")]
-                    info!(
-                        "[key_provision] message_to_peri_local: {:?}",
-                        self.message_to_peri_local
-                    );
 
-                    message_to_peri.send(self.message_to_peri_local);
+                message_to_peri.send(self.message_to_peri_local);
 
-                    self.message_to_peri_local_old = self.message_to_peri_local;
-                }
+                self.message_to_peri_local_old = self.message_to_peri_local;
             }
         }
     }
@@ -344,8 +344,8 @@ pub enum KC {
     BitwiseXor = KeyboardUsage::KeypadBitwiseXor as isize,
     LogicalXor = KeyboardUsage::KeypadLogicalXor as isize,
     Modulo = KeyboardUsage::KeypadModulo as isize,
-    LShift = KeyboardUsage::KeypadLeftShift as isize,
-    RightShift = KeyboardUsage::KeypadRightShift as isize,
+    KeypadLeftShift = KeyboardUsage::KeypadLeftShift as isize,
+    KeypadRightShift = KeyboardUsage::KeypadRightShift as isize,
     BitwiseAnd = KeyboardUsage::KeypadBitwiseAnd as isize,
     LogicalAnd = KeyboardUsage::KeypadLogicalAnd as isize,
     BitwiseOr = KeyboardUsage::KeypadBitwiseOr as isize,
@@ -377,10 +377,10 @@ pub enum KC {
     // ------------------------------------------------------------------------
     // 0xE0‑0xE7: Modifier keys
     LCtrl = KeyboardUsage::KeyboardLeftControl as isize,
-    LeftShift = KeyboardUsage::KeyboardLeftShift as isize,
+    LShift = KeyboardUsage::KeyboardLeftShift as isize,
     LAlt = KeyboardUsage::KeyboardLeftAlt as isize,
     LGUI = KeyboardUsage::KeyboardLeftGUI as isize,
-    RCtrs = KeyboardUsage::KeyboardRightControl as isize,
+    RCtrl = KeyboardUsage::KeyboardRightControl as isize,
     RShift = KeyboardUsage::KeyboardRightShift as isize,
     RAlt = KeyboardUsage::KeyboardRightAlt as isize,
     RGUI = KeyboardUsage::KeyboardRightGUI as isize,
@@ -390,103 +390,153 @@ pub enum KC {
     Reserved = KeyboardUsage::Reserved as isize,
 
     // -----------------------------------------------------------------------
-    // Custom Internal Keycodes
-    /// Layer 1
-    L1 = 0xF0,
-    /// Layer 2
-    L2 = 0xF1,
-    /// Layer 3
-    L3 = 0xF2,
-    /// Layer 4
-    L4 = 0xF3,
-    /// Layer 5
-    L5 = 0xF4,
+    // Custom Internal Keycodes: consumer page, sent over the consumer report
+    /// Play / Pause
+    MediaPlayPause = 0x100,
+    /// Next track
+    MediaNext = 0x101,
+    /// Previous track
+    MediaPrev = 0x102,
+    /// Stop
+    MediaStop = 0x103,
+    /// Mute
+    AudioMute = 0x104,
+    /// Volume up
+    AudioVolUp = 0x105,
+    /// Volume down
+    AudioVolDown = 0x106,
+    /// Display brightness up
+    BrightnessUp = 0x107,
+    /// Display brightness down
+    BrightnessDown = 0x108,
+
+    // -----------------------------------------------------------------------
+    // Custom Internal Keycodes: mouse keys, handled by the mouse keys task
+    /// Move cursor left
+    MoGL = 0x110,
+    /// Move cursor down
+    MoGD = 0x111,
+    /// Move cursor up
+    MoGU = 0x112,
+    /// Move cursor right
+    MoGR = 0x113,
+    /// Left click
+    MoLC = 0x114,
+    /// Right click
+    MoRC = 0x115,
+    /// Middle click
+    MoMC = 0x116,
+    /// Scroll left
+    MoSL = 0x117,
+    /// Scroll right
+    MoSR = 0x118,
+    /// Scroll up
+    MoSU = 0x119,
+    /// Scroll down
+    MoSD = 0x11A,
+    /// Fast constant speed while held
+    MoCF = 0x11B,
+    /// Normal constant speed while held
+    MoCN = 0x11C,
+    /// Slow constant speed while held
+    MoCS = 0x11D,
+
+    // -----------------------------------------------------------------------
+    // Custom Internal Keycodes: keyboard functions, handled by key provision
+    /// Switch between the n-key rollover and the 6 keys report
+    NkroToggle = 0x120,
+    /// Send the reports over usb while a usb host is connected, over ble otherwise
+    OutAuto = 0x121,
+    /// Send the reports over usb only
+    OutUsb = 0x122,
+    /// Send the reports over ble only
+    OutBle = 0x123,
 }
 
 impl KC {
-    pub fn get_modifier(&self) -> u8 {
+    /// Bit of the modifier keycodes in the modifier byte of the keyboard report
+    pub const fn get_modifier(&self) -> u8 {
         match self {
             KC::LCtrl => 0x01,
             KC::LShift => 0x02,
             KC::LAlt => 0x04,
             KC::LGUI => 0x08,
-            // KC::RShift => {}
-            // KC::RCtrs => {}
-            // KC::RAlt => {}
-            // KC::RGUI => {}
+            KC::RCtrl => 0x10,
+            KC::RShift => 0x20,
+            KC::RAlt => 0x40,
+            KC::RGUI => 0x80,
             _ => 0x00,
         }
     }
 
-    pub fn get_layer(&self) -> u8 {
+    /// Consumer page usage of the consumer keycodes
+    pub fn get_consumer(&self) -> u16 {
         match self {
-            KC::L1 => 1,
-            KC::L2 => 2,
-            KC::L3 => 3,
-            KC::L4 => 4,
-            KC::L5 => 5,
-            _ => 0,
+            KC::MediaPlayPause => 0x00CD,
+            KC::MediaNext => 0x00B5,
+            KC::MediaPrev => 0x00B6,
+            KC::MediaStop => 0x00B7,
+            KC::AudioMute => 0x00E2,
+            KC::AudioVolUp => 0x00E9,
+            KC::AudioVolDown => 0x00EA,
+            KC::BrightnessUp => 0x006F,
+            KC::BrightnessDown => 0x0070,
+            _ => 0x0000,
         }
     }
 }
 
 pub enum KeyType {
     Combo,
-    Macro,
+    Consumer,
+    Function,
     Modifier,
     Mouse,
     Key,
-    Layer,
 }
 
 impl KeyType {
     pub fn check_type(key: &KC) -> KeyType {
         match *key {
-            // // return Macro key type
-            // KC::MaLP
-            // | KC::MaRP
-            // | KC::MaCp
-            // | KC::MaPa
-            // | KC::MaEx
-            // | KC::MaAt
-            // | KC::MaHs
-            // | KC::MaDl
-            // | KC::MaMd
-            // | KC::MaCa
-            // | KC::MaAmp
-            // | KC::MaAst
-            // | KC::MaSL
-            // | KC::MaLB
-            // | KC::MaRB
-            // | KC::MaPipe => KeyType::Macro,
-
-            // return Layer key type
-            KC::L1 | KC::L2 | KC::L3 | KC::L4 | KC::L5 => KeyType::Layer,
-
             // return Modifier key type
             KC::LShift
             | KC::LCtrl
             | KC::LAlt
             | KC::LGUI
             | KC::RShift
-            | KC::RCtrs
+            | KC::RCtrl
             | KC::RAlt
             | KC::RGUI => KeyType::Modifier,
 
-            // // return Mouse key type
-            // KC::MoGL
-            // | KC::MoGD
-            // | KC::MoGU
-            // | KC::MoGR
-            // | KC::MoLC
-            // | KC::MoRC
-            // | KC::MoSL
-            // | KC::MoSR
-            // | KC::MoSU
-            // | KC::MoSD
-            // | KC::MoCF
-            // | KC::MoCN
-            // | KC::MoCS => KeyType::Mouse,
+            // return Consumer key type
+            KC::MediaPlayPause
+            | KC::MediaNext
+            | KC::MediaPrev
+            | KC::MediaStop
+            | KC::AudioMute
+            | KC::AudioVolUp
+            | KC::AudioVolDown
+            | KC::BrightnessUp
+            | KC::BrightnessDown => KeyType::Consumer,
+
+            // return Mouse key type
+            KC::MoGL
+            | KC::MoGD
+            | KC::MoGU
+            | KC::MoGR
+            | KC::MoLC
+            | KC::MoRC
+            | KC::MoMC
+            | KC::MoSL
+            | KC::MoSR
+            | KC::MoSU
+            | KC::MoSD
+            | KC::MoCF
+            | KC::MoCN
+            | KC::MoCS => KeyType::Mouse,
+
+            // return Function key type
+            KC::NkroToggle | KC::OutAuto | KC::OutUsb | KC::OutBle => KeyType::Function,
 
             // return Combo key type
             // KC::ComboCtrlD => KeyType::Combo,
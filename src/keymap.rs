@@ -1,6 +1,8 @@
+use crate::action::{Action, NO, TRNS, k, lsft, mo};
 use crate::keycodes::KC;
 
-use crate::{COLS, LAYERS, ROWS};
+use crate::config::KEYMAP_COLS;
+use crate::{LAYERS, ROWS};
 
 //*****************************************************************************************
 // LAYER 0:
@@ -20,32 +22,34 @@ use crate::{COLS, LAYERS, ROWS};
 //   3             |SUPER|SPACE|SHIFT|              3 |_TAB_|ENTER|LYR_1|
 //
 //*****************************************************************************************
+// split keyboards map the local columns first, then the columns of the split half,
+// unibody keyboards (`split = false`) only map the local columns
 #[rustfmt::skip]
-pub fn provide_keymap() -> [[[KC; COLS * 2]; ROWS]; LAYERS] {
+pub fn provide_keymap() -> [[[Action; KEYMAP_COLS]; ROWS]; LAYERS] {
 
 [
     [
         /* LAYER 0 */  /*    COL 0           COL 1          COL 2           COL 3          COL 4                   COL 5         COL 6         COL 7       COL 8       COL 9     */
         /*               +--------------+--------------+---------------+--------------+---------------+        +------------+--------------+-----------+-----------+------------+*/
-        /*  ROW 0  */ [/*|*//*KC::EU,*/ KC::Quote,/*|*/KC::Comma,/*|*/KC::Period,/*|*/KC::Pp,   /*|*/KC::Yy,    /*|        |*/KC::Ff, /*|*/KC::Gg,   /*|*/KC::Cc,/*|*/KC::Rr,/*|*/KC::Ll /*,KC::EU*/, /*|*/],
+        /*  ROW 0  */ [/*|*//*KC::EU,*/ k(KC::Quote),/*|*/k(KC::Comma),/*|*/k(KC::Period),/*|*/k(KC::Pp),   /*|*/k(KC::Yy),    /*|        |*/k(KC::Ff), /*|*/k(KC::Gg),   /*|*/k(KC::Cc),/*|*/k(KC::Rr),/*|*/k(KC::Ll) /*,KC::EU*/, /*|*/],
         /*               +----------------+--------------+--------------+---------------+---------------+        +------------+--------------+-----------+-----------+------------+*/
-        /*  ROW 1  */ [/*|*//*KC::EU,*/KC::Aa,   /*|*/ KC::Oo,  /*|*/KC::Ee,    /*|*/KC::Uu,   /*|*/KC::Ii,    /*|        |*/KC::Dd, /*|*/KC::Hh,   /*|*/KC::Tt,/*|*/KC::Nn,/*|*/KC::Ss,/* KC::EU,*//*|*/],
+        /*  ROW 1  */ [/*|*//*KC::EU,*/k(KC::Aa),   /*|*/ k(KC::Oo),  /*|*/k(KC::Ee),    /*|*/k(KC::Uu),   /*|*/k(KC::Ii),    /*|        |*/k(KC::Dd), /*|*/k(KC::Hh),   /*|*/k(KC::Tt),/*|*/k(KC::Nn),/*|*/k(KC::Ss),/* KC::EU,*//*|*/],
         /*               +----------------+--------------+--------------+---------------+---------------+        +------------+--------------+-----------+-----------+------------+*/
-        /*  ROW 2  */ [/*|*//*KC::EU,*/KC::LCtrl,/*|*/ KC::Qq,  /*|*/KC::Jj,    /*|*/KC::Kk,   /*|*/KC::Xx,    /*|        |*/KC::Bb, /*|*/KC::Mm,   /*|*/KC::Ww,/*|*/KC::Vv,/*|*/KC::Zz,/* KC::EU,*//*|*/],
+        /*  ROW 2  */ [/*|*//*KC::EU,*/k(KC::LCtrl),/*|*/ k(KC::Qq),  /*|*/k(KC::Jj),    /*|*/k(KC::Kk),   /*|*/k(KC::Xx),    /*|        |*/k(KC::Bb), /*|*/k(KC::Mm),   /*|*/k(KC::Ww),/*|*/k(KC::Vv),/*|*/k(KC::Zz),/* KC::EU,*//*|*/],
         /*               +----------------+--------------+--------------+---------------+---------------+        +------------+--------------+-----------+-----------+------------+*/
-        /*  ROW 3  */ [/*|*//*KC::EU,*/KC::EU,   /*|*/ KC::EU,  /*|*/KC::LGUI,  /*|*/KC::Space,/*|*/KC::LShift,/*|        |*/KC::Tab,/*|*/KC::Enter,/*|*/KC::L1,/*|*/KC::EU,/*|*/KC::EU,/* KC::EU,*//*|*/ ],
+        /*  ROW 3  */ [/*|*//*KC::EU,*/NO,           /*|*/ NO,          /*|*/k(KC::LGUI),  /*|*/k(KC::Space),/*|*/k(KC::LShift),/*|        |*/k(KC::Tab),/*|*/k(KC::Enter),/*|*/mo(1),   /*|*/NO,        /*|*/NO,        /* KC::EU,*//*|*/ ],
         /*               +----------------+--------------+--------------+---------------+---------------+        +------------+--------------+-----------+-----------+------------+*/
     ],
     [
         /* LAYER 1 */  /*    COL 0            COL 1          COL 2          COL 3          COL 4                       COL 5             COL 6                 COL 7              COL 8                 COL 9          */
         /*               +--------------------+----------------+-----------+-------------+-------------+          +---------------+--------------------+---------------------+----------------------+------------------+*/
-        /*  ROW 0  */ [/*|*//*KC::EU,*/ KC::Escape,   /*|*/KC::K7,/*|*/KC::K8,  /*|*/KC::K9,   /*|*/KC::PrintS,/*|          |*/KC::EU,    /*|*/KC::OpenParens, /*|*/KC::CloseParens, /*|*/KC::Bslash,        /*|*/KC::Fslash, /*  KC::EU,*//*|*/],
+        /*  ROW 0  */ [/*|*//*KC::EU,*/ k(KC::Escape),   /*|*/k(KC::K7),/*|*/k(KC::K8),  /*|*/k(KC::K9),   /*|*/k(KC::PrintS),/*|          |*/NO,            /*|*/lsft(KC::K9)     , /*|*/lsft(KC::K0)      , /*|*/k(KC::Bslash),        /*|*/k(KC::Fslash), /*  KC::EU,*//*|*/],
         /*               +----------- ----------------+-----------+-------------+--------------+---------------+          +---------------+--------------------+---------------------+----------------------+-------------------------+*/
-        /*  ROW 1  */ [/*|*//*KC::EU,*/ KC::Backspace,/*|*/KC::K4,/*|*/KC::K5,  /*|*/KC::K6,   /*|*/KC::Delete,/*|          |*/KC::Dash,  /*|*/KC::LeftArr,    /*|*/KC::DownArr,     /*|*/KC::UpArr,        /*|*/KC::RightArr,/* KC::EU,*//*|*/],
+        /*  ROW 1  */ [/*|*//*KC::EU,*/ k(KC::Backspace),/*|*/k(KC::K4),/*|*/k(KC::K5),  /*|*/k(KC::K6),   /*|*/k(KC::Delete),/*|          |*/k(KC::Dash),  /*|*/k(KC::LeftArr),    /*|*/k(KC::DownArr),     /*|*/k(KC::UpArr),        /*|*/k(KC::RightArr),/* KC::EU,*//*|*/],
         /*               +----------- ----------------+-----------+-------------+--------------+---------------+          +---------------+--------------------+---------------------+----------------------+-------------------------++*/
-        /*  ROW 2  */ [/*|*//*KC::EU,*/ KC::K0,       /*|*/KC::K1,/*|*/KC::K2,  /*|*/KC::K3,   /*|*/KC::LAlt,  /*|          |*/KC::Equal,/*|*/KC::OpenBracket,/*|*/KC::CloseBracket,/*|*/KC::BacktickTilde,/*|*/KC::SemiColon,/* KC::EU,*//*|*/],
+        /*  ROW 2  */ [/*|*//*KC::EU,*/ k(KC::K0),       /*|*/k(KC::K1),/*|*/k(KC::K2),  /*|*/k(KC::K3),   /*|*/k(KC::LAlt),  /*|          |*/k(KC::Equal),/*|*/k(KC::OpenBracket),/*|*/k(KC::CloseBracket),/*|*/k(KC::BacktickTilde),/*|*/k(KC::SemiColon),/* KC::EU,*//*|*/],
         /*               +----------- ----------------+-----------+-------------+--------------+---------------+          +---------------+--------------------+---------------------+----------------------+-------------------------+*/
-        /*  ROW 3  */ [/*|*//*KC::EU,*/ KC::EU,       /*|*/KC::EU,/*|*/KC::LGUI,/*|*/KC::Space,/*|*/KC::LShift,/*|          |*/KC::Tab,   /*|*/KC::Enter,      /*|*/KC::L1,          /*|*/KC::EU,           /*|*/KC::EU,      /* KC::EU,*//*|*/],
+        /*  ROW 3  */ [/*|*//*KC::EU,*/ TRNS,             /*|*/TRNS,      /*|*/TRNS,        /*|*/TRNS,         /*|*/TRNS,          /*|          |*/TRNS,          /*|*/TRNS,               /*|*/TRNS,                /*|*/TRNS,                 /*|*/TRNS,            /* KC::EU,*//*|*/],
         /*               +------------------+----------------+-----------+-------------+-------------+          +---------------+--------------------+---------------------+----------------------+------------------+*/
     ],
 ]}
@@ -0,0 +1,150 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+use crate::LAYERS;
+
+// every layer has its bit in the enabled mask
+const _: () = assert!(
+    LAYERS <= u32::BITS as usize,
+    "at most 32 layers are supported"
+);
+
+/// Enabled layers as a bitmask on top of the default layer
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LayerState {
+    default: u8,
+    enabled: u32,
+}
+
+impl LayerState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enable a layer
+    pub fn on(&mut self, layer: u8) {
+        if (layer as usize) < LAYERS {
+            self.enabled |= 1 << layer;
+        }
+    }
+
+    /// Disable a layer
+    pub fn off(&mut self, layer: u8) {
+        if (layer as usize) < LAYERS {
+            self.enabled &= !(1 << layer);
+        }
+    }
+
+    /// Toggle a layer
+    pub fn toggle(&mut self, layer: u8) {
+        if (layer as usize) < LAYERS {
+            self.enabled ^= 1 << layer;
+        }
+    }
+
+    /// Enable only the given layer, besides the default one
+    pub fn to(&mut self, layer: u8) {
+        if (layer as usize) < LAYERS {
+            self.enabled = 1 << layer;
+        }
+    }
+
+    /// Set the default layer, the base all other layers are stacked on
+    pub fn set_default(&mut self, layer: u8) {
+        if (layer as usize) < LAYERS {
+            self.default = layer;
+        }
+    }
+
+    /// Whether the layer is enabled or is the default one
+    pub fn is_active(&self, layer: u8) -> bool {
+        layer == self.default || self.enabled & (1 << layer) != 0
+    }
+
+    /// Active layers from the highest to the lowest
+    pub fn active_layers(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..LAYERS as u8)
+            .rev()
+            .filter(|layer| self.is_active(*layer))
+    }
+
+    /// Highest active layer
+    pub fn highest(&self) -> u8 {
+        let active = self.enabled | (1 << self.default);
+        (u32::BITS - 1 - active.leading_zeros()) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Highest layer of the keymap
+    const TOP: u8 = LAYERS as u8 - 1;
+
+    #[test]
+    fn momentary_layer_is_active_while_held() {
+        let mut layers = LayerState::new();
+        assert_eq!(layers.highest(), 0);
+
+        layers.on(TOP);
+        assert!(layers.is_active(TOP));
+        assert_eq!(layers.highest(), TOP);
+        assert_eq!(layers.active_layers().collect::<Vec<_>>(), [TOP, 0]);
+
+        layers.off(TOP);
+        assert!(!layers.is_active(TOP));
+        assert_eq!(layers.highest(), 0);
+    }
+
+    #[test]
+    fn toggled_layer_stays_till_toggled_again() {
+        let mut layers = LayerState::new();
+
+        layers.toggle(TOP);
+        assert_eq!(layers.highest(), TOP);
+
+        layers.toggle(TOP);
+        assert_eq!(layers, LayerState::new());
+    }
+
+    #[test]
+    fn to_layer_replaces_the_enabled_ones() {
+        let mut layers = LayerState::new();
+        layers.on(TOP);
+
+        layers.to(0);
+        assert!(!layers.is_active(TOP));
+        assert_eq!(layers.highest(), 0);
+
+        layers.to(TOP);
+        assert_eq!(layers.active_layers().collect::<Vec<_>>(), [TOP, 0]);
+    }
+
+    #[test]
+    fn default_layer_is_active_underneath() {
+        let mut layers = LayerState::new();
+
+        layers.set_default(TOP);
+        assert!(!layers.is_active(0));
+        assert_eq!(layers.highest(), TOP);
+
+        // releasing a momentary layer on the default one keeps it
+        layers.on(TOP);
+        layers.off(TOP);
+        assert!(layers.is_active(TOP));
+        assert_eq!(layers.active_layers().collect::<Vec<_>>(), [TOP]);
+    }
+
+    #[test]
+    fn layers_beyond_the_keymap_are_ignored() {
+        let mut layers = LayerState::new();
+
+        layers.on(TOP + 1);
+        layers.toggle(TOP + 1);
+        layers.to(TOP + 1);
+        layers.set_default(TOP + 1);
+        assert_eq!(layers, LayerState::new());
+    }
+}
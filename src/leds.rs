@@ -0,0 +1,29 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+/// Lock LED state set by the host, bitmap of the keyboard LED output report
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct KeyboardLeds(pub u8);
+
+impl KeyboardLeds {
+    pub fn num_lock(&self) -> bool {
+        self.0 & 0x01 != 0
+    }
+
+    pub fn caps_lock(&self) -> bool {
+        self.0 & 0x02 != 0
+    }
+
+    pub fn scroll_lock(&self) -> bool {
+        self.0 & 0x04 != 0
+    }
+
+    pub fn compose(&self) -> bool {
+        self.0 & 0x08 != 0
+    }
+
+    pub fn kana(&self) -> bool {
+        self.0 & 0x10 != 0
+    }
+}
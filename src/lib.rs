@@ -1,44 +1,123 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
+// the modules driving the nrf peripherals are left out of the host tests
+pub mod action;
+#[cfg(not(test))]
 pub mod battery;
+#[cfg(not(test))]
 pub mod ble;
+pub mod combo;
 pub mod config;
+#[cfg(all(feature = "console", not(test)))]
+pub mod console;
+pub mod emit;
+#[cfg(not(test))]
 pub mod key_provision;
 pub mod keycodes;
 pub mod keymap;
+pub mod layer;
+pub mod leds;
+pub mod macros;
 pub mod matrix;
+pub mod mouse;
+pub mod nkro;
+pub mod output;
+#[cfg(not(test))]
 pub mod peripherals;
+pub mod profile;
+pub mod role;
+pub mod split;
 pub mod storage;
+#[cfg(not(test))]
+pub mod usb;
 
-use crate::{config::MATRIX_KEYS_BUFFER, matrix::KeyPos};
+use crate::{config::MATRIX_KEYS_BUFFER, leds::KeyboardLeds, matrix::KeyPos, role::Role};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
 
-/// Shared variable between matrix scan and key provision tasks
+/// Shared variable between matrix scan, role selection and key provision tasks
 pub static MATRIX_KEYS_LOCAL: Watch<CriticalSectionRawMutex, [KeyPos; MATRIX_KEYS_BUFFER], 2> =
     Watch::new();
 
-#[cfg(feature = "peripheral")]
+/// Shared variable between ble, key provision and usb tasks, the role picked at power-up
+pub static ROLE: Watch<CriticalSectionRawMutex, Role, 2> = Watch::new();
+
 use usbd_hid::descriptor::KeyboardReport;
 
-#[cfg(feature = "peripheral")]
-/// Shared variable between ble and key provision tasks
-pub static KEY_REPORT: Watch<CriticalSectionRawMutex, KeyboardReport, 2> = Watch::new();
+/// Shared variable between key provision and the ble and usb tasks
+pub static KEY_REPORT: Watch<CriticalSectionRawMutex, KeyboardReport, 3> = Watch::new();
+
+use crate::nkro::NkroReport;
+
+/// Shared variable between key provision and the ble and usb tasks, n-key rollover report
+pub static NKRO_REPORT: Watch<CriticalSectionRawMutex, NkroReport, 3> = Watch::new();
+
+/// Shared variable between key provision and the ble and usb tasks, whether the n-key rollover
+/// report is used
+pub static NKRO_ENABLED: Watch<CriticalSectionRawMutex, bool, 3> = Watch::new();
+
+/// Shared variable between key provision and the ble and usb tasks, consumer usage of the
+/// pressed media key
+pub static CONSUMER_REPORT: Watch<CriticalSectionRawMutex, u16, 3> = Watch::new();
+
+use crate::mouse::{MouseKeys, MouseReport};
+
+/// Shared variable between key provision and mouse keys tasks, the held mouse keys
+pub static MOUSE_KEYS: Watch<CriticalSectionRawMutex, MouseKeys, 2> = Watch::new();
+
+/// Shared variable between mouse keys and the ble and usb tasks
+pub static MOUSE_REPORT: Watch<CriticalSectionRawMutex, MouseReport, 3> = Watch::new();
+
+use crate::output::OutputMode;
+
+/// Shared variable between key provision and the ble and usb tasks, the output picked with the
+/// output keycodes
+pub static OUTPUT_MODE: Watch<CriticalSectionRawMutex, OutputMode, 1> = Watch::new();
+
+/// Shared variable between the usb and the ble tasks, whether a usb host has configured the
+/// keyboard
+pub static USB_UP: Watch<CriticalSectionRawMutex, bool, 1> = Watch::new();
+
+use crate::profile::{HostBonds, ProfileCommand};
+
+/// Shared variable between key provision and ble tasks, host profile commands
+pub static PROFILE_COMMAND: Watch<CriticalSectionRawMutex, ProfileCommand, 1> = Watch::new();
 
-#[cfg(feature = "peripheral")]
 /// Shared variable between matrix scan and key provision tasks
 pub static MATRIX_KEYS_SPLIT: Watch<CriticalSectionRawMutex, [KeyPos; MATRIX_KEYS_BUFFER], 2> =
     Watch::new();
 
-#[cfg(feature = "central")]
-/// Shared variable between ble and key provision tasks
-pub static MESSAGE_TO_PERI: Watch<CriticalSectionRawMutex, [u8; 6], 2> = Watch::new();
+/// Shared variable between ble and key provision tasks, the keys held on the central half
+pub static MESSAGE_TO_PERI: Watch<CriticalSectionRawMutex, [KeyPos; MATRIX_KEYS_BUFFER], 2> =
+    Watch::new();
+
+/// Shared variable between ble and console tasks, the hosts bonded to the host profiles
+pub static HOST_BONDS: Watch<CriticalSectionRawMutex, HostBonds, 1> = Watch::new();
+
+/// Shared variable between key provision, split link and console tasks, the highest active
+/// layer, received from the half running the keymap on the other half
+pub static ACTIVE_LAYER: Watch<CriticalSectionRawMutex, u8, 2> = Watch::new();
 
 /// Shared variable for battery percentage information
 pub static BATTERY_LEVEL: Watch<CriticalSectionRawMutex, u8, 3> = Watch::new();
 
+/// Shared variable for the lock LED state set by the host, forwarded to the split half
+pub static KEYBOARD_LEDS: Watch<CriticalSectionRawMutex, KeyboardLeds, 3> = Watch::new();
+
 use embassy_time::{Duration, Timer};
 
+/// Restart into the uf2 bootloader, which is flagged through the GPREGRET register
+#[cfg(not(test))]
+pub fn enter_bootloader() -> ! {
+    // write to register to boot into BL
+    embassy_nrf::pac::POWER
+        .gpregret()
+        .write_value(embassy_nrf::pac::power::regs::Gpregret(0x57));
+
+    // reboot into bl
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
 pub async fn delay_ms(delay: u64) {
     let duration = Duration::from_millis(delay);
     Timer::after(duration).await;
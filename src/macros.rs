@@ -0,0 +1,113 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+use crate::keycodes::KC;
+
+/// Single step of a macro
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum MacroStep {
+    /// Press and keep holding a keycode
+    Press(KC),
+    /// Release a held keycode
+    Release(KC),
+    /// Press and release a keycode
+    Tap(KC),
+    /// Wait for the given time in ms
+    Delay(u64),
+    /// Type an ASCII text (US layout)
+    Text(&'static str),
+}
+
+/// Macro generated from `user_config.toml` by the build script
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, Clone, Copy)]
+pub struct Macro {
+    pub steps: &'static [MacroStep],
+    /// Delay between two consecutive reports in ms
+    pub delay: u64,
+}
+
+/// Keycode and shift state typing an ASCII character on the US layout
+pub fn ascii_to_kc(c: char) -> Option<(KC, bool)> {
+    let kc = match c.to_ascii_lowercase() {
+        'a' => KC::Aa,
+        'b' => KC::Bb,
+        'c' => KC::Cc,
+        'd' => KC::Dd,
+        'e' => KC::Ee,
+        'f' => KC::Ff,
+        'g' => KC::Gg,
+        'h' => KC::Hh,
+        'i' => KC::Ii,
+        'j' => KC::Jj,
+        'k' => KC::Kk,
+        'l' => KC::Ll,
+        'm' => KC::Mm,
+        'n' => KC::Nn,
+        'o' => KC::Oo,
+        'p' => KC::Pp,
+        'q' => KC::Qq,
+        'r' => KC::Rr,
+        's' => KC::Ss,
+        't' => KC::Tt,
+        'u' => KC::Uu,
+        'v' => KC::Vv,
+        'w' => KC::Ww,
+        'x' => KC::Xx,
+        'y' => KC::Yy,
+        'z' => KC::Zz,
+        _ => {
+            return match c {
+                '1' => Some((KC::K1, false)),
+                '2' => Some((KC::K2, false)),
+                '3' => Some((KC::K3, false)),
+                '4' => Some((KC::K4, false)),
+                '5' => Some((KC::K5, false)),
+                '6' => Some((KC::K6, false)),
+                '7' => Some((KC::K7, false)),
+                '8' => Some((KC::K8, false)),
+                '9' => Some((KC::K9, false)),
+                '0' => Some((KC::K0, false)),
+                '!' => Some((KC::K1, true)),
+                '@' => Some((KC::K2, true)),
+                '#' => Some((KC::K3, true)),
+                '$' => Some((KC::K4, true)),
+                '%' => Some((KC::K5, true)),
+                '^' => Some((KC::K6, true)),
+                '&' => Some((KC::K7, true)),
+                '*' => Some((KC::K8, true)),
+                '(' => Some((KC::K9, true)),
+                ')' => Some((KC::K0, true)),
+                '\n' => Some((KC::Enter, false)),
+                '\t' => Some((KC::Tab, false)),
+                ' ' => Some((KC::Space, false)),
+                '-' => Some((KC::Dash, false)),
+                '_' => Some((KC::Dash, true)),
+                '=' => Some((KC::Equal, false)),
+                '+' => Some((KC::Equal, true)),
+                '[' => Some((KC::OpenBracket, false)),
+                '{' => Some((KC::OpenBracket, true)),
+                ']' => Some((KC::CloseBracket, false)),
+                '}' => Some((KC::CloseBracket, true)),
+                '\\' => Some((KC::Bslash, false)),
+                '|' => Some((KC::Bslash, true)),
+                ';' => Some((KC::SemiColon, false)),
+                ':' => Some((KC::SemiColon, true)),
+                '\'' => Some((KC::Quote, false)),
+                '"' => Some((KC::Quote, true)),
+                '`' => Some((KC::BacktickTilde, false)),
+                '~' => Some((KC::BacktickTilde, true)),
+                ',' => Some((KC::Comma, false)),
+                '<' => Some((KC::Comma, true)),
+                '.' => Some((KC::Period, false)),
+                '>' => Some((KC::Period, true)),
+                '/' => Some((KC::Fslash, false)),
+                '?' => Some((KC::Fslash, true)),
+                _ => None,
+            };
+        }
+    };
+
+    Some((kc, c.is_ascii_uppercase()))
+}
@@ -2,7 +2,12 @@
 #![no_main]
 
 use embassy_executor::Spawner;
+#[cfg(feature = "receiver")]
 use embassy_futures::join::join3;
+#[cfg(not(feature = "receiver"))]
+use embassy_futures::join::join4;
+use nrf_rustboard::mouse::mouse_keys_task;
+use nrf_rustboard::usb::usb_run;
 use nrf_rustboard::{ble::ble_init_run, key_provision::KeyProvision, peripherals::AppPeri};
 
 use {defmt_rtt as _, panic_probe as _};
@@ -10,15 +15,33 @@ use {defmt_rtt as _, panic_probe as _};
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     // init peripherals
-    let mut p = AppPeri::new();
+    let p = AppPeri::new();
 
     // init key provision
     let mut key_provision = KeyProvision::init();
 
+    // mouse keys run on their own so the movement keeps going between key changes,
+    // the task stays idle on the central
+    spawner.must_spawn(mouse_keys_task());
+
     // run tasks
+    #[cfg(not(feature = "receiver"))]
+    {
+        let mut matrix_peri = p.matrix_peri;
+        let _ = join4(
+            ble_init_run(p.ble_peri, spawner),
+            matrix_peri.scan(),
+            key_provision.run(),
+            usb_run(p.usbd),
+        )
+        .await;
+    }
+
+    // the receiver dongle runs the keymap of both halves and talks to the host over usb
+    #[cfg(feature = "receiver")]
     let _ = join3(
         ble_init_run(p.ble_peri, spawner),
-        p.matrix_peri.scan(),
+        usb_run(p.usbd),
         key_provision.run(),
     )
     .await;
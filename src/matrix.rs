@@ -1,15 +1,23 @@
-use crate::config::{ENTER_SLEEP_DEBOUNCE, MATRIX_KEYS_BUFFER};
-use crate::keycodes::KC;
-use crate::{COLS, KEY_DEBOUNCE, ROWS};
-use crate::{MATRIX_KEYS_LOCAL, delay_ms, delay_us};
+use crate::action::Action;
 
-use core::pin::pin;
 #[cfg(feature = "defmt")]
-use defmt::{Format, info};
-use embassy_futures::select::{Either, select, select_slice};
-use embassy_nrf::gpio::{Input, Output};
-use embassy_time::{Duration, Instant};
-use heapless::Vec;
+use defmt::Format;
+use embassy_time::Instant;
+
+// the matrix scan drives the gpio pins, the host tests leave it out
+#[cfg(all(feature = "defmt", not(test)))]
+use defmt::info;
+#[cfg(not(test))]
+use {
+    crate::config::{ENTER_SLEEP_DEBOUNCE, MATRIX_KEYS_BUFFER},
+    crate::{COLS, KEY_DEBOUNCE, ROWS},
+    crate::{MATRIX_KEYS_LOCAL, delay_ms, delay_us},
+    core::pin::pin,
+    embassy_futures::select::{Either, select, select_slice},
+    embassy_nrf::gpio::{Input, Output},
+    embassy_time::Duration,
+    heapless::Vec,
+};
 
 #[cfg_attr(feature = "defmt", derive(Format))]
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -35,7 +43,14 @@ pub enum KeyState {
 #[cfg_attr(feature = "defmt", derive(Format))]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Key {
-    pub code: KC,
+    /// Action resolved at press time, tap-hold and combos may still replace it
+    pub action: Action,
+    /// Action emitted by this press, the release undoes exactly this one
+    pub emitted: Option<Action>,
+    /// Held back while waiting for the other keys of a combo
+    pub pending_combo: bool,
+    /// Position of the key carrying the action of the combo this key triggered
+    pub combo: Option<KeyPos>,
     pub position: KeyPos,
     pub state: KeyState,
     pub time: Instant,
@@ -44,7 +59,10 @@ pub struct Key {
 impl Default for Key {
     fn default() -> Self {
         Self {
-            code: KC::default(),
+            action: Action::default(),
+            emitted: None,
+            pending_combo: false,
+            combo: None,
             position: KeyPos::default(),
             state: KeyState::default(),
             time: Instant::now(),
@@ -52,6 +70,7 @@ impl Default for Key {
     }
 }
 
+#[cfg(not(test))]
 #[cfg_attr(feature = "defmt", derive(Format))]
 #[derive(Copy, Clone, PartialEq)]
 struct MatrixKey {
@@ -59,6 +78,7 @@ struct MatrixKey {
     time: Instant,
 }
 
+#[cfg(not(test))]
 impl Default for MatrixKey {
     fn default() -> Self {
         Self {
@@ -68,6 +88,7 @@ impl Default for MatrixKey {
     }
 }
 
+#[cfg(not(test))]
 pub struct Matrix<'a> {
     rows: [Output<'a>; ROWS],
     cols: [Input<'a>; COLS],
@@ -76,6 +97,7 @@ pub struct Matrix<'a> {
     keys_to_send_old: [KeyPos; MATRIX_KEYS_BUFFER],
 }
 
+#[cfg(not(test))]
 impl<'a> Matrix<'a> {
     pub fn init(rows: [Output<'a>; ROWS], cols: [Input<'a>; COLS]) -> Self {
         Self {
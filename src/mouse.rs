@@ -0,0 +1,194 @@
+#[cfg(feature = "defmt")]
+use defmt::{Format, info};
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::config::{
+    MOUSE_INTERVAL, MOUSE_MOVE_MAX, MOUSE_MOVE_MIN, MOUSE_MOVE_TIME_TO_MAX, MOUSE_WHEEL_INTERVAL,
+    MOUSE_WHEEL_MAX, MOUSE_WHEEL_MIN, MOUSE_WHEEL_TIME_TO_MAX,
+};
+use crate::keycodes::KC;
+use crate::{MOUSE_KEYS, MOUSE_REPORT};
+
+/// Mouse keys currently held, one bit per mouse keycode
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct MouseKeys(u16);
+
+impl MouseKeys {
+    fn bit(kc: KC) -> u16 {
+        1 << (kc as u16 - KC::MoGL as u16)
+    }
+
+    pub fn press(&mut self, kc: KC) {
+        self.0 |= Self::bit(kc);
+    }
+
+    pub fn release(&mut self, kc: KC) {
+        self.0 &= !Self::bit(kc);
+    }
+
+    pub fn is_held(&self, kc: KC) -> bool {
+        self.0 & Self::bit(kc) != 0
+    }
+
+    /// Button bits of the mouse report: left, right, middle
+    fn buttons(&self) -> u8 {
+        self.is_held(KC::MoLC) as u8
+            | (self.is_held(KC::MoRC) as u8) << 1
+            | (self.is_held(KC::MoMC) as u8) << 2
+    }
+
+    /// Direction of an axis, opposite keys cancel each other
+    fn axis(&self, negative: KC, positive: KC) -> i8 {
+        self.is_held(positive) as i8 - self.is_held(negative) as i8
+    }
+
+    fn moves(&self) -> bool {
+        self.axis(KC::MoGL, KC::MoGR) != 0 || self.axis(KC::MoGU, KC::MoGD) != 0
+    }
+
+    fn scrolls(&self) -> bool {
+        self.axis(KC::MoSD, KC::MoSU) != 0 || self.axis(KC::MoSL, KC::MoSR) != 0
+    }
+}
+
+/// Mouse input report: buttons, cursor movement, vertical and horizontal scroll
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl MouseReport {
+    /// Report layout as described in the HID report map
+    pub fn to_bytes(&self) -> [u8; 5] {
+        [
+            self.buttons,
+            self.x as u8,
+            self.y as u8,
+            self.wheel as u8,
+            self.pan as u8,
+        ]
+    }
+}
+
+/// Acceleration curve, quadratic ease-in from `min` to `max` in `time_to_max` ms
+struct Curve {
+    min: u8,
+    max: u8,
+    time_to_max: u64,
+}
+
+impl Curve {
+    /// Speed after the movement keys have been held for `held` ms,
+    /// the constant speed keys bypass the curve
+    fn speed(&self, held: u64, keys: &MouseKeys) -> i8 {
+        let speed = if keys.is_held(KC::MoCS) {
+            self.min
+        } else if keys.is_held(KC::MoCN) {
+            self.min + (self.max - self.min) / 2
+        } else if keys.is_held(KC::MoCF) || held >= self.time_to_max {
+            self.max
+        } else {
+            let range = (self.max - self.min) as u64;
+            self.min + (range * held * held / (self.time_to_max * self.time_to_max)) as u8
+        };
+
+        speed.min(i8::MAX as u8) as i8
+    }
+}
+
+const MOVE_CURVE: Curve = Curve {
+    min: MOUSE_MOVE_MIN,
+    max: MOUSE_MOVE_MAX,
+    time_to_max: MOUSE_MOVE_TIME_TO_MAX,
+};
+
+const WHEEL_CURVE: Curve = Curve {
+    min: MOUSE_WHEEL_MIN,
+    max: MOUSE_WHEEL_MAX,
+    time_to_max: MOUSE_WHEEL_TIME_TO_MAX,
+};
+
+/// Mouse keys task, turns the held mouse keys from key provision into mouse reports
+#[cfg_attr(not(test), embassy_executor::task)]
+pub async fn mouse_keys_task() {
+    let mut mouse_keys_receiver = MOUSE_KEYS
+        .receiver()
+        .expect("[mouse] unable to create mouse_keys_receiver");
+    let mouse_report_sender = MOUSE_REPORT.sender();
+
+    let mut keys = MouseKeys::default();
+    let mut buttons_sent = 0;
+    let mut move_start = Instant::now();
+    let mut wheel_start = Instant::now();
+    let mut next_wheel = Instant::now();
+
+    loop {
+        // keep reporting at a steady rate while movement or scroll keys are held
+        let new_keys = if keys.moves() || keys.scrolls() {
+            match select(
+                mouse_keys_receiver.changed(),
+                Timer::after_millis(MOUSE_INTERVAL),
+            )
+            .await
+            {
+                Either::First(new_keys) => Some(new_keys),
+                Either::Second(()) => None,
+            }
+        } else {
+            Some(mouse_keys_receiver.changed().await)
+        };
+
+        let now = Instant::now();
+
+        if let Some(new_keys) = new_keys {
+            // restart the acceleration when the movement or the scroll starts
+            if !keys.moves() && new_keys.moves() {
+                move_start = now;
+            }
+            if !keys.scrolls() && new_keys.scrolls() {
+                wheel_start = now;
+                next_wheel = now;
+            }
+            keys = new_keys;
+        }
+
+        let mut report = MouseReport {
+            buttons: keys.buttons(),
+            ..Default::default()
+        };
+
+        if keys.moves() {
+            let speed = MOVE_CURVE.speed((now - move_start).as_millis(), &keys);
+            report.x = keys.axis(KC::MoGL, KC::MoGR) * speed;
+            report.y = keys.axis(KC::MoGU, KC::MoGD) * speed;
+        }
+
+        if keys.scrolls() && now >= next_wheel {
+            let speed = WHEEL_CURVE.speed((now - wheel_start).as_millis(), &keys);
+            report.wheel = keys.axis(KC::MoSD, KC::MoSU) * speed;
+            report.pan = keys.axis(KC::MoSL, KC::MoSR) * speed;
+            next_wheel = now + Duration::from_millis(MOUSE_WHEEL_INTERVAL);
+        }
+
+        // the report is relative, only send it if something moved or a button changed
+        if report.x != 0
+            || report.y != 0
+            || report.wheel != 0
+            || report.pan != 0
+            || report.buttons != buttons_sent
+        {
+            #[cfg(feature = "defmt")]
+            info!("[mouse] report: {:?}", report);
+
+            mouse_report_sender.send(report);
+            buttons_sent = report.buttons;
+        }
+    }
+}
@@ -0,0 +1,49 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+/// Number of keycodes covered by the bitmap, usages 0x00 to 0xDF
+pub const NKRO_KEYS: usize = 0xE0;
+
+/// Size of the n-key rollover report: modifier byte plus the keycode bitmap
+pub const NKRO_REPORT_SIZE: usize = 1 + NKRO_KEYS / 8;
+
+/// N-key rollover input report, one bit per keycode so every key can be held at once
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct NkroReport {
+    pub modifier: u8,
+    pub keys: [u8; NKRO_KEYS / 8],
+}
+
+impl Default for NkroReport {
+    fn default() -> Self {
+        Self {
+            modifier: 0,
+            keys: [0; NKRO_KEYS / 8],
+        }
+    }
+}
+
+impl NkroReport {
+    /// Set the bit of a keycode, keycodes outside the bitmap are ignored
+    pub fn press(&mut self, keycode: u8) {
+        if (keycode as usize) < NKRO_KEYS {
+            self.keys[keycode as usize / 8] |= 1 << (keycode % 8);
+        }
+    }
+
+    /// Clear the bit of a keycode
+    pub fn release(&mut self, keycode: u8) {
+        if (keycode as usize) < NKRO_KEYS {
+            self.keys[keycode as usize / 8] &= !(1 << (keycode % 8));
+        }
+    }
+
+    /// Report layout as described in the HID report map
+    pub fn to_bytes(&self) -> [u8; NKRO_REPORT_SIZE] {
+        let mut bytes = [0; NKRO_REPORT_SIZE];
+        bytes[0] = self.modifier;
+        bytes[1..].copy_from_slice(&self.keys);
+        bytes
+    }
+}
@@ -0,0 +1,35 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+use crate::{OUTPUT_MODE, USB_UP};
+
+/// Transport of the hid reports
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Output {
+    Usb,
+    Ble,
+}
+
+/// Output picked with the output keycodes
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum OutputMode {
+    /// Usb while a usb host is connected, ble otherwise
+    #[default]
+    Auto,
+    Usb,
+    Ble,
+}
+
+/// Output the reports currently go through, each transport checks it before sending a report
+pub fn active_output() -> Output {
+    let usb_up = USB_UP.anon_receiver().try_get().unwrap_or(false);
+
+    match OUTPUT_MODE.anon_receiver().try_get().unwrap_or_default() {
+        OutputMode::Auto if usb_up => Output::Usb,
+        OutputMode::Auto => Output::Ble,
+        OutputMode::Usb => Output::Usb,
+        OutputMode::Ble => Output::Ble,
+    }
+}
@@ -1,14 +1,21 @@
 use embassy_nrf::{
     Peri,
-    gpio::{Input, Level, Output, OutputDrive, Pull},
     peripherals::{
         NVMC, P0_04, PPI_CH17, PPI_CH18, PPI_CH19, PPI_CH20, PPI_CH21, PPI_CH22, PPI_CH23,
         PPI_CH24, PPI_CH25, PPI_CH26, PPI_CH27, PPI_CH28, PPI_CH29, PPI_CH30, PPI_CH31, RNG, RTC0,
-        SAADC, TEMP, TIMER0,
+        SAADC, TEMP, TIMER0, USBD,
     },
 };
 
+#[cfg(feature = "receiver")]
+use core::marker::PhantomData;
+#[cfg(not(feature = "receiver"))]
+use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
+
+#[cfg(not(feature = "receiver"))]
 use crate::matrix::Matrix;
+#[cfg(split_uart)]
+use embassy_nrf::peripherals::{P0_06, P0_08, PPI_CH0, PPI_CH1, PPI_GROUP0, TIMER1, UARTE0};
 
 pub struct BlePeri {
     pub ppi_ch17: Peri<'static, PPI_CH17>,
@@ -33,11 +40,30 @@ pub struct BlePeri {
     pub rng: Peri<'static, RNG>,
     pub p_04: Peri<'static, P0_04>,
     pub saadc: Peri<'static, SAADC>,
+    #[cfg(split_uart)]
+    pub split_peri: SplitPeri,
+}
+
+/// Serial link between the halves of a wired split keyboard
+#[cfg(split_uart)]
+pub struct SplitPeri {
+    pub uarte0: Peri<'static, UARTE0>,
+    pub timer1: Peri<'static, TIMER1>,
+    pub ppi_ch0: Peri<'static, PPI_CH0>,
+    pub ppi_ch1: Peri<'static, PPI_CH1>,
+    pub ppi_group0: Peri<'static, PPI_GROUP0>,
+    pub p0_06: Peri<'static, P0_06>,
+    pub p0_08: Peri<'static, P0_08>,
 }
 
 pub struct AppPeri<'a> {
     pub ble_peri: BlePeri,
+    /// The receiver dongle has no matrix
+    #[cfg(not(feature = "receiver"))]
     pub matrix_peri: Matrix<'a>,
+    #[cfg(feature = "receiver")]
+    _matrix: PhantomData<&'a ()>,
+    pub usbd: Peri<'static, USBD>,
 }
 
 impl<'a> Default for AppPeri<'a> {
@@ -75,31 +101,54 @@ impl<'a> AppPeri<'a> {
             rng: p.RNG,
             p_04: p.P0_04,
             saadc: p.SAADC,
+            #[cfg(split_uart)]
+            split_peri: SplitPeri {
+                uarte0: p.UARTE0,
+                timer1: p.TIMER1,
+                ppi_ch0: p.PPI_CH0,
+                ppi_ch1: p.PPI_CH1,
+                ppi_group0: p.PPI_GROUP0,
+                p0_06: p.P0_06,
+                p0_08: p.P0_08,
+            },
         };
 
-        // init rows
-        let rows = [
-            Output::new(p.P0_17, Level::Low, OutputDrive::Standard),
-            Output::new(p.P0_20, Level::Low, OutputDrive::Standard),
-            Output::new(p.P0_22, Level::Low, OutputDrive::Standard),
-            Output::new(p.P0_24, Level::Low, OutputDrive::Standard),
-        ];
+        #[cfg(feature = "receiver")]
+        {
+            Self {
+                ble_peri,
+                usbd: p.USBD,
+                _matrix: PhantomData,
+            }
+        }
+
+        #[cfg(not(feature = "receiver"))]
+        {
+            // init rows
+            let rows = [
+                Output::new(p.P0_17, Level::Low, OutputDrive::Standard),
+                Output::new(p.P0_20, Level::Low, OutputDrive::Standard),
+                Output::new(p.P0_22, Level::Low, OutputDrive::Standard),
+                Output::new(p.P0_24, Level::Low, OutputDrive::Standard),
+            ];
 
-        // init cols
-        let cols = [
-            Input::new(p.P0_31, Pull::Down),
-            Input::new(p.P0_29, Pull::Down),
-            Input::new(p.P0_02, Pull::Down),
-            Input::new(p.P1_15, Pull::Down),
-            Input::new(p.P1_13, Pull::Down),
-        ];
+            // init cols
+            let cols = [
+                Input::new(p.P0_31, Pull::Down),
+                Input::new(p.P0_29, Pull::Down),
+                Input::new(p.P0_02, Pull::Down),
+                Input::new(p.P1_15, Pull::Down),
+                Input::new(p.P1_13, Pull::Down),
+            ];
 
-        // init matrix
-        let matrix_peri = Matrix::init(rows, cols);
+            // init matrix
+            let matrix_peri = Matrix::init(rows, cols);
 
-        Self {
-            ble_peri,
-            matrix_peri,
+            Self {
+                ble_peri,
+                matrix_peri,
+                usbd: p.USBD,
+            }
         }
     }
 }
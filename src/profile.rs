@@ -0,0 +1,26 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use trouble_host::prelude::BdAddr;
+
+use crate::PROFILES;
+
+/// Host profile command sent from the keymap to the ble task
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ProfileCommand {
+    /// Switch to the host profile with the given index
+    Switch(u8),
+    /// Clear the bond of the current host profile
+    Clear,
+    /// Clear the bonds of every host profile
+    ClearAll,
+    /// Erase the storage and restart, every bond and the role of the half are forgotten
+    FactoryReset,
+}
+
+/// Host profiles as shown on the console, the address of the host bonded to each profile
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct HostBonds {
+    pub active: u8,
+    pub hosts: [Option<BdAddr>; PROFILES],
+}
@@ -0,0 +1,94 @@
+#[cfg(feature = "defmt")]
+use defmt::{Format, info};
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::config::ROLE_KEY_WINDOW;
+use crate::storage::{load_role, store_role};
+use crate::{CENTRAL_KEY, DONGLE, MATRIX_KEYS_LOCAL, PERIPHERAL_KEY, SPLIT};
+
+/// Role of a half, picked at power-up so both halves run the same firmware
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Role {
+    /// Connects to the hosts and runs the keymap for both halves
+    Peripheral,
+    /// Sends its matrix keys to the peripheral
+    Central,
+}
+
+impl Role {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Role::Peripheral => 0,
+            Role::Central => 1,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Role::Peripheral),
+            1 => Some(Role::Central),
+            _ => None,
+        }
+    }
+
+    /// Whether the half runs the keymap and sends the reports to the hosts, the halves of a
+    /// dongle setup leave it to the dongle
+    pub fn runs_keymap(self) -> bool {
+        self == Role::Peripheral && !(DONGLE && !cfg!(feature = "receiver"))
+    }
+}
+
+/// Pick the role of the half: a role key held at power-up selects it and is
+/// remembered, otherwise the stored role is used, peripheral if none was ever selected.
+/// Whether a role key picked it is returned along with the role
+pub async fn select_role<S: NorFlash>(storage: &mut S) -> (Role, bool) {
+    // a unibody keyboard only ever connects to the hosts
+    if !SPLIT {
+        return (Role::Peripheral, false);
+    }
+
+    let mut matrix_keys_receiver = MATRIX_KEYS_LOCAL
+        .receiver()
+        .expect("[role] unable to create matrix_keys_receiver");
+
+    let deadline = Instant::now() + Duration::from_millis(ROLE_KEY_WINDOW);
+
+    let held = loop {
+        match select(matrix_keys_receiver.changed(), Timer::at(deadline)).await {
+            Either::First(matrix_keys) => {
+                if matrix_keys.contains(&CENTRAL_KEY) {
+                    break Some(Role::Central);
+                } else if matrix_keys.contains(&PERIPHERAL_KEY) {
+                    break Some(Role::Peripheral);
+                }
+            }
+            Either::Second(()) => break None,
+        }
+    };
+
+    let stored = load_role(storage).await.and_then(Role::from_u8);
+
+    let role = match held {
+        Some(role) => {
+            if stored != Some(role) {
+                store_role(storage, role.to_u8())
+                    .await
+                    .expect("[role] error storing role");
+            }
+            role
+        }
+        None => stored.unwrap_or(Role::Peripheral),
+    };
+
+    #[cfg(feature = "defmt")]
+    info!(
+        "[role] role: {:?}, selected by key: {}",
+        role,
+        held.is_some()
+    );
+
+    (role, held.is_some())
+}
@@ -0,0 +1,933 @@
+use core::cell::RefCell;
+use core::ptr::addr_of_mut;
+
+#[cfg(feature = "defmt")]
+use defmt::{error, info, warn};
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
+use embassy_nrf::pac::{FICR, RADIO, TIMER0, radio::vals};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+use embedded_storage_async::nor_flash::NorFlash;
+use nrf_mpsl::raw::{
+    MPSL_TIMESLOT_HFCLK_CFG_XTAL_GUARANTEED, MPSL_TIMESLOT_PRIORITY_NORMAL,
+    MPSL_TIMESLOT_REQ_TYPE_EARLIEST, MPSL_TIMESLOT_REQ_TYPE_NORMAL,
+    MPSL_TIMESLOT_SIGNAL_ACTION_NONE, MPSL_TIMESLOT_SIGNAL_ACTION_REQUEST,
+    MPSL_TIMESLOT_SIGNAL_BLOCKED, MPSL_TIMESLOT_SIGNAL_CANCELLED, MPSL_TIMESLOT_SIGNAL_RADIO,
+    MPSL_TIMESLOT_SIGNAL_START, MPSL_TIMESLOT_SIGNAL_TIMER0, mpsl_timeslot_request,
+    mpsl_timeslot_request_earliest_t, mpsl_timeslot_request_normal_t, mpsl_timeslot_request_t,
+    mpsl_timeslot_session_id_t, mpsl_timeslot_session_open, mpsl_timeslot_signal_return_param_t,
+};
+
+use rand::RngCore;
+
+use crate::ble::split_bond::SPLIT_SLOT;
+use crate::config::{
+    ESB_ACK_TIMEOUT_US, ESB_INTERVAL_US, ESB_KEEPALIVE_US, ESB_PAIRING_BASE_ADDRESS,
+    ESB_PAIRING_CHANNEL, ESB_PAIRING_PREFIX, ESB_PAIRING_TIMEOUT, ESB_PRX_SLOT_US,
+    ESB_PRX_WINDOW_US, ESB_PTX_SLOT_US, ESB_RETRANSMITS,
+};
+use crate::role::Role;
+use crate::split::esb_bond::{
+    DEVICE_ID_SIZE, DeviceId, Direction, EsbBond, Freshness, HELLO_SIZE, Hello, KEY_SIZE, LinkKey,
+    Nonces, PAIRING_SIZE, Pipe, ReplayGuard, SEAL_OVERHEAD, open, pairing_payload, parse_pairing,
+    seal,
+};
+use crate::split::link::{SplitReceiver, SplitSender, central_link, peripheral_link};
+use crate::split::protocol::{FRAME_MAX, Frame, ProtocolError};
+use crate::storage::{load_esb_bond, store_esb_bond};
+
+/// Frames queued in each direction between the link tasks and the radio
+const ESB_QUEUE: usize = 4;
+
+/// Largest payload of a packet, a sealed frame
+const PAYLOAD_MAX: usize = FRAME_MAX + SEAL_OVERHEAD;
+
+/// Packet in ram: length, pid and the payload
+const PACKET_SIZE: usize = 2 + PAYLOAD_MAX;
+
+// the pairing and hello packets fit in a packet, and the payload length in its single byte
+const _: () = assert!(
+    PAIRING_SIZE <= PAYLOAD_MAX && HELLO_SIZE <= PAYLOAD_MAX && PAYLOAD_MAX <= u8::MAX as usize
+);
+
+/// Time kept at the end of a timeslot to stop the radio in us
+const SLOT_MARGIN_US: u32 = 100;
+
+/// How long the peripheral half waits for a listening timeslot before asking again in us
+const PRX_REQUEST_TIMEOUT_US: u32 = 1_000_000;
+
+/// Time into a listening window at which the address of the packets of the central half is
+/// aimed, leaving room for the clock drift of both halves since the last packet in us
+const PRX_ADDRESS_TARGET_US: u32 = 150;
+
+/// Time into a listening window after which the window ends if no packet started in us
+const PRX_LISTEN_US: u32 = 450;
+
+/// Frame carried by a packet or an acknowledgement, empty for the polls of the central half
+#[derive(Clone, Copy)]
+struct EsbPayload {
+    len: u8,
+    data: [u8; FRAME_MAX],
+}
+
+impl EsbPayload {
+    const EMPTY: Self = Self {
+        len: 0,
+        data: [0; FRAME_MAX],
+    };
+}
+
+/// Frames waiting for the radio
+static TX_PAYLOADS: Channel<CriticalSectionRawMutex, EsbPayload, ESB_QUEUE> = Channel::new();
+
+/// Frames received by the radio
+static RX_PAYLOADS: Channel<CriticalSectionRawMutex, EsbPayload, ESB_QUEUE> = Channel::new();
+
+/// Raised when the mpsl dropped the next timeslot and the session needs a new request
+static REQUEST_AGAIN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Device id and key share of the half answering in pairing mode
+static PAIRING_PEER: Signal<CriticalSectionRawMutex, (DeviceId, LinkKey)> = Signal::new();
+
+/// Raised for every authenticated packet of the paired half
+static PEER_HEARD: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Radio state, only touched by the timeslot callback once the session is open
+static ESB_RADIO: Mutex<CriticalSectionRawMutex, RefCell<EsbRadio>> =
+    Mutex::new(RefCell::new(EsbRadio::new()));
+
+/// Answer handed back to the mpsl by the timeslot callback
+static mut SIGNAL_RETURN: mpsl_timeslot_signal_return_param_t = unsafe { core::mem::zeroed() };
+
+/// Next timeslot requested through the answer of the timeslot callback
+static mut NEXT_REQUEST: mpsl_timeslot_request_t = unsafe { core::mem::zeroed() };
+
+#[derive(PartialEq)]
+enum Phase {
+    Idle,
+    /// central: sending a packet
+    Tx,
+    /// central: waiting for the acknowledgement
+    AckWait,
+    /// peripheral: listening for a packet
+    Rx,
+    /// peripheral: sending the acknowledgement
+    AckTx,
+}
+
+/// What the radio links with
+#[derive(Clone, Copy)]
+enum Mode {
+    /// Exchanging device ids and key shares on the pairing pipe
+    Pairing { id: DeviceId, share: LinkKey },
+    /// Linked to the paired half on the pipe of the pair, the key of the bond opens the sessions
+    /// whose own key seals every packet
+    Linked { pipe: Pipe, key: LinkKey },
+}
+
+/// What the timeslot callback tells the mpsl
+enum Action {
+    Continue,
+    /// end the timeslot and ask for the next one
+    End,
+}
+
+/// Enhanced ShockBurst in the timeslots: the central half is the primary transmitter and
+/// sends a queued frame, or an empty poll once in a while, in the timeslots of a fixed
+/// interval. The peripheral half listens in long timeslots till it hears the central half,
+/// then in short windows in step with its timeslots, and acknowledges each packet with its
+/// own frame. Each link starts with a session: the central half sends a fresh nonce, the
+/// peripheral half answers with its own and both seal the frames with the key of the pair of
+/// nonces, so packets recorded in an earlier session are never taken again
+struct EsbRadio {
+    role: Role,
+    /// Nothing is sent or received till the first mode is set
+    mode: Option<Mode>,
+    /// Mode taken at the start of the next timeslot
+    next_mode: Option<Mode>,
+    phase: Phase,
+    /// Buffer the radio sends from and receives into
+    packet: [u8; PACKET_SIZE],
+    /// central: frame waiting for its acknowledgement, peripheral: acknowledgement frame
+    pending: Option<EsbPayload>,
+    /// Packet id, the same for the retransmissions of a packet
+    pid: u8,
+    retransmits: u8,
+    /// Counter of the packet sent, the same for the retransmissions of a packet
+    counter: u32,
+    /// Counters of the packets of the other half in the session
+    replay_guard: ReplayGuard,
+    /// Source of the nonce of this half for each session
+    nonces: Nonces,
+    /// Nonces of the session being opened, sent instead of frames: by the central half till
+    /// the peripheral half answered, by the peripheral half till the first frame of the session
+    hello: Option<Hello>,
+    /// Key of the current session
+    session_key: Option<LinkKey>,
+    /// Length of the current timeslot
+    slot_us: u32,
+    /// central: time since the last packet sent, peripheral: time since the last packet of
+    /// the central half
+    idle_us: u32,
+    /// peripheral: listening in windows in step with the central half
+    synced: bool,
+    /// peripheral: time into the timeslot of the address of the packet received in it
+    address_us: Option<u32>,
+}
+
+impl EsbRadio {
+    const fn new() -> Self {
+        Self {
+            role: Role::Central,
+            mode: None,
+            next_mode: None,
+            phase: Phase::Idle,
+            packet: [0; PACKET_SIZE],
+            pending: None,
+            pid: 0,
+            retransmits: 0,
+            counter: 0,
+            replay_guard: ReplayGuard::new(),
+            nonces: Nonces::new([0; KEY_SIZE]),
+            hello: None,
+            session_key: None,
+            slot_us: 0,
+            idle_us: 0,
+            synced: false,
+            address_us: None,
+        }
+    }
+
+    /// Direction of the packets this half sends
+    fn direction(&self) -> Direction {
+        match self.role {
+            Role::Central => Direction::ToPeripheral,
+            Role::Peripheral => Direction::ToCentral,
+        }
+    }
+
+    /// Direction of the packets of the other half
+    fn peer_direction(&self) -> Direction {
+        match self.role {
+            Role::Central => Direction::ToCentral,
+            Role::Peripheral => Direction::ToPeripheral,
+        }
+    }
+
+    fn signal(&mut self, signal: u32) -> Action {
+        match signal {
+            MPSL_TIMESLOT_SIGNAL_START => self.start(),
+            MPSL_TIMESLOT_SIGNAL_RADIO => self.radio(),
+            MPSL_TIMESLOT_SIGNAL_TIMER0 => self.timer(),
+            MPSL_TIMESLOT_SIGNAL_BLOCKED | MPSL_TIMESLOT_SIGNAL_CANCELLED => {
+                // the next timeslot is asked for anew, out of step with the other half
+                self.synced = false;
+                REQUEST_AGAIN.signal(());
+                Action::Continue
+            }
+            _ => Action::Continue,
+        }
+    }
+
+    fn start(&mut self) -> Action {
+        // a new mode waits for a new session, the central half opens it with a fresh nonce
+        if let Some(mode) = self.next_mode.take() {
+            self.mode = Some(mode);
+            self.session_key = None;
+            self.hello = match self.role {
+                Role::Central => Some(Hello {
+                    central: self.nonces.draw(),
+                    peripheral: 0,
+                }),
+                Role::Peripheral => None,
+            };
+        }
+
+        let pipe = match self.mode {
+            Some(Mode::Pairing { .. }) => Pipe {
+                channel: ESB_PAIRING_CHANNEL,
+                base: ESB_PAIRING_BASE_ADDRESS,
+                prefix: ESB_PAIRING_PREFIX,
+            },
+            Some(Mode::Linked { pipe, .. }) => pipe,
+            None => return Action::End,
+        };
+
+        // the central half keeps its timeslots without a packet due, the peripheral half
+        // listens in step with them
+        self.slot_us = match self.role {
+            Role::Central => {
+                self.idle_us = self.idle_us.saturating_add(ESB_INTERVAL_US);
+                if !self.packet_due() {
+                    return Action::End;
+                }
+                ESB_PTX_SLOT_US
+            }
+            Role::Peripheral if self.synced => ESB_PRX_WINDOW_US,
+            Role::Peripheral => ESB_PRX_SLOT_US,
+        };
+        configure_radio(&pipe);
+
+        // the timer starts with the timeslot, stop the radio before the end of it
+        TIMER0.cc(0).write_value(self.slot_us - SLOT_MARGIN_US);
+        TIMER0.events_compare(0).write_value(0);
+        TIMER0.intenset().write(|w| w.set_compare(0, true));
+
+        // a window in step ends early when the packet of the central half did not come
+        if self.role == Role::Peripheral && self.synced {
+            TIMER0.cc(3).write_value(PRX_LISTEN_US);
+            TIMER0.events_compare(3).write_value(0);
+            TIMER0.intenset().write(|w| w.set_compare(3, true));
+        }
+
+        match self.role {
+            Role::Central => self.transmit(),
+            Role::Peripheral => self.listen(),
+        }
+        Action::Continue
+    }
+
+    /// Whether the central half sends in this timeslot: an unacknowledged or a queued frame,
+    /// a poll after a while without any, the hello once in a while, or the pairing packet
+    fn packet_due(&self) -> bool {
+        match self.mode {
+            Some(Mode::Linked { .. }) if self.hello.is_some() => self.idle_us >= ESB_KEEPALIVE_US,
+            Some(Mode::Linked { .. }) => {
+                self.pending.is_some()
+                    || !TX_PAYLOADS.is_empty()
+                    || self.idle_us >= ESB_KEEPALIVE_US
+            }
+            _ => true,
+        }
+    }
+
+    /// Send the unacknowledged frame again, else the next frame or a poll
+    fn transmit(&mut self) {
+        self.idle_us = 0;
+        if self.pending.is_none() {
+            if matches!(self.mode, Some(Mode::Linked { .. })) && self.hello.is_none() {
+                self.pending = TX_PAYLOADS.try_receive().ok();
+            }
+            self.pid = (self.pid + 1) % 4;
+            self.counter = self.counter.wrapping_add(1);
+            self.retransmits = 0;
+        }
+
+        self.load();
+
+        // the radio turns around on its own to receive the acknowledgement
+        RADIO.shorts().write(|w| {
+            w.set_ready_start(true);
+            w.set_end_disable(true);
+            w.set_disabled_rxen(true);
+        });
+        self.phase = Phase::Tx;
+        RADIO.tasks_txen().write_value(1);
+    }
+
+    fn listen(&mut self) {
+        RADIO.events_address().write_value(0);
+        RADIO
+            .packetptr()
+            .write_value(self.packet.as_mut_ptr() as u32);
+        RADIO.shorts().write(|w| {
+            w.set_ready_start(true);
+            w.set_end_disable(true);
+        });
+        self.phase = Phase::Rx;
+        RADIO.tasks_rxen().write_value(1);
+    }
+
+    fn radio(&mut self) -> Action {
+        if RADIO.events_disabled().read() == 0 {
+            return Action::Continue;
+        }
+        RADIO.events_disabled().write_value(0);
+
+        match self.phase {
+            Phase::Tx => {
+                RADIO.shorts().write(|w| {
+                    w.set_ready_start(true);
+                    w.set_end_disable(true);
+                });
+
+                TIMER0.tasks_capture(1).write_value(1);
+                let now = TIMER0.cc(1).read();
+                TIMER0.cc(1).write_value(now + ESB_ACK_TIMEOUT_US);
+                TIMER0.events_compare(1).write_value(0);
+                TIMER0.intenset().write(|w| w.set_compare(1, true));
+
+                self.phase = Phase::AckWait;
+                Action::Continue
+            }
+            Phase::AckWait => {
+                TIMER0.intenclr().write(|w| w.set_compare(1, true));
+
+                if self.acknowledged() {
+                    self.pending = None;
+                } else {
+                    self.missed();
+                }
+
+                self.end()
+            }
+            Phase::Rx => {
+                TIMER0.tasks_capture(2).write_value(1);
+                let end_us = TIMER0.cc(2).read();
+
+                if !self.received() {
+                    self.listen();
+                    return Action::Continue;
+                }
+
+                // the packets of the paired half set the pace of the windows
+                if let Some(Mode::Linked { .. }) = self.mode {
+                    let after_address_us = on_air_after_address_us(self.packet[0]);
+                    self.address_us = Some(end_us.saturating_sub(after_address_us));
+                }
+
+                self.pid = self.packet[1] >> 1;
+                self.load();
+
+                self.phase = Phase::AckTx;
+                RADIO.tasks_txen().write_value(1);
+                Action::Continue
+            }
+            // the central half sends one packet per timeslot
+            Phase::AckTx => self.end(),
+            Phase::Idle => Action::Continue,
+        }
+    }
+
+    fn timer(&mut self) -> Action {
+        // no packet started in the window, one under way is received to its end
+        if TIMER0.events_compare(3).read() != 0 {
+            TIMER0.events_compare(3).write_value(0);
+            TIMER0.intenclr().write(|w| w.set_compare(3, true));
+
+            if self.phase == Phase::Rx && RADIO.events_address().read() == 0 {
+                return self.end();
+            }
+        }
+
+        // no acknowledgement in time
+        if TIMER0.events_compare(1).read() != 0 {
+            TIMER0.events_compare(1).write_value(0);
+            TIMER0.intenclr().write(|w| w.set_compare(1, true));
+
+            self.missed();
+            return self.end();
+        }
+
+        // end of the timeslot
+        if TIMER0.events_compare(0).read() != 0 {
+            TIMER0.events_compare(0).write_value(0);
+            if self.phase == Phase::AckWait {
+                self.missed();
+            }
+            return self.end();
+        }
+
+        Action::Continue
+    }
+
+    /// Whether the received acknowledgement answers the packet sent, its frame is handed over
+    fn acknowledged(&mut self) -> bool {
+        if !crc_ok() {
+            return false;
+        }
+
+        match self.mode {
+            Some(Mode::Pairing { .. }) => self.received_pairing(),
+            Some(Mode::Linked { key, .. }) => match self.session_key {
+                Some(session_key) => self.received_sealed(&session_key) == Some(Freshness::New),
+                None => self.received_hello(&key),
+            },
+            None => false,
+        }
+    }
+
+    /// Whether the received packet is to be acknowledged, its frame is handed over once and the
+    /// acknowledgement frame of a new packet is taken from the queue
+    fn received(&mut self) -> bool {
+        if !crc_ok() {
+            return false;
+        }
+
+        match self.mode {
+            Some(Mode::Pairing { .. }) => self.received_pairing(),
+            Some(Mode::Linked { key, .. }) => {
+                if self.received_hello(&key) {
+                    return true;
+                }
+                match self.session_key {
+                    Some(session_key) => self.received_frame(&session_key),
+                    None => false,
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the received packet of the session is to be acknowledged
+    fn received_frame(&mut self, session_key: &LinkKey) -> bool {
+        match self.received_sealed(session_key) {
+            // a new packet tells the acknowledgement frame of the previous one got through, and
+            // the central half the answer to its hello
+            Some(Freshness::New) => {
+                self.hello = None;
+                self.pending = TX_PAYLOADS.try_receive().ok();
+                self.counter = self.counter.wrapping_add(1);
+                true
+            }
+            // the acknowledgement got lost, the same one goes out again
+            Some(Freshness::Repeated) => true,
+            Some(Freshness::Stale) | None => false,
+        }
+    }
+
+    /// Take the nonces of a received hello sealed with the bond key: the central half starts
+    /// the session on the answer to its nonce, the peripheral half answers a new nonce of the
+    /// central half with one of its own and starts the session
+    fn received_hello(&mut self, key: &LinkKey) -> bool {
+        let Some(hello) = Hello::open(key, self.peer_direction(), payload(&self.packet)) else {
+            return false;
+        };
+
+        match self.role {
+            Role::Central => {
+                let own = self.hello.map(|own| own.central);
+                if own != Some(hello.central) || hello.peripheral == 0 {
+                    return false;
+                }
+                self.start_session(key, hello);
+                self.hello = None;
+            }
+            Role::Peripheral => {
+                if hello.peripheral != 0 {
+                    return false;
+                }
+                // a repeated hello lost its answer, which goes out again unchanged
+                if self.hello.map(|own| own.central) != Some(hello.central) {
+                    let hello = Hello {
+                        peripheral: self.nonces.draw(),
+                        ..hello
+                    };
+                    self.start_session(key, hello);
+                    self.hello = Some(hello);
+                }
+            }
+        }
+
+        PEER_HEARD.signal(());
+        true
+    }
+
+    /// Seal and open the packets with the key of the session from now on, the packet counters
+    /// start over since no packet of an earlier session opens with it
+    fn start_session(&mut self, key: &LinkKey, hello: Hello) {
+        self.session_key = Some(hello.session_key(key));
+        self.replay_guard.reset();
+        self.counter = 0;
+    }
+
+    /// Hand the device id and key share of the answering half over to the pairing
+    fn received_pairing(&self) -> bool {
+        match parse_pairing(payload(&self.packet)) {
+            Some(peer) => {
+                PAIRING_PEER.signal(peer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Open a packet of the paired half, the frame of a new one is handed to the link
+    fn received_sealed(&mut self, key: &LinkKey) -> Option<Freshness> {
+        let (counter, frame) = open(key, self.peer_direction(), payload(&self.packet))?;
+
+        let freshness = self.replay_guard.check(counter);
+        if freshness == Freshness::New {
+            deliver(frame);
+        }
+        PEER_HEARD.signal(());
+        Some(freshness)
+    }
+
+    /// Put the payload of the mode in the packet buffer with the current packet id: the
+    /// pairing packet, the hello of the session being opened, or the pending frame sealed with
+    /// the key of the session
+    fn load(&mut self) {
+        let len = match self.mode {
+            Some(Mode::Pairing { id, share }) => {
+                self.packet[2..2 + PAIRING_SIZE].copy_from_slice(&pairing_payload(&id, &share));
+                PAIRING_SIZE
+            }
+            Some(Mode::Linked { key, .. }) => match (self.hello, self.session_key) {
+                (Some(hello), _) => hello.seal(&key, self.direction(), &mut self.packet[2..]),
+                (None, Some(session_key)) => {
+                    let payload = self.pending.unwrap_or(EsbPayload::EMPTY);
+                    seal(
+                        &session_key,
+                        self.direction(),
+                        self.counter,
+                        &payload.data[..payload.len as usize],
+                        &mut self.packet[2..],
+                    )
+                }
+                (None, None) => 0,
+            },
+            None => 0,
+        };
+
+        self.packet[0] = len as u8;
+        self.packet[1] = self.pid << 1;
+        RADIO
+            .packetptr()
+            .write_value(self.packet.as_mut_ptr() as u32);
+    }
+
+    /// Count a missing acknowledgement, the link notices the frames given up on
+    fn missed(&mut self) {
+        if self.pending.is_some() {
+            self.retransmits += 1;
+            if self.retransmits > ESB_RETRANSMITS {
+                self.pending = None;
+            }
+        }
+    }
+
+    /// Stop the radio and end the timeslot
+    fn end(&mut self) -> Action {
+        RADIO.shorts().write(|_| {});
+        RADIO.intenclr().write(|w| w.set_disabled(true));
+        RADIO.tasks_disable().write_value(1);
+        TIMER0.intenclr().write(|w| {
+            w.set_compare(0, true);
+            w.set_compare(1, true);
+            w.set_compare(3, true);
+        });
+
+        self.phase = Phase::Idle;
+        Action::End
+    }
+
+    /// Timeslot following the current one, the central half keeps a fixed interval and the
+    /// peripheral half opens its windows where the packets of the central half are due, or
+    /// listens whenever the host link leaves it the radio while out of step
+    fn next_request(&mut self) -> mpsl_timeslot_request_t {
+        if self.role == Role::Central {
+            return normal_request(ESB_INTERVAL_US, ESB_PTX_SLOT_US);
+        }
+
+        if let Some(address_us) = self.address_us.take() {
+            self.synced = true;
+            self.idle_us = 0;
+
+            // the central half sends at whole intervals from this packet on
+            let mut distance_us = address_us + ESB_INTERVAL_US - PRX_ADDRESS_TARGET_US;
+            while distance_us < self.slot_us {
+                distance_us += ESB_INTERVAL_US;
+            }
+            return normal_request(distance_us, ESB_PRX_WINDOW_US);
+        }
+
+        if self.synced {
+            // the central half polls at least every keepalive, it went out of step or away
+            self.idle_us = self.idle_us.saturating_add(ESB_INTERVAL_US);
+            if self.idle_us <= 2 * ESB_KEEPALIVE_US {
+                return normal_request(ESB_INTERVAL_US, ESB_PRX_WINDOW_US);
+            }
+            self.synced = false;
+        }
+
+        earliest_request(self.role)
+    }
+}
+
+/// Time on air of a received packet after its address at 2 Mbit/s in us: length, s1, payload
+/// and crc
+fn on_air_after_address_us(len: u8) -> u32 {
+    (8 + 3 + 8 * len as u32 + 16) / 2
+}
+
+/// Payload of a received packet
+fn payload(packet: &[u8; PACKET_SIZE]) -> &[u8] {
+    let len = (packet[0] as usize).min(PAYLOAD_MAX);
+    &packet[2..2 + len]
+}
+
+/// Hand a received frame over to the link, empty polls and acknowledgements carry none
+fn deliver(frame: &[u8]) {
+    if frame.is_empty() {
+        return;
+    }
+
+    let mut payload = EsbPayload::EMPTY;
+    payload.len = frame.len() as u8;
+    payload.data[..frame.len()].copy_from_slice(frame);
+
+    // a full queue drops the frame, the link asks for the state on the sequence gap
+    if RX_PAYLOADS.try_send(payload).is_err() {
+        #[cfg(feature = "defmt")]
+        warn!("[split_esb] receive queue full, frame dropped");
+    }
+}
+
+/// The radio is handed over in its reset state at the start of every timeslot
+fn configure_radio(pipe: &Pipe) {
+    RADIO.mode().write(|w| w.set_mode(vals::Mode::NRF_2MBIT));
+    RADIO.modecnf0().write(|w| w.set_ru(vals::Ru::FAST));
+    RADIO.frequency().write(|w| w.set_frequency(pipe.channel));
+
+    // dynamic payload length with the packet id in s1, as Enhanced ShockBurst does
+    RADIO.pcnf0().write(|w| {
+        w.set_lflen(8);
+        w.set_s0len(false);
+        w.set_s1len(3);
+    });
+    RADIO.pcnf1().write(|w| {
+        w.set_maxlen(PAYLOAD_MAX as u8);
+        w.set_statlen(0);
+        w.set_balen(4);
+        w.set_endian(vals::Endian::BIG);
+        w.set_whiteen(false);
+    });
+
+    RADIO.base0().write_value(pipe.base);
+    RADIO.prefix0().write(|w| w.set_ap(0, pipe.prefix));
+    RADIO.txaddress().write(|w| w.set_txaddress(0));
+    RADIO.rxaddresses().write(|w| w.set_addr(0, true));
+
+    RADIO.crccnf().write(|w| {
+        w.set_len(vals::Len::TWO);
+        w.set_skipaddr(vals::Skipaddr::INCLUDE);
+    });
+    RADIO.crcpoly().write(|w| w.set_crcpoly(0x11021));
+    RADIO.crcinit().write(|w| w.set_crcinit(0xffff));
+
+    RADIO.events_disabled().write_value(0);
+    RADIO.intenset().write(|w| w.set_disabled(true));
+}
+
+fn crc_ok() -> bool {
+    RADIO.crcstatus().read().crcstatus() == vals::Crcstatus::CRCOK
+}
+
+/// First timeslot of the session, the one after a dropped timeslot and the listening
+/// timeslots of the peripheral half out of step with the central half
+fn earliest_request(role: Role) -> mpsl_timeslot_request_t {
+    let length_us = match role {
+        Role::Central => ESB_PTX_SLOT_US,
+        Role::Peripheral => ESB_PRX_SLOT_US,
+    };
+
+    let mut request: mpsl_timeslot_request_t = unsafe { core::mem::zeroed() };
+    request.request_type = MPSL_TIMESLOT_REQ_TYPE_EARLIEST as u8;
+    request.params.earliest = mpsl_timeslot_request_earliest_t {
+        hfclk: MPSL_TIMESLOT_HFCLK_CFG_XTAL_GUARANTEED as u8,
+        priority: MPSL_TIMESLOT_PRIORITY_NORMAL as u8,
+        length_us,
+        timeout_us: PRX_REQUEST_TIMEOUT_US,
+    };
+    request
+}
+
+/// Timeslot at a distance from the start of the current one
+fn normal_request(distance_us: u32, length_us: u32) -> mpsl_timeslot_request_t {
+    let mut request: mpsl_timeslot_request_t = unsafe { core::mem::zeroed() };
+    request.request_type = MPSL_TIMESLOT_REQ_TYPE_NORMAL as u8;
+    request.params.normal = mpsl_timeslot_request_normal_t {
+        hfclk: MPSL_TIMESLOT_HFCLK_CFG_XTAL_GUARANTEED as u8,
+        priority: MPSL_TIMESLOT_PRIORITY_NORMAL as u8,
+        distance_us,
+        length_us,
+    };
+    request
+}
+
+/// Called by the mpsl in its high priority interrupt for every event of the session
+unsafe extern "C" fn timeslot_callback(
+    _session_id: mpsl_timeslot_session_id_t,
+    signal: u32,
+) -> *mut mpsl_timeslot_signal_return_param_t {
+    let next_request = ESB_RADIO.lock(|radio| {
+        let mut radio = radio.borrow_mut();
+        match radio.signal(signal) {
+            Action::Continue => None,
+            Action::End => Some(radio.next_request()),
+        }
+    });
+
+    let signal_return = addr_of_mut!(SIGNAL_RETURN);
+    unsafe {
+        match next_request {
+            None => {
+                (*signal_return).callback_action = MPSL_TIMESLOT_SIGNAL_ACTION_NONE as u8;
+            }
+            Some(next_request) => {
+                *addr_of_mut!(NEXT_REQUEST) = next_request;
+                (*signal_return).callback_action = MPSL_TIMESLOT_SIGNAL_ACTION_REQUEST as u8;
+                (*signal_return).params.request.p_next = addr_of_mut!(NEXT_REQUEST);
+            }
+        }
+    }
+
+    signal_return
+}
+
+/// Sending side of the esb split transport
+struct EsbSender;
+
+impl SplitSender for EsbSender {
+    async fn send(&mut self, frame: &[u8]) -> bool {
+        let mut payload = EsbPayload::EMPTY;
+        payload.len = frame.len() as u8;
+        payload.data[..frame.len()].copy_from_slice(frame);
+        TX_PAYLOADS.send(payload).await;
+
+        true
+    }
+}
+
+/// Receiving side of the esb split transport
+struct EsbReceiver;
+
+impl SplitReceiver for EsbReceiver {
+    // the radio does not tell whether the other half is still there
+    const KEEPALIVE: bool = true;
+
+    async fn receive(&mut self) -> Option<Result<Frame, ProtocolError>> {
+        let payload = RX_PAYLOADS.receive().await;
+        Some(Frame::decode(&payload.data[..payload.len as usize]))
+    }
+}
+
+/// Ask for the first timeslot, and again whenever the mpsl dropped the next one
+#[embassy_executor::task]
+async fn esb_timeslot_task(session_id: mpsl_timeslot_session_id_t, role: Role) -> ! {
+    loop {
+        let request = earliest_request(role);
+        let ret = unsafe { mpsl_timeslot_request(session_id, &request) };
+        if ret != 0 {
+            #[cfg(feature = "defmt")]
+            error!("[split_esb] error requesting a timeslot: {}", ret);
+        }
+
+        REQUEST_AGAIN.wait().await;
+    }
+}
+
+/// Open the timeslot session of the radio, it idles till the pairing or the link sets its mode
+pub(crate) fn start_esb_radio(spawner: Spawner, role: Role, rng: &mut impl RngCore) {
+    // the nonces of the link sessions differ from those of any earlier power-up
+    let mut seed = [0; KEY_SIZE];
+    rng.fill_bytes(&mut seed);
+    ESB_RADIO.lock(|radio| {
+        let mut radio = radio.borrow_mut();
+        radio.role = role;
+        radio.nonces = Nonces::new(seed);
+    });
+
+    // the session memory is handed to the mpsl at its initialization
+    let mut session_id: mpsl_timeslot_session_id_t = 0;
+    let ret = unsafe { mpsl_timeslot_session_open(Some(timeslot_callback), &mut session_id) };
+    if ret != 0 {
+        panic!("[split_esb] error opening the timeslot session: {}", ret);
+    }
+
+    spawner.must_spawn(esb_timeslot_task(session_id, role));
+}
+
+/// Mode taken by the radio from its next timeslot on
+fn set_mode(mode: Mode) {
+    ESB_RADIO.lock(|radio| radio.borrow_mut().next_mode = Some(mode));
+}
+
+/// Device id of the half, from the factory information of the chip
+fn device_id() -> DeviceId {
+    let mut id = [0; DEVICE_ID_SIZE];
+    id[..4].copy_from_slice(&FICR.deviceid(0).read().to_le_bytes());
+    id[4..].copy_from_slice(&FICR.deviceid(1).read().to_le_bytes());
+    id
+}
+
+/// Linked mode with the paired half, the pipe follows from the device ids of both halves
+fn linked_mode(role: Role, bond: &EsbBond) -> Mode {
+    let own = device_id();
+    let pipe = match role {
+        Role::Central => Pipe::derive(&own, &bond.peer),
+        Role::Peripheral => Pipe::derive(&bond.peer, &own),
+    };
+
+    Mode::Linked {
+        pipe,
+        key: bond.key,
+    }
+}
+
+/// Bond with the other half, the halves pair on the pairing pipe when none is stored: each
+/// sends its device id and a random key share, and the bond is stored once the other half
+/// is heard on the pipe of the pair. The shares go out in clear, whoever listens to the
+/// pairing learns the key
+pub(crate) async fn esb_bond<S: NorFlash>(
+    role: Role,
+    storage: &mut S,
+    rng: &mut impl RngCore,
+) -> EsbBond {
+    if let Some(bond) = load_esb_bond(storage, SPLIT_SLOT).await {
+        return bond;
+    }
+
+    let id = device_id();
+    let mut share = [0; KEY_SIZE];
+    rng.fill_bytes(&mut share);
+
+    loop {
+        #[cfg(feature = "defmt")]
+        info!("[split_esb] pairing, waiting for the other half");
+
+        PAIRING_PEER.reset();
+        set_mode(Mode::Pairing { id, share });
+        let (peer, peer_share) = PAIRING_PEER.wait().await;
+
+        let bond = EsbBond::new(peer, &share, &peer_share);
+        set_mode(linked_mode(role, &bond));
+        PEER_HEARD.reset();
+
+        // the other half may have missed the last pairing packet and still be pairing
+        let heard = select(PEER_HEARD.wait(), Timer::after_millis(ESB_PAIRING_TIMEOUT)).await;
+        if let Either::First(()) = heard {
+            store_esb_bond(storage, SPLIT_SLOT, &bond)
+                .await
+                .expect("[split_esb] error storing the esb bond");
+            return bond;
+        }
+
+        #[cfg(feature = "defmt")]
+        warn!("[split_esb] paired half not heard, pairing again");
+    }
+}
+
+/// Run the split link over the proprietary radio with the paired half, started over whenever
+/// the other half goes silent
+pub(crate) async fn run_esb_link(role: Role, bond: EsbBond) {
+    loop {
+        // frames of the previous link would only be taken as stale, and the link starts over
+        // in a new session
+        TX_PAYLOADS.clear();
+        RX_PAYLOADS.clear();
+        set_mode(linked_mode(role, &bond));
+
+        match role {
+            Role::Central => central_link(&mut EsbSender, &mut EsbReceiver).await,
+            Role::Peripheral => peripheral_link(&mut EsbSender, &mut EsbReceiver).await,
+        }
+
+        #[cfg(feature = "defmt")]
+        warn!("[split_esb] link lost, waiting for the other half");
+    }
+}
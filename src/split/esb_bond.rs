@@ -0,0 +1,505 @@
+use crate::split::protocol::FRAME_MAX;
+
+/// Size of the device id of a half, read from the factory information of the chip
+pub const DEVICE_ID_SIZE: usize = 8;
+
+/// Size of the link key and of the share each half adds to it
+pub const KEY_SIZE: usize = 16;
+
+/// Payload of the packets exchanged in pairing mode: device id and key share of the sender
+pub const PAIRING_SIZE: usize = DEVICE_ID_SIZE + KEY_SIZE;
+
+/// Size of the packet counter in front of a sealed frame
+const COUNTER_SIZE: usize = 4;
+
+/// Size of the authentication tag behind a sealed frame
+const TAG_SIZE: usize = 4;
+
+/// Size of the nonce each half adds to a link session
+const NONCE_SIZE: usize = 8;
+
+/// First byte of the payload of a linked packet
+const KIND_HELLO: u8 = 1;
+const KIND_SEALED: u8 = 2;
+
+/// Bytes added to a frame by sealing it: kind, packet counter and tag
+pub const SEAL_OVERHEAD: usize = 1 + COUNTER_SIZE + TAG_SIZE;
+
+/// Size of the packets opening a link session: kind, nonces of both halves and tag
+pub const HELLO_SIZE: usize = 1 + 2 * NONCE_SIZE + TAG_SIZE;
+
+/// Largest authenticated part of a linked packet
+const SEALED_MAX: usize = SEAL_OVERHEAD - TAG_SIZE + FRAME_MAX;
+
+const _: () = assert!(HELLO_SIZE - TAG_SIZE <= SEALED_MAX);
+
+/// Device id of a half
+pub type DeviceId = [u8; DEVICE_ID_SIZE];
+
+/// Key shared by the paired halves
+pub type LinkKey = [u8; KEY_SIZE];
+
+/// Bond of the esb split link: the other half and the key both halves agreed on in pairing
+/// mode, the link sessions are opened with it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EsbBond {
+    pub peer: DeviceId,
+    pub key: LinkKey,
+}
+
+impl EsbBond {
+    /// Bond with the peer whose pairing packet carried `peer_share`, both halves get the same
+    /// key whichever share is their own
+    pub fn new(peer: DeviceId, own_share: &LinkKey, peer_share: &LinkKey) -> Self {
+        let mut key = [0; KEY_SIZE];
+        for (byte, (own, other)) in key.iter_mut().zip(own_share.iter().zip(peer_share)) {
+            *byte = own ^ other;
+        }
+        Self { peer, key }
+    }
+}
+
+/// Encode the payload of a pairing packet
+pub fn pairing_payload(id: &DeviceId, share: &LinkKey) -> [u8; PAIRING_SIZE] {
+    let mut payload = [0; PAIRING_SIZE];
+    payload[..DEVICE_ID_SIZE].copy_from_slice(id);
+    payload[DEVICE_ID_SIZE..].copy_from_slice(share);
+    payload
+}
+
+/// Device id and key share of a received pairing packet
+pub fn parse_pairing(payload: &[u8]) -> Option<(DeviceId, LinkKey)> {
+    if payload.len() != PAIRING_SIZE {
+        return None;
+    }
+    Some((
+        payload[..DEVICE_ID_SIZE].try_into().ok()?,
+        payload[DEVICE_ID_SIZE..].try_into().ok()?,
+    ))
+}
+
+/// Radio channel and address of a pair of halves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pipe {
+    /// 2400 MHz + channel
+    pub channel: u8,
+    pub base: u32,
+    pub prefix: u8,
+}
+
+impl Pipe {
+    /// Pipe of the halves with the given device ids, keyboards nearby end up on their own
+    /// channel and address
+    pub fn derive(central: &DeviceId, peripheral: &DeviceId) -> Self {
+        let mut ids = [0; 2 * DEVICE_ID_SIZE];
+        ids[..DEVICE_ID_SIZE].copy_from_slice(central);
+        ids[DEVICE_ID_SIZE..].copy_from_slice(peripheral);
+        let hash = siphash(&[0; KEY_SIZE], &ids).to_le_bytes();
+
+        // stay off the ble advertising channels 2, 26 and 80
+        let mut channel = 4 + hash[0] % 72;
+        if channel == 26 {
+            channel += 1;
+        }
+
+        Self {
+            channel,
+            base: u32::from_le_bytes([hash[1], hash[2], hash[3], address_byte(hash[4])]),
+            prefix: address_byte(hash[5]),
+        }
+    }
+}
+
+/// Avoid address bytes without bit transitions or looking like the preamble, the radio takes
+/// noise or the preamble for them
+fn address_byte(byte: u8) -> u8 {
+    match byte {
+        0x00 | 0xff | 0x55 | 0xaa => byte ^ 0x3c,
+        _ => byte,
+    }
+}
+
+/// Direction of a packet, a packet sealed for one direction does not open in the other
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    ToPeripheral,
+    ToCentral,
+}
+
+/// Nonces of a link session, each half draws its own whenever the link starts over so the
+/// packets of earlier sessions do not open in this one. The nonce of the peripheral half is
+/// zero till it answered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hello {
+    pub central: u64,
+    pub peripheral: u64,
+}
+
+impl Hello {
+    /// Key the packets of the session are sealed with
+    pub fn session_key(&self, key: &LinkKey) -> LinkKey {
+        let mut message = [0; 1 + 2 * NONCE_SIZE];
+        message[1..1 + NONCE_SIZE].copy_from_slice(&self.central.to_le_bytes());
+        message[1 + NONCE_SIZE..].copy_from_slice(&self.peripheral.to_le_bytes());
+
+        let mut session_key = [0; KEY_SIZE];
+        session_key[..8].copy_from_slice(&siphash(key, &message).to_le_bytes());
+        message[0] = 1;
+        session_key[8..].copy_from_slice(&siphash(key, &message).to_le_bytes());
+        session_key
+    }
+
+    /// Encode into `out` authenticated with the bond key, returns the length
+    pub fn seal(&self, key: &LinkKey, direction: Direction, out: &mut [u8]) -> usize {
+        out[0] = KIND_HELLO;
+        out[1..1 + NONCE_SIZE].copy_from_slice(&self.central.to_le_bytes());
+        out[1 + NONCE_SIZE..1 + 2 * NONCE_SIZE].copy_from_slice(&self.peripheral.to_le_bytes());
+
+        let len = HELLO_SIZE - TAG_SIZE;
+        let tag = tag(key, direction, &out[..len]);
+        out[len..HELLO_SIZE].copy_from_slice(&tag);
+        HELLO_SIZE
+    }
+
+    /// Nonces of a received payload, none if it is no hello sealed with the bond key for this
+    /// direction
+    pub fn open(key: &LinkKey, direction: Direction, payload: &[u8]) -> Option<Self> {
+        if payload.len() != HELLO_SIZE || payload[0] != KIND_HELLO {
+            return None;
+        }
+
+        let (sealed, received_tag) = payload.split_at(HELLO_SIZE - TAG_SIZE);
+        if tag(key, direction, sealed) != received_tag {
+            return None;
+        }
+
+        Some(Self {
+            central: u64::from_le_bytes(sealed[1..1 + NONCE_SIZE].try_into().ok()?),
+            peripheral: u64::from_le_bytes(sealed[1 + NONCE_SIZE..].try_into().ok()?),
+        })
+    }
+}
+
+/// Nonces that never repeat: a keyed hash of a count, under a seed drawn at power-up
+pub struct Nonces {
+    seed: LinkKey,
+    count: u64,
+}
+
+impl Nonces {
+    pub const fn new(seed: LinkKey) -> Self {
+        Self { seed, count: 0 }
+    }
+
+    /// Next nonce, never zero
+    pub fn draw(&mut self) -> u64 {
+        self.count += 1;
+        siphash(&self.seed, &self.count.to_le_bytes()).max(1)
+    }
+}
+
+/// Seal a frame into `out`: kind, packet counter, frame and authentication tag, returns the
+/// length
+pub fn seal(
+    key: &LinkKey,
+    direction: Direction,
+    counter: u32,
+    frame: &[u8],
+    out: &mut [u8],
+) -> usize {
+    let len = 1 + COUNTER_SIZE + frame.len();
+    out[0] = KIND_SEALED;
+    out[1..1 + COUNTER_SIZE].copy_from_slice(&counter.to_le_bytes());
+    out[1 + COUNTER_SIZE..len].copy_from_slice(frame);
+
+    let tag = tag(key, direction, &out[..len]);
+    out[len..len + TAG_SIZE].copy_from_slice(&tag);
+    len + TAG_SIZE
+}
+
+/// Packet counter and frame of a sealed payload, none if it was not sealed with the key for
+/// this direction
+pub fn open<'a>(key: &LinkKey, direction: Direction, payload: &'a [u8]) -> Option<(u32, &'a [u8])> {
+    if !(SEAL_OVERHEAD..=SEAL_OVERHEAD + FRAME_MAX).contains(&payload.len())
+        || payload[0] != KIND_SEALED
+    {
+        return None;
+    }
+
+    let (sealed, received_tag) = payload.split_at(payload.len() - TAG_SIZE);
+    if tag(key, direction, sealed) != received_tag {
+        return None;
+    }
+
+    let counter = u32::from_le_bytes(sealed[1..1 + COUNTER_SIZE].try_into().ok()?);
+    Some((counter, &sealed[1 + COUNTER_SIZE..]))
+}
+
+/// Truncated SipHash-2-4 of the direction and the authenticated part of the payload
+fn tag(key: &LinkKey, direction: Direction, sealed: &[u8]) -> [u8; TAG_SIZE] {
+    let mut message = [0; 1 + SEALED_MAX];
+    message[0] = direction as u8;
+    message[1..1 + sealed.len()].copy_from_slice(sealed);
+
+    let hash = siphash(key, &message[..1 + sealed.len()]).to_le_bytes();
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// How a received packet counter relates to the last accepted one
+#[derive(Debug, PartialEq)]
+pub enum Freshness {
+    New,
+    /// Retransmission of the last accepted packet
+    Repeated,
+    /// Older packet, replayed by someone else
+    Stale,
+}
+
+/// Packet counters of the other half only ever go up, older packets are refused
+#[derive(Default)]
+pub struct ReplayGuard {
+    last: Option<u32>,
+}
+
+impl ReplayGuard {
+    pub const fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// Check a received packet counter, a new one is accepted
+    pub fn check(&mut self, counter: u32) -> Freshness {
+        match self.last {
+            Some(last) if counter == last => Freshness::Repeated,
+            Some(last) if counter < last => Freshness::Stale,
+            _ => {
+                self.last = Some(counter);
+                Freshness::New
+            }
+        }
+    }
+
+    /// Accept any counter again, the other half counts from zero in a new session
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// SipHash-2-4, a keyed hash made for short messages
+fn siphash(key: &LinkKey, message: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let mut chunks = message.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        sip_rounds(&mut v, 2);
+        v[0] ^= m;
+    }
+
+    // the last block holds the remaining bytes and the length of the message
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = message.len() as u8;
+    let m = u64::from_le_bytes(last);
+    v[3] ^= m;
+    sip_rounds(&mut v, 2);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    sip_rounds(&mut v, 4);
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_rounds(v: &mut [u64; 4], rounds: usize) {
+    for _ in 0..rounds {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: LinkKey = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    #[test]
+    fn siphash_matches_the_reference_vectors() {
+        assert_eq!(siphash(&KEY, &[]), 0x726f_db47_dd0e_0e31);
+
+        let message: [u8; 15] = core::array::from_fn(|i| i as u8);
+        assert_eq!(siphash(&KEY, &message), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn sealed_frame_opens_with_the_same_key() {
+        let frame = [0xa5, 1, 2, 3];
+        let mut payload = [0; 32];
+        let len = seal(&KEY, Direction::ToPeripheral, 7, &frame, &mut payload);
+        assert_eq!(len, frame.len() + SEAL_OVERHEAD);
+
+        let (counter, opened) = open(&KEY, Direction::ToPeripheral, &payload[..len]).unwrap();
+        assert_eq!(counter, 7);
+        assert_eq!(opened, frame);
+    }
+
+    #[test]
+    fn foreign_or_altered_packets_do_not_open() {
+        let mut payload = [0; 32];
+        let len = seal(&KEY, Direction::ToPeripheral, 7, &[1, 2, 3], &mut payload);
+
+        let mut other_key = KEY;
+        other_key[0] ^= 1;
+        assert!(open(&other_key, Direction::ToPeripheral, &payload[..len]).is_none());
+
+        // reflected back to the sender
+        assert!(open(&KEY, Direction::ToCentral, &payload[..len]).is_none());
+
+        payload[1 + COUNTER_SIZE] ^= 1;
+        assert!(open(&KEY, Direction::ToPeripheral, &payload[..len]).is_none());
+
+        assert!(open(&KEY, Direction::ToPeripheral, &payload[..TAG_SIZE]).is_none());
+    }
+
+    #[test]
+    fn empty_polls_are_authenticated_too() {
+        let mut payload = [0; SEAL_OVERHEAD];
+        let len = seal(&KEY, Direction::ToCentral, 1, &[], &mut payload);
+
+        let (counter, frame) = open(&KEY, Direction::ToCentral, &payload[..len]).unwrap();
+        assert_eq!(counter, 1);
+        assert!(frame.is_empty());
+    }
+
+    #[test]
+    fn hello_opens_with_the_bond_key_only() {
+        let hello = Hello {
+            central: 3,
+            peripheral: 0,
+        };
+        let mut payload = [0; HELLO_SIZE];
+        assert_eq!(
+            hello.seal(&KEY, Direction::ToPeripheral, &mut payload),
+            HELLO_SIZE
+        );
+        assert_eq!(
+            Hello::open(&KEY, Direction::ToPeripheral, &payload),
+            Some(hello)
+        );
+
+        assert_eq!(Hello::open(&KEY, Direction::ToCentral, &payload), None);
+        let mut other_key = KEY;
+        other_key[0] ^= 1;
+        assert_eq!(
+            Hello::open(&other_key, Direction::ToPeripheral, &payload),
+            None
+        );
+
+        // neither kind of packet is taken for the other
+        assert!(open(&KEY, Direction::ToPeripheral, &payload).is_none());
+        let mut sealed = [0; HELLO_SIZE];
+        let len = seal(&KEY, Direction::ToPeripheral, 1, &[0; 12], &mut sealed);
+        assert_eq!(len, HELLO_SIZE);
+        assert_eq!(Hello::open(&KEY, Direction::ToPeripheral, &sealed), None);
+    }
+
+    #[test]
+    fn packets_of_another_session_do_not_open() {
+        let session = Hello {
+            central: 1,
+            peripheral: 2,
+        };
+        let key = session.session_key(&KEY);
+        let mut payload = [0; 32];
+        let len = seal(&key, Direction::ToCentral, 7, &[1, 2, 3], &mut payload);
+
+        // a replay from an earlier session meets the fresh nonce of one half
+        for other in [
+            Hello {
+                central: 3,
+                ..session
+            },
+            Hello {
+                peripheral: 3,
+                ..session
+            },
+        ] {
+            let other_key = other.session_key(&KEY);
+            assert!(open(&other_key, Direction::ToCentral, &payload[..len]).is_none());
+        }
+        assert!(open(&key, Direction::ToCentral, &payload[..len]).is_some());
+    }
+
+    #[test]
+    fn nonces_do_not_repeat() {
+        let mut nonces = Nonces::new(KEY);
+        let mut seen: Vec<u64> = (0..1000).map(|_| nonces.draw()).collect();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 1000);
+        assert!(!seen.contains(&0));
+
+        let mut other_boot = Nonces::new([7; KEY_SIZE]);
+        assert!(!seen.contains(&other_boot.draw()));
+    }
+
+    #[test]
+    fn replayed_packets_are_refused() {
+        let mut guard = ReplayGuard::new();
+        assert_eq!(guard.check(5), Freshness::New);
+        assert_eq!(guard.check(5), Freshness::Repeated);
+        assert_eq!(guard.check(4), Freshness::Stale);
+        assert_eq!(guard.check(6), Freshness::New);
+
+        guard.reset();
+        assert_eq!(guard.check(0), Freshness::New);
+    }
+
+    #[test]
+    fn both_halves_agree_on_the_key() {
+        let central_share = [0x11; KEY_SIZE];
+        let peripheral_share = [0x2e; KEY_SIZE];
+        let central = EsbBond::new([2; DEVICE_ID_SIZE], &central_share, &peripheral_share);
+        let peripheral = EsbBond::new([1; DEVICE_ID_SIZE], &peripheral_share, &central_share);
+        assert_eq!(central.key, peripheral.key);
+    }
+
+    #[test]
+    fn pairing_payload_round_trips() {
+        let payload = pairing_payload(&[9; DEVICE_ID_SIZE], &KEY);
+        assert_eq!(parse_pairing(&payload), Some(([9; DEVICE_ID_SIZE], KEY)));
+        assert_eq!(parse_pairing(&payload[1..]), None);
+    }
+
+    #[test]
+    fn pipe_depends_on_both_halves() {
+        let central = [1; DEVICE_ID_SIZE];
+        let peripheral = [2; DEVICE_ID_SIZE];
+        let pipe = Pipe::derive(&central, &peripheral);
+
+        assert_eq!(pipe, Pipe::derive(&central, &peripheral));
+        assert_ne!(pipe, Pipe::derive(&peripheral, &central));
+        assert_ne!(pipe, Pipe::derive(&central, &[3; DEVICE_ID_SIZE]));
+
+        for id in 0..=u8::MAX {
+            let pipe = Pipe::derive(&central, &[id; DEVICE_ID_SIZE]);
+            assert!((4..=76).contains(&pipe.channel) && pipe.channel != 26);
+            assert!(![0x00, 0xff, 0x55, 0xaa].contains(&pipe.prefix));
+        }
+    }
+}
@@ -0,0 +1,392 @@
+#[cfg(feature = "receiver")]
+use core::sync::atomic::{AtomicU8, Ordering};
+#[cfg(feature = "defmt")]
+use defmt::{error, info};
+use embassy_futures::select::{Either, Either4, select, select4};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::Watch;
+use embassy_time::Timer;
+
+use crate::config::{MATRIX_KEYS_BUFFER, SPLIT_LINK_TIMEOUT, SPLIT_PING_INTERVAL};
+use crate::matrix::KeyPos;
+use crate::role::Role;
+use crate::split::protocol::{
+    FRAME_MAX, Frame, HeldKeys, LinkRequest, Message, ProtocolError, Sequence, Sequencer,
+    apply_key, apply_keys, key_changes,
+};
+use crate::{
+    ACTIVE_LAYER, BATTERY_LEVEL, COLS, KEYBOARD_LEDS, MATRIX_KEYS_LOCAL, MATRIX_KEYS_SPLIT,
+    MESSAGE_TO_PERI,
+};
+
+/// Battery level of each half on the dongle, indexed by the role of the half
+#[cfg(feature = "receiver")]
+static HALF_BATTERY_LEVELS: [AtomicU8; 2] = [AtomicU8::new(u8::MAX), AtomicU8::new(u8::MAX)];
+
+/// Sending side of a split transport
+pub(crate) trait SplitSender {
+    /// Send an encoded frame, false once the link is gone
+    async fn send(&mut self, frame: &[u8]) -> bool;
+}
+
+/// Receiving side of a split transport
+pub(crate) trait SplitReceiver {
+    /// The transport does not notice a lost link by itself: idle links are kept alive with
+    /// pings and a silent one is taken as gone
+    const KEEPALIVE: bool;
+
+    /// Next received frame, `None` once the link is gone
+    async fn receive(&mut self) -> Option<Result<Frame, ProtocolError>>;
+}
+
+/// Split link of the central half, sends the held keys and the battery level and receives
+/// the lock LEDs, returns once the link is gone
+pub(crate) async fn central_link<T: SplitSender, R: SplitReceiver>(tx: &mut T, rx: &mut R) {
+    // requests of the received frames to the sending side
+    let requests = Signal::new();
+    let keyboard_leds_sender = KEYBOARD_LEDS.sender();
+    let active_layer_sender = ACTIVE_LAYER.sender();
+
+    let _ = select(
+        central_send(tx, &requests, R::KEEPALIVE),
+        receive_messages(rx, &requests, |message| match message {
+            Message::Leds(leds) => keyboard_leds_sender.send(leds),
+            Message::Layer(layer) => active_layer_sender.send(layer),
+            _ => {}
+        }),
+    )
+    .await;
+}
+
+/// Split link of the peripheral half, receives the keys and the battery level of the central
+/// half and sends the lock LEDs, returns once the link is gone
+pub(crate) async fn peripheral_link<T: SplitSender, R: SplitReceiver>(tx: &mut T, rx: &mut R) {
+    keymap_link(tx, rx, Role::Central).await;
+}
+
+/// Split link of the dongle to one of the halves, receives the keys and the battery level of
+/// the half and sends the lock LEDs, returns once the link is gone
+#[cfg(feature = "receiver")]
+pub(crate) async fn dongle_link<T: SplitSender, R: SplitReceiver>(
+    tx: &mut T,
+    rx: &mut R,
+    half: Role,
+) {
+    keymap_link(tx, rx, half).await;
+}
+
+/// Split link of the side running the keymap to the given half
+async fn keymap_link<T: SplitSender, R: SplitReceiver>(tx: &mut T, rx: &mut R, half: Role) {
+    let requests = Signal::new();
+    let mut half_keys_local = [KeyPos::default(); MATRIX_KEYS_BUFFER];
+
+    let _ = select(
+        peripheral_send(tx, &requests, R::KEEPALIVE),
+        receive_messages(rx, &requests, |message| {
+            receive_half_message(message, &mut half_keys_local, half)
+        }),
+    )
+    .await;
+
+    release_half_keys(half);
+}
+
+/// Release the keys held on a half, its link is gone
+pub(crate) fn release_half_keys(half: Role) {
+    half_keys(half)
+        .sender()
+        .send([KeyPos::default(); MATRIX_KEYS_BUFFER]);
+}
+
+/// Keys of a half handed to the keymap, the keys of the peripheral half only arrive over a
+/// link on the dongle
+fn half_keys(half: Role) -> &'static Watch<CriticalSectionRawMutex, HeldKeys, 2> {
+    match half {
+        Role::Peripheral => &MATRIX_KEYS_LOCAL,
+        Role::Central => &MATRIX_KEYS_SPLIT,
+    }
+}
+
+/// Send a message with the next sequence number, false once the link is gone
+async fn send_message(
+    tx: &mut impl SplitSender,
+    sequencer: &mut Sequencer,
+    message: Message,
+) -> bool {
+    let mut buf = [0; FRAME_MAX];
+    let len = Frame {
+        seq: sequencer.next_seq(),
+        message,
+    }
+    .encode(&mut buf);
+
+    #[cfg(feature = "defmt")]
+    info!("[split_link] send: {:?}", message);
+
+    tx.send(&buf[..len]).await
+}
+
+/// Wait till the idle link needs a ping, never on transports which notice a lost link
+async fn ping_due(keepalive: bool) {
+    if keepalive {
+        Timer::after_millis(SPLIT_PING_INTERVAL).await;
+    } else {
+        core::future::pending::<()>().await;
+    }
+}
+
+/// Send the held keys and the battery level of the central half, in full on connection and
+/// when the peripheral asks for it
+async fn central_send(
+    tx: &mut impl SplitSender,
+    requests: &Signal<NoopRawMutex, LinkRequest>,
+    keepalive: bool,
+) {
+    let mut message_to_peri = MESSAGE_TO_PERI
+        .receiver()
+        .expect("[split_link] maximum number of receivers has been reached");
+    let mut battery_percantage_receiver = BATTERY_LEVEL
+        .receiver()
+        .expect("[split_link] failed to create receiver");
+
+    let mut sequencer = Sequencer::default();
+
+    // keys the peripheral knows to be held
+    let mut keys_sent = [KeyPos::default(); MATRIX_KEYS_BUFFER];
+
+    // the full state opens every link
+    let mut request = Some(LinkRequest::SendState);
+
+    loop {
+        match request.take() {
+            Some(LinkRequest::SendState) => {
+                keys_sent = message_to_peri
+                    .try_get()
+                    .unwrap_or([KeyPos::default(); MATRIX_KEYS_BUFFER]);
+
+                if !send_message(tx, &mut sequencer, Message::Keys(keys_sent)).await {
+                    return;
+                }
+
+                if let Some(battery_level) = battery_percantage_receiver.try_get()
+                    && !send_message(tx, &mut sequencer, Message::Battery(battery_level)).await
+                {
+                    return;
+                }
+            }
+            Some(LinkRequest::RequestState) => {
+                if !send_message(tx, &mut sequencer, Message::Resync).await {
+                    return;
+                }
+            }
+            None => {}
+        }
+
+        // wait till new keys are received from key_provision, the battery changes, the
+        // received frames ask for something or the idle link needs a ping
+        let sent = match select4(
+            message_to_peri.changed(),
+            battery_percantage_receiver.changed(),
+            requests.wait(),
+            ping_due(keepalive),
+        )
+        .await
+        {
+            Either4::First(keys) => {
+                let mut sent = true;
+                for message in key_changes(&keys_sent, &keys) {
+                    sent = sent && send_message(tx, &mut sequencer, message).await;
+                }
+                keys_sent = keys;
+                sent
+            }
+            Either4::Second(battery_level) => {
+                send_message(tx, &mut sequencer, Message::Battery(battery_level)).await
+            }
+            Either4::Third(new_request) => {
+                request = Some(new_request);
+                true
+            }
+            Either4::Fourth(()) => send_message(tx, &mut sequencer, Message::Ping).await,
+        };
+
+        if !sent {
+            return;
+        }
+    }
+}
+
+/// Send the lock LEDs set by the host, in full on connection and when the other side asks for
+/// it
+async fn peripheral_send(
+    tx: &mut impl SplitSender,
+    requests: &Signal<NoopRawMutex, LinkRequest>,
+    keepalive: bool,
+) {
+    let mut keyboard_leds = KEYBOARD_LEDS
+        .receiver()
+        .expect("[split_link] maximum number of receivers has been reached");
+    let mut active_layer = ACTIVE_LAYER
+        .receiver()
+        .expect("[split_link] maximum number of receivers has been reached");
+    let mut sequencer = Sequencer::default();
+
+    // the host may have set the leds and the keymap a layer before the split half connected
+    let mut messages = [
+        Some(Message::Leds(keyboard_leds.try_get().unwrap_or_default())),
+        Some(Message::Layer(active_layer.try_get().unwrap_or_default())),
+    ];
+
+    loop {
+        for message in messages.into_iter().flatten() {
+            if !send_message(tx, &mut sequencer, message).await {
+                return;
+            }
+        }
+
+        // wait till the host changes the leds or the keymap the layer, the received frames ask
+        // for something or the idle link needs a ping
+        messages = match select4(
+            keyboard_leds.changed(),
+            active_layer.changed(),
+            requests.wait(),
+            ping_due(keepalive),
+        )
+        .await
+        {
+            Either4::First(leds) => [Some(Message::Leds(leds)), None],
+            Either4::Second(layer) => [Some(Message::Layer(layer)), None],
+            Either4::Third(LinkRequest::SendState) => [
+                Some(Message::Leds(keyboard_leds.try_get().unwrap_or_default())),
+                Some(Message::Layer(active_layer.try_get().unwrap_or_default())),
+            ],
+            Either4::Third(LinkRequest::RequestState) => [Some(Message::Resync), None],
+            Either4::Fourth(()) => [Some(Message::Ping), None],
+        };
+    }
+}
+
+/// Receive frames till the link is gone, in order messages are handed to `on_message`,
+/// resyncs are answered through `requests`
+async fn receive_messages<R: SplitReceiver>(
+    rx: &mut R,
+    requests: &Signal<NoopRawMutex, LinkRequest>,
+    mut on_message: impl FnMut(Message),
+) {
+    let mut sequencer = Sequencer::default();
+
+    loop {
+        let received = if R::KEEPALIVE {
+            match select(rx.receive(), Timer::after_millis(SPLIT_LINK_TIMEOUT)).await {
+                Either::First(received) => received,
+                // not even a ping, the other half is gone
+                Either::Second(()) => return,
+            }
+        } else {
+            rx.receive().await
+        };
+
+        let frame = match received {
+            Some(Ok(frame)) => frame,
+            Some(Err(_e)) => {
+                #[cfg(feature = "defmt")]
+                error!("[split_link] frame dropped: {:?}", _e);
+                continue;
+            }
+            None => return,
+        };
+
+        let sequence = sequencer.receive(frame.seq);
+
+        #[cfg(feature = "defmt")]
+        info!("[split_link] received: {:?}, {:?}", frame, sequence);
+
+        if sequence == Sequence::Stale {
+            continue;
+        }
+
+        // frames were lost, the state of the other half may be off
+        if let Sequence::Gap(_) = sequence {
+            requests.signal(LinkRequest::RequestState);
+        }
+
+        match frame.message {
+            Message::Resync => requests.signal(LinkRequest::SendState),
+            Message::Ping => {}
+            message => on_message(message),
+        }
+    }
+}
+
+/// Apply a message of a half, the keys of the central half sit right of the peripheral ones in
+/// the keymap
+fn receive_half_message(message: Message, half_keys_local: &mut HeldKeys, half: Role) {
+    let offset = match half {
+        Role::Peripheral => 0,
+        Role::Central => COLS as u8,
+    };
+    let to_keymap = |pos: KeyPos| KeyPos {
+        row: pos.row,
+        col: pos.col + offset,
+    };
+
+    match message {
+        Message::Key { pos, pressed } => {
+            apply_key(half_keys_local, to_keymap(pos), pressed);
+            half_keys(half).sender().send(*half_keys_local);
+        }
+        Message::Keys(keys) => {
+            let keys = keys.map(|pos| {
+                if pos == KeyPos::default() {
+                    pos
+                } else {
+                    to_keymap(pos)
+                }
+            });
+            apply_keys(half_keys_local, &keys);
+            half_keys(half).sender().send(*half_keys_local);
+        }
+        Message::Battery(split_b_level) => receive_battery(half, split_b_level),
+        // only sent by the side running the keymap
+        _ => {}
+    }
+}
+
+/// Battery level of the central half, the peripheral reports the lower one of both halves
+#[cfg(not(feature = "receiver"))]
+fn receive_battery(_half: Role, split_b_level: u8) {
+    let battery_level_sender = BATTERY_LEVEL.sender();
+
+    if let Some(b_level) = battery_level_sender.try_get() {
+        // send only the lower value (either peripheral or central battery level)
+        if split_b_level < b_level {
+            battery_level_sender.send(split_b_level);
+        }
+
+        #[cfg(feature = "defmt")]
+        info!(
+            "[split_battery_level] central bat lvl: {:?}; peri bat lvl: {:?}",
+            split_b_level, b_level
+        );
+    }
+}
+
+/// Battery level of a half, the dongle reports the lower one of both halves
+#[cfg(feature = "receiver")]
+fn receive_battery(half: Role, b_level: u8) {
+    HALF_BATTERY_LEVELS[half.to_u8() as usize].store(b_level, Ordering::Relaxed);
+
+    let lowest = HALF_BATTERY_LEVELS
+        .iter()
+        .map(|level| level.load(Ordering::Relaxed))
+        .min()
+        .unwrap_or(b_level);
+    BATTERY_LEVEL.sender().send(lowest);
+
+    #[cfg(feature = "defmt")]
+    info!(
+        "[split_battery_level] {:?} bat lvl: {:?}; reported: {:?}",
+        half, b_level, lowest
+    );
+}
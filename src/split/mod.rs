@@ -0,0 +1,9 @@
+#[cfg(all(split_esb, not(test)))]
+pub mod esb;
+#[cfg(any(split_esb, test))]
+pub mod esb_bond;
+#[cfg(all(split, not(test)))]
+pub mod link;
+pub mod protocol;
+#[cfg(all(split_uart, not(test)))]
+pub mod uart;
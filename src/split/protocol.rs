@@ -0,0 +1,747 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+use crate::config::MATRIX_KEYS_BUFFER;
+use crate::leds::KeyboardLeds;
+use crate::matrix::{Key, KeyPos, KeyState};
+
+/// Version of the split protocol, both halves must run the same one
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// First byte of every frame, a byte stream resynchronizes on it after garbage
+pub const SYNC: u8 = 0xA5;
+
+/// Sync byte, version, message type, sequence number and payload length
+const HEADER_SIZE: usize = 5;
+
+/// Largest payload, the held keys of a full state: count followed by row and col of each key
+const PAYLOAD_MAX: usize = 1 + 2 * MATRIX_KEYS_BUFFER;
+
+/// Largest frame: header, payload and checksum
+pub const FRAME_MAX: usize = HEADER_SIZE + PAYLOAD_MAX + 1;
+
+// the payload length is a single byte
+const _: () = assert!(PAYLOAD_MAX <= u8::MAX as usize);
+
+const TYPE_KEY: u8 = 1;
+const TYPE_KEYS: u8 = 2;
+const TYPE_BATTERY: u8 = 3;
+const TYPE_LEDS: u8 = 4;
+const TYPE_LAYER: u8 = 5;
+const TYPE_PING: u8 = 6;
+const TYPE_RESYNC: u8 = 7;
+
+/// Keys held on a half, empty slots are `KeyPos::default()`
+pub type HeldKeys = [KeyPos; MATRIX_KEYS_BUFFER];
+
+/// Message exchanged between the halves
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Message {
+    /// A key of the sending half was pressed or released, position in the half's own matrix
+    Key { pos: KeyPos, pressed: bool },
+    /// Every key held on the sending half, part of the full state
+    Keys(HeldKeys),
+    /// Battery level of the sending half in percent
+    Battery(u8),
+    /// Lock LEDs set by the host
+    Leds(KeyboardLeds),
+    /// Highest active layer of the keymap, for the layer indicators of the other half
+    Layer(u8),
+    /// Keeps an idle link alive
+    Ping,
+    /// Asks the other half for its full state
+    Resync,
+}
+
+impl Message {
+    fn kind(&self) -> u8 {
+        match self {
+            Message::Key { .. } => TYPE_KEY,
+            Message::Keys(_) => TYPE_KEYS,
+            Message::Battery(_) => TYPE_BATTERY,
+            Message::Leds(_) => TYPE_LEDS,
+            Message::Layer(_) => TYPE_LAYER,
+            Message::Ping => TYPE_PING,
+            Message::Resync => TYPE_RESYNC,
+        }
+    }
+
+    /// Write the payload, returns its length
+    fn encode_payload(&self, payload: &mut [u8]) -> usize {
+        match self {
+            Message::Key { pos, pressed } => {
+                payload[..3].copy_from_slice(&[pos.row, pos.col, *pressed as u8]);
+                3
+            }
+            Message::Keys(keys) => {
+                let mut count = 0;
+                for pos in keys.iter().filter(|pos| **pos != KeyPos::default()) {
+                    payload[1 + 2 * count] = pos.row;
+                    payload[2 + 2 * count] = pos.col;
+                    count += 1;
+                }
+                payload[0] = count as u8;
+                1 + 2 * count
+            }
+            Message::Battery(level) => {
+                payload[0] = *level;
+                1
+            }
+            Message::Leds(leds) => {
+                payload[0] = leds.0;
+                1
+            }
+            Message::Layer(layer) => {
+                payload[0] = *layer;
+                1
+            }
+            Message::Ping | Message::Resync => 0,
+        }
+    }
+
+    fn decode(kind: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        match (kind, payload) {
+            (TYPE_KEY, [row, col, pressed]) => Ok(Message::Key {
+                pos: KeyPos {
+                    row: *row,
+                    col: *col,
+                },
+                pressed: *pressed != 0,
+            }),
+            (TYPE_KEYS, [count, positions @ ..])
+                if *count as usize <= MATRIX_KEYS_BUFFER
+                    && positions.len() == 2 * *count as usize =>
+            {
+                let mut keys = [KeyPos::default(); MATRIX_KEYS_BUFFER];
+                for (key, pos) in keys.iter_mut().zip(positions.chunks_exact(2)) {
+                    *key = KeyPos {
+                        row: pos[0],
+                        col: pos[1],
+                    };
+                }
+                Ok(Message::Keys(keys))
+            }
+            (TYPE_BATTERY, [level]) => Ok(Message::Battery(*level)),
+            (TYPE_LEDS, [leds]) => Ok(Message::Leds(KeyboardLeds(*leds))),
+            (TYPE_LAYER, [layer]) => Ok(Message::Layer(*layer)),
+            (TYPE_PING, []) => Ok(Message::Ping),
+            (TYPE_RESYNC, []) => Ok(Message::Resync),
+            (TYPE_KEY..=TYPE_RESYNC, _) => Err(ProtocolError::Payload),
+            _ => Err(ProtocolError::Type(kind)),
+        }
+    }
+}
+
+/// Reason a frame was dropped
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolError {
+    /// The frame does not start with the sync byte
+    Sync,
+    /// Fewer bytes than the header announces
+    Truncated,
+    /// The payload length exceeds the largest payload
+    Length,
+    /// The checksum does not match, the frame was corrupted
+    Checksum,
+    /// The other half runs another protocol version
+    Version(u8),
+    /// Unknown message type
+    Type(u8),
+    /// The payload does not match the message type
+    Payload,
+}
+
+/// Message with the sequence number it was sent with
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub seq: u8,
+    pub message: Message,
+}
+
+impl Frame {
+    /// Write the frame to `buf`, returns its length
+    pub fn encode(&self, buf: &mut [u8; FRAME_MAX]) -> usize {
+        let len = self
+            .message
+            .encode_payload(&mut buf[HEADER_SIZE..HEADER_SIZE + PAYLOAD_MAX]);
+        let end = HEADER_SIZE + len;
+
+        buf[..HEADER_SIZE].copy_from_slice(&[
+            SYNC,
+            PROTOCOL_VERSION,
+            self.message.kind(),
+            self.seq,
+            len as u8,
+        ]);
+        buf[end] = crc8(&buf[1..end]);
+
+        end + 1
+    }
+
+    /// Read the frame at the start of `bytes`, trailing bytes are ignored
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(ProtocolError::Truncated);
+        }
+        if bytes[0] != SYNC {
+            return Err(ProtocolError::Sync);
+        }
+
+        let len = bytes[4] as usize;
+        if len > PAYLOAD_MAX {
+            return Err(ProtocolError::Length);
+        }
+
+        let end = HEADER_SIZE + len;
+        if bytes.len() <= end {
+            return Err(ProtocolError::Truncated);
+        }
+        if crc8(&bytes[1..end]) != bytes[end] {
+            return Err(ProtocolError::Checksum);
+        }
+
+        // checked after the checksum so a corrupted frame is not taken for another version
+        if bytes[1] != PROTOCOL_VERSION {
+            return Err(ProtocolError::Version(bytes[1]));
+        }
+
+        Ok(Frame {
+            seq: bytes[3],
+            message: Message::decode(bytes[2], &bytes[HEADER_SIZE..end])?,
+        })
+    }
+}
+
+/// CRC-8 with the polynomial 0x07
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Cuts a byte stream into frames, bytes before a sync byte are skipped
+pub struct FrameReader {
+    buf: [u8; FRAME_MAX],
+    len: usize,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; FRAME_MAX],
+            len: 0,
+        }
+    }
+
+    /// Feed a received byte, returns the frame it completes
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame, ProtocolError>> {
+        if self.len == 0 && byte != SYNC {
+            return None;
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        if self.len < HEADER_SIZE {
+            return None;
+        }
+
+        let size = HEADER_SIZE + self.buf[4] as usize + 1;
+        if size > FRAME_MAX {
+            self.skip();
+            return Some(Err(ProtocolError::Length));
+        }
+        if self.len < size {
+            return None;
+        }
+
+        let frame = Frame::decode(&self.buf[..size]);
+        match frame {
+            Ok(_) => self.consume(size),
+            // the sync byte was part of the garbage, look for the next one
+            Err(_) => self.skip(),
+        }
+
+        Some(frame)
+    }
+
+    /// Drop a broken frame up to the next sync byte in the buffer
+    fn skip(&mut self) {
+        let next = self.buf[1..self.len]
+            .iter()
+            .position(|byte| *byte == SYNC)
+            .map_or(self.len, |index| index + 1);
+        self.consume(next);
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.buf.copy_within(count..self.len, 0);
+        self.len -= count;
+    }
+}
+
+/// Order of a received frame
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sequence {
+    /// The frame follows the previous one
+    InOrder,
+    /// The given number of frames before this one were lost, the state needs a resync
+    Gap(u8),
+    /// The frame is older than the previous one, reordered or repeated, and is dropped
+    Stale,
+}
+
+/// Sequence numbers of a link, one counter per direction, a new link starts with a new one
+#[derive(Default)]
+pub struct Sequencer {
+    next: u8,
+    expected: Option<u8>,
+}
+
+impl Sequencer {
+    /// Sequence number of the next sent frame
+    pub fn next_seq(&mut self) -> u8 {
+        let seq = self.next;
+        self.next = self.next.wrapping_add(1);
+        seq
+    }
+
+    /// Check the sequence number of a received frame, the first one is always in order
+    pub fn receive(&mut self, seq: u8) -> Sequence {
+        let Some(expected) = self.expected else {
+            self.expected = Some(seq.wrapping_add(1));
+            return Sequence::InOrder;
+        };
+
+        // numbers up to half the range ahead are taken as lost frames, the rest as old ones
+        let ahead = seq.wrapping_sub(expected);
+        if ahead >= 0x80 {
+            return Sequence::Stale;
+        }
+
+        self.expected = Some(seq.wrapping_add(1));
+        match ahead {
+            0 => Sequence::InOrder,
+            lost => Sequence::Gap(lost),
+        }
+    }
+}
+
+/// What the receiving side of a link asks its sending side to do
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkRequest {
+    /// Send the full state, the other half asked for it
+    SendState,
+    /// Ask the other half for its full state, frames were lost
+    RequestState,
+}
+
+/// Mark a key of the other half as held or released
+pub fn apply_key(keys: &mut HeldKeys, pos: KeyPos, pressed: bool) {
+    let held = keys.iter().position(|key| *key == pos);
+
+    match (held, pressed) {
+        (None, true) => {
+            if let Some(free) = keys.iter_mut().find(|key| **key == KeyPos::default()) {
+                *free = pos;
+            }
+        }
+        (Some(index), false) => keys[index] = KeyPos::default(),
+        _ => {}
+    }
+}
+
+/// Key events turning the held keys `old` into `new`, releases first
+pub fn key_changes<'a>(old: &'a HeldKeys, new: &'a HeldKeys) -> impl Iterator<Item = Message> + 'a {
+    let released = old
+        .iter()
+        .filter(|pos| **pos != KeyPos::default() && !new.contains(pos))
+        .map(|pos| Message::Key {
+            pos: *pos,
+            pressed: false,
+        });
+    let pressed = new
+        .iter()
+        .filter(|pos| **pos != KeyPos::default() && !old.contains(pos))
+        .map(|pos| Message::Key {
+            pos: *pos,
+            pressed: true,
+        });
+
+    released.chain(pressed)
+}
+
+/// Bring the held keys of the other half to a full report by position, whatever slots the
+/// sender used, so a key keeps its slot for as long as it is held
+pub fn apply_keys(keys: &mut HeldKeys, new: &HeldKeys) {
+    let old = *keys;
+    for change in key_changes(&old, new) {
+        if let Message::Key { pos, pressed } = change {
+            apply_key(keys, pos, pressed);
+        }
+    }
+}
+
+/// Bring the keys of the other half held by the keymap to the received ones by position:
+/// keys no longer received are released, newly received ones are pressed into a free slot
+pub fn sync_split_keys(
+    keys: &mut [Key],
+    received: &HeldKeys,
+    mut press: impl FnMut(&KeyPos) -> Key,
+) {
+    for key in keys
+        .iter_mut()
+        .filter(|key| key.position != KeyPos::default() && !received.contains(&key.position))
+    {
+        key.state = KeyState::Released;
+    }
+
+    for pos in received.iter().filter(|pos| **pos != KeyPos::default()) {
+        if keys.iter().any(|key| key.position == *pos) {
+            continue;
+        }
+        if let Some(free) = keys
+            .iter_mut()
+            .find(|key| key.position == KeyPos::default())
+        {
+            *free = press(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(row: u8, col: u8) -> KeyPos {
+        KeyPos { row, col }
+    }
+
+    fn held(keys: &[KeyPos]) -> HeldKeys {
+        let mut held = [KeyPos::default(); MATRIX_KEYS_BUFFER];
+        held[..keys.len()].copy_from_slice(keys);
+        held
+    }
+
+    fn encode(frame: Frame) -> ([u8; FRAME_MAX], usize) {
+        let mut buf = [0; FRAME_MAX];
+        let len = frame.encode(&mut buf);
+        (buf, len)
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let mut all_keys = [KeyPos::default(); MATRIX_KEYS_BUFFER];
+        for (index, key) in all_keys.iter_mut().enumerate() {
+            *key = KeyPos {
+                row: (index / 8) as u8,
+                col: (index % 8) as u8,
+            };
+        }
+
+        let messages = [
+            Message::Key {
+                pos: key(1, 2),
+                pressed: true,
+            },
+            Message::Key {
+                pos: key(3, 4),
+                pressed: false,
+            },
+            Message::Keys(held(&[])),
+            Message::Keys(held(&[key(0, 1), key(2, 3)])),
+            Message::Keys(all_keys),
+            Message::Battery(87),
+            Message::Leds(KeyboardLeds(0b101)),
+            Message::Layer(0),
+            Message::Layer(3),
+            Message::Ping,
+            Message::Resync,
+        ];
+
+        for (seq, message) in messages.into_iter().enumerate() {
+            let frame = Frame {
+                seq: seq as u8,
+                message,
+            };
+            let (buf, len) = encode(frame);
+
+            assert_eq!(Frame::decode(&buf[..len]), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn corrupted_frame_is_rejected() {
+        let (mut buf, len) = encode(Frame {
+            seq: 0,
+            message: Message::Battery(50),
+        });
+        buf[HEADER_SIZE] ^= 0x01;
+
+        assert_eq!(Frame::decode(&buf[..len]), Err(ProtocolError::Checksum));
+    }
+
+    #[test]
+    fn other_version_is_rejected() {
+        let (mut buf, len) = encode(Frame {
+            seq: 0,
+            message: Message::Ping,
+        });
+        buf[1] = PROTOCOL_VERSION + 1;
+        buf[len - 1] = crc8(&buf[1..len - 1]);
+
+        assert_eq!(
+            Frame::decode(&buf[..len]),
+            Err(ProtocolError::Version(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let (buf, len) = encode(Frame {
+            seq: 0,
+            message: Message::Battery(50),
+        });
+        assert_eq!(
+            Frame::decode(&buf[..len - 1]),
+            Err(ProtocolError::Truncated)
+        );
+        assert_eq!(
+            Frame::decode(&buf[..HEADER_SIZE - 1]),
+            Err(ProtocolError::Truncated)
+        );
+        assert_eq!(Frame::decode(&buf[1..len]), Err(ProtocolError::Sync));
+
+        let mut long = buf;
+        long[4] = PAYLOAD_MAX as u8 + 1;
+        assert_eq!(Frame::decode(&long[..len]), Err(ProtocolError::Length));
+
+        // a valid checksum over an unknown type or a payload of the wrong size
+        let unknown = [SYNC, PROTOCOL_VERSION, 0x7f, 0, 0];
+        let mut frame = [0; HEADER_SIZE + 1];
+        frame[..HEADER_SIZE].copy_from_slice(&unknown);
+        frame[HEADER_SIZE] = crc8(&frame[1..HEADER_SIZE]);
+        assert_eq!(Frame::decode(&frame), Err(ProtocolError::Type(0x7f)));
+
+        frame[2] = TYPE_BATTERY;
+        frame[HEADER_SIZE] = crc8(&frame[1..HEADER_SIZE]);
+        assert_eq!(Frame::decode(&frame), Err(ProtocolError::Payload));
+    }
+
+    /// Feed the bytes to the reader, returns the frames it completed
+    fn read(reader: &mut FrameReader, bytes: &[u8]) -> Vec<Frame> {
+        bytes
+            .iter()
+            .filter_map(|byte| reader.push(*byte))
+            .filter_map(Result::ok)
+            .collect()
+    }
+
+    #[test]
+    fn reader_resyncs_after_garbage() {
+        let frame = Frame {
+            seq: 3,
+            message: Message::Key {
+                pos: key(1, 1),
+                pressed: true,
+            },
+        };
+        let (buf, len) = encode(frame);
+
+        let mut reader = FrameReader::new();
+        assert_eq!(read(&mut reader, &[0x13, 0x00, SYNC, 0x42, 0x00]), []);
+        assert_eq!(read(&mut reader, &buf[..len]), [frame]);
+
+        // back to back frames after the garbage
+        assert_eq!(read(&mut reader, &buf[..len]), [frame]);
+    }
+
+    #[test]
+    fn reader_resyncs_after_truncated_frame() {
+        let first = Frame {
+            seq: 1,
+            message: Message::Key {
+                pos: key(2, 2),
+                pressed: true,
+            },
+        };
+        let second = Frame {
+            seq: 2,
+            message: Message::Key {
+                pos: key(2, 2),
+                pressed: false,
+            },
+        };
+        let (first_buf, first_len) = encode(first);
+        let (second_buf, second_len) = encode(second);
+
+        let mut reader = FrameReader::new();
+        assert_eq!(read(&mut reader, &first_buf[..first_len - 2]), []);
+        assert_eq!(read(&mut reader, &second_buf[..second_len]), [second]);
+    }
+
+    #[test]
+    fn sequencer_wraps_around() {
+        let mut sender = Sequencer::default();
+        let mut receiver = Sequencer::default();
+
+        for _ in 0..300 {
+            assert_eq!(receiver.receive(sender.next_seq()), Sequence::InOrder);
+        }
+    }
+
+    #[test]
+    fn sequencer_detects_gaps_and_stale_frames() {
+        let mut receiver = Sequencer::default();
+
+        // the first frame of a link is taken as it comes
+        assert_eq!(receiver.receive(250), Sequence::InOrder);
+        assert_eq!(receiver.receive(251), Sequence::InOrder);
+
+        // lost frames across the wrap-around
+        assert_eq!(receiver.receive(2), Sequence::Gap(6));
+        assert_eq!(receiver.receive(3), Sequence::InOrder);
+
+        // a repeated or reordered frame is dropped without moving the sequence
+        assert_eq!(receiver.receive(3), Sequence::Stale);
+        assert_eq!(receiver.receive(255), Sequence::Stale);
+        assert_eq!(receiver.receive(4), Sequence::InOrder);
+    }
+
+    #[test]
+    fn key_changes_release_first() {
+        let old = held(&[key(0, 0), key(0, 1)]);
+        let new = held(&[key(0, 1), key(1, 0)]);
+
+        let changes: Vec<Message> = key_changes(&old, &new).collect();
+        assert_eq!(
+            changes,
+            [
+                Message::Key {
+                    pos: key(0, 0),
+                    pressed: false,
+                },
+                Message::Key {
+                    pos: key(1, 0),
+                    pressed: true,
+                },
+            ]
+        );
+
+        assert_eq!(key_changes(&new, &new).count(), 0);
+    }
+
+    #[test]
+    fn key_changes_replay_onto_the_old_keys() {
+        let old = held(&[key(0, 0), key(0, 1), key(2, 2)]);
+        let new = held(&[key(2, 2), key(1, 0)]);
+
+        let mut keys = old;
+        for change in key_changes(&old, &new) {
+            if let Message::Key { pos, pressed } = change {
+                apply_key(&mut keys, pos, pressed);
+            }
+        }
+
+        let mut keys: Vec<KeyPos> = keys
+            .into_iter()
+            .filter(|pos| *pos != KeyPos::default())
+            .collect();
+        keys.sort_by_key(|pos| (pos.row, pos.col));
+        assert_eq!(keys, [key(1, 0), key(2, 2)]);
+    }
+
+    #[test]
+    fn apply_key_holds_each_key_once() {
+        let mut keys = held(&[]);
+
+        apply_key(&mut keys, key(1, 1), true);
+        apply_key(&mut keys, key(1, 1), true);
+        assert_eq!(keys, held(&[key(1, 1)]));
+
+        // releasing a key that is not held changes nothing
+        apply_key(&mut keys, key(2, 2), false);
+        assert_eq!(keys, held(&[key(1, 1)]));
+
+        apply_key(&mut keys, key(1, 1), false);
+        assert_eq!(keys, held(&[]));
+    }
+
+    #[test]
+    fn apply_keys_keeps_held_keys_in_their_slots() {
+        let mut keys = held(&[key(0, 0), key(0, 1)]);
+
+        // the sender moved the held key to another slot and released the first one
+        apply_keys(&mut keys, &held(&[key(1, 1), key(0, 1)]));
+        assert_eq!(keys, held(&[key(1, 1), key(0, 1)]));
+
+        apply_keys(&mut keys, &held(&[key(2, 2), key(0, 0), key(0, 1)]));
+        assert_eq!(keys, held(&[key(2, 2), key(0, 1), key(0, 0)]));
+
+        apply_keys(&mut keys, &held(&[]));
+        assert_eq!(keys, held(&[]));
+    }
+
+    fn pressed(pos: KeyPos) -> Key {
+        Key {
+            position: pos,
+            state: KeyState::Pressed,
+            ..Key::default()
+        }
+    }
+
+    #[test]
+    fn sync_split_keys_diffs_by_position() {
+        let mut keys = [Key::default(); 3];
+        keys[1] = pressed(key(0, 1));
+        keys[2] = pressed(key(0, 2));
+
+        // the held key arrives in another slot next to a new one
+        sync_split_keys(&mut keys, &held(&[key(0, 2), key(1, 1)]), |pos| {
+            pressed(*pos)
+        });
+
+        let keys = keys.map(|key| (key.position, key.state));
+        assert_eq!(
+            keys,
+            [
+                (key(1, 1), KeyState::Pressed),
+                (key(0, 1), KeyState::Released),
+                (key(0, 2), KeyState::Pressed),
+            ]
+        );
+    }
+
+    #[test]
+    fn sync_split_keys_releases_what_is_no_longer_received() {
+        let mut keys = [pressed(key(0, 0)), pressed(key(0, 1))];
+        let mut presses = 0;
+
+        sync_split_keys(&mut keys, &held(&[key(0, 1), key(0, 0)]), |pos| {
+            presses += 1;
+            pressed(*pos)
+        });
+        assert_eq!(presses, 0);
+        assert!(keys.iter().all(|key| key.state == KeyState::Pressed));
+
+        sync_split_keys(&mut keys, &held(&[]), |pos| pressed(*pos));
+        assert!(keys.iter().all(|key| key.state == KeyState::Released));
+    }
+}
@@ -0,0 +1,133 @@
+#[cfg(feature = "defmt")]
+use defmt::{error, warn};
+use embassy_nrf::buffered_uarte::{self, BufferedUarte, BufferedUarteRx, BufferedUarteTx};
+use embassy_nrf::uarte::{Baudrate, Config};
+use embassy_nrf::{Peri, bind_interrupts, gpio::AnyPin, peripherals::UARTE0};
+use static_cell::StaticCell;
+
+use crate::peripherals::SplitPeri;
+use crate::role::Role;
+use crate::split::link::{SplitReceiver, SplitSender, central_link, peripheral_link};
+use crate::split::protocol::{FRAME_MAX, Frame, FrameReader, ProtocolError};
+
+bind_interrupts!(struct Irqs {
+    UARTE0 => buffered_uarte::InterruptHandler<UARTE0>;
+});
+
+/// Size of the receive and the transmit buffers, a few frames each
+const UART_BUFFER_SIZE: usize = FRAME_MAX * 4;
+
+/// Sending side of the serial split transport
+struct UartSender {
+    tx: BufferedUarteTx<'static>,
+}
+
+impl SplitSender for UartSender {
+    async fn send(&mut self, mut frame: &[u8]) -> bool {
+        while !frame.is_empty() {
+            match self.tx.write(frame).await {
+                Ok(written) => frame = &frame[written..],
+                Err(_e) => {
+                    #[cfg(feature = "defmt")]
+                    error!("[split_uart] write error: {:?}", _e);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// Receiving side of the serial split transport
+struct UartReceiver {
+    rx: BufferedUarteRx<'static>,
+    reader: FrameReader,
+}
+
+impl SplitReceiver for UartReceiver {
+    // a serial line does not tell whether the other half is still there
+    const KEEPALIVE: bool = true;
+
+    async fn receive(&mut self) -> Option<Result<Frame, ProtocolError>> {
+        loop {
+            let received = match self.rx.fill_buf().await {
+                Ok(received) => received,
+                Err(_e) => {
+                    // the frame reader resynchronizes on the next sync byte
+                    #[cfg(feature = "defmt")]
+                    error!("[split_uart] read error: {:?}", _e);
+                    continue;
+                }
+            };
+
+            // bytes after a complete frame stay in the buffer for the next call
+            let mut used = 0;
+            let mut frame = None;
+            for byte in received {
+                used += 1;
+                frame = self.reader.push(*byte);
+                if frame.is_some() {
+                    break;
+                }
+            }
+            self.rx.consume(used);
+
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
+
+/// Run the split link over the serial cable, started over whenever the other half goes silent
+pub(crate) async fn run_uart_link(role: Role, split_peri: SplitPeri) {
+    let mut config = Config::default();
+    config.baudrate = Baudrate::BAUD1M;
+
+    let rx_buffer = {
+        static RX_BUFFER: StaticCell<[u8; UART_BUFFER_SIZE]> = StaticCell::new();
+        RX_BUFFER.init([0; UART_BUFFER_SIZE])
+    };
+    let tx_buffer = {
+        static TX_BUFFER: StaticCell<[u8; UART_BUFFER_SIZE]> = StaticCell::new();
+        TX_BUFFER.init([0; UART_BUFFER_SIZE])
+    };
+
+    // both halves share the pinout, the roles cross the data lines of the straight cable
+    let (rxd, txd): (Peri<'static, AnyPin>, Peri<'static, AnyPin>) = match role {
+        Role::Central => (split_peri.p0_06.into(), split_peri.p0_08.into()),
+        Role::Peripheral => (split_peri.p0_08.into(), split_peri.p0_06.into()),
+    };
+
+    let uarte = BufferedUarte::new(
+        split_peri.uarte0,
+        split_peri.timer1,
+        split_peri.ppi_ch0,
+        split_peri.ppi_ch1,
+        split_peri.ppi_group0,
+        rxd,
+        txd,
+        Irqs,
+        config,
+        rx_buffer,
+        tx_buffer,
+    );
+    let (rx, tx) = uarte.split();
+
+    let mut sender = UartSender { tx };
+    let mut receiver = UartReceiver {
+        rx,
+        reader: FrameReader::new(),
+    };
+
+    loop {
+        match role {
+            Role::Central => central_link(&mut sender, &mut receiver).await,
+            Role::Peripheral => peripheral_link(&mut sender, &mut receiver).await,
+        }
+
+        #[cfg(feature = "defmt")]
+        warn!("[split_uart] link lost, waiting for the other half");
+    }
+}
@@ -1,149 +1,622 @@
+use core::ops::Range;
 #[cfg(feature = "defmt")]
 use defmt::info;
 use embedded_storage_async::nor_flash::NorFlash;
 use sequential_storage::cache::NoCache;
-use sequential_storage::map::{Key, SerializationError, Value, fetch_all_items};
+use sequential_storage::map::{
+    Key, SerializationError, Value, fetch_all_items, fetch_item, remove_item, store_item,
+};
 use trouble_host::prelude::{BdAddr, SecurityLevel};
-use trouble_host::{BondInformation, Identity, LongTermKey};
+use trouble_host::{BondInformation, Identity, IdentityResolvingKey, LongTermKey};
+
+#[cfg(split_esb)]
+use crate::split::esb_bond::{DEVICE_ID_SIZE, EsbBond, KEY_SIZE};
 
 const NUM_OF_SECTORS: u32 = 8;
 
+/// Start address of the storage range
+const START_ADDR: u32 = 0xA0000;
+
+/// Flash range used for the key-value storage
+fn storage_range<S: NorFlash>() -> Range<u32> {
+    START_ADDR..(START_ADDR + NUM_OF_SECTORS * S::ERASE_SIZE as u32)
+}
+
+/// Version of the on-flash format, bumped whenever a stored record changes layout
+const STORAGE_VERSION: u8 = 1;
+
+/// Keys of the stored items
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct StoredAddr(BdAddr);
+enum StorageKey {
+    /// Bond of a host profile
+    Profile(u8),
+    /// Last used host profile
+    ActiveProfile,
+    /// Version of the on-flash format
+    Version,
+    /// Bond with the other half of the split keyboard, or with a half on the dongle
+    SplitBond(u8),
+    /// Address of the other half of the split keyboard, or of a half on the dongle
+    SplitPeer(u8),
+    /// Role of the half
+    Role,
+    /// Bond with the other half over the esb split link
+    EsbBond(u8),
+}
 
-impl Key for StoredAddr {
+impl Key for StorageKey {
     fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
-        if buffer.len() < 6 {
-            Err(SerializationError::BufferTooSmall)
-        } else {
-            buffer[0..6].copy_from_slice(self.0.raw());
-            Ok(6)
+        if buffer.len() < 2 {
+            return Err(SerializationError::BufferTooSmall);
         }
+        buffer[0..2].copy_from_slice(&match self {
+            StorageKey::Profile(profile) => [0x01, *profile],
+            StorageKey::ActiveProfile => [0x02, 0x00],
+            StorageKey::Version => [0x03, 0x00],
+            StorageKey::SplitBond(slot) => [0x04, *slot],
+            StorageKey::SplitPeer(slot) => [0x05, *slot],
+            StorageKey::Role => [0x06, 0x00],
+            StorageKey::EsbBond(slot) => [0x07, *slot],
+        });
+        Ok(2)
     }
+
     fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
-        if buffer.len() < 6 {
-            Err(SerializationError::BufferTooSmall)
-        } else {
-            Ok((
-                StoredAddr(BdAddr::new(
-                    buffer[0..6]
-                        .try_into()
-                        .expect("[deserialize_from] error deserializing buffer"),
-                )),
-                6,
-            ))
+        if buffer.len() < 2 {
+            return Err(SerializationError::BufferTooSmall);
         }
+        match buffer[0] {
+            0x01 => Ok((StorageKey::Profile(buffer[1]), 2)),
+            0x02 => Ok((StorageKey::ActiveProfile, 2)),
+            0x03 => Ok((StorageKey::Version, 2)),
+            0x04 => Ok((StorageKey::SplitBond(buffer[1]), 2)),
+            0x05 => Ok((StorageKey::SplitPeer(buffer[1]), 2)),
+            0x06 => Ok((StorageKey::Role, 2)),
+            0x07 => Ok((StorageKey::EsbBond(buffer[1]), 2)),
+            _ => Err(SerializationError::InvalidData),
+        }
+    }
+}
+
+fn security_level_to_u8(security_level: SecurityLevel) -> u8 {
+    match security_level {
+        SecurityLevel::NoEncryption => 0,
+        SecurityLevel::Encrypted => 1,
+        SecurityLevel::EncryptedAuthenticated => 2,
     }
 }
 
+fn security_level_from_u8(value: u8) -> Result<SecurityLevel, SerializationError> {
+    match value {
+        0 => Ok(SecurityLevel::NoEncryption),
+        1 => Ok(SecurityLevel::Encrypted),
+        2 => Ok(SecurityLevel::EncryptedAuthenticated),
+        _ => Err(SerializationError::InvalidData),
+    }
+}
+
+/// Size of a stored bond: version, address, ltk, security level, irk flag and irk
+const BOND_SIZE: usize = 1 + 6 + 16 + 1 + 1 + 16;
+
 struct StoredBondInformation {
+    bd_addr: BdAddr,
     ltk: LongTermKey,
     security_level: SecurityLevel,
+    irk: Option<IdentityResolvingKey>,
 }
 
 impl<'a> Value<'a> for StoredBondInformation {
     fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
-        if buffer.len() < 17 {
+        if buffer.len() < BOND_SIZE {
             return Err(SerializationError::BufferTooSmall);
         }
-        buffer[0..16].copy_from_slice(self.ltk.to_le_bytes().as_slice());
+        buffer[0] = STORAGE_VERSION;
+        buffer[1..7].copy_from_slice(self.bd_addr.raw());
+        buffer[7..23].copy_from_slice(self.ltk.to_le_bytes().as_slice());
+        buffer[23] = security_level_to_u8(self.security_level);
+
+        match self.irk {
+            Some(irk) => {
+                buffer[24] = 1;
+                buffer[25..41].copy_from_slice(irk.to_le_bytes().as_slice());
+            }
+            None => {
+                buffer[24] = 0;
+                buffer[25..41].fill(0);
+            }
+        }
+        Ok(BOND_SIZE)
+    }
 
-        buffer[16] = match self.security_level {
-            SecurityLevel::NoEncryption => 0,
-            SecurityLevel::Encrypted => 1,
-            SecurityLevel::EncryptedAuthenticated => 2,
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        // the exact size also rejects the records of other formats
+        if buffer.len() != BOND_SIZE || buffer[0] != STORAGE_VERSION {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let bd_addr = BdAddr::new(buffer[1..7].try_into().unwrap());
+        let ltk = LongTermKey::from_le_bytes(buffer[7..23].try_into().unwrap());
+        let security_level = security_level_from_u8(buffer[23])?;
+        let irk = match buffer[24] {
+            0 => None,
+            1 => Some(IdentityResolvingKey::from_le_bytes(
+                buffer[25..41].try_into().unwrap(),
+            )),
+            _ => return Err(SerializationError::InvalidData),
         };
-        Ok(17)
+        Ok(StoredBondInformation {
+            bd_addr,
+            ltk,
+            security_level,
+            irk,
+        })
+    }
+}
+
+/// Size of a stored esb bond: version, device id of the other half and link key
+#[cfg(split_esb)]
+const ESB_BOND_SIZE: usize = 1 + DEVICE_ID_SIZE + KEY_SIZE;
+
+#[cfg(split_esb)]
+impl<'a> Value<'a> for EsbBond {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < ESB_BOND_SIZE {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = STORAGE_VERSION;
+        buffer[1..1 + DEVICE_ID_SIZE].copy_from_slice(&self.peer);
+        buffer[1 + DEVICE_ID_SIZE..ESB_BOND_SIZE].copy_from_slice(&self.key);
+        Ok(ESB_BOND_SIZE)
     }
 
     fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
     where
         Self: Sized,
     {
-        if buffer.len() < 17 {
-            Err(SerializationError::BufferTooSmall)
-        } else {
-            let ltk = LongTermKey::from_le_bytes(buffer[0..16].try_into().unwrap());
-            let security_level = match buffer[16] {
-                0 => SecurityLevel::NoEncryption,
-                1 => SecurityLevel::Encrypted,
-                2 => SecurityLevel::EncryptedAuthenticated,
-                _ => return Err(SerializationError::InvalidData),
-            };
-            Ok(StoredBondInformation {
-                ltk,
-                security_level,
-            })
+        if buffer.len() != ESB_BOND_SIZE || buffer[0] != STORAGE_VERSION {
+            return Err(SerializationError::InvalidFormat);
         }
+        Ok(EsbBond {
+            peer: buffer[1..1 + DEVICE_ID_SIZE].try_into().unwrap(),
+            key: buffer[1 + DEVICE_ID_SIZE..].try_into().unwrap(),
+        })
     }
 }
 
-pub async fn store_bonding_info<S: NorFlash>(
+/// Key of the single bond record written before the versioned format: the host address
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LegacyAddr(BdAddr);
+
+impl Key for LegacyAddr {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < 6 {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0..6].copy_from_slice(self.0.raw());
+        Ok(6)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
+        if buffer.len() < 6 {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        Ok((LegacyAddr(BdAddr::new(buffer[0..6].try_into().unwrap())), 6))
+    }
+}
+
+/// Size of the single bond record written before the versioned format: ltk and security level
+const LEGACY_BOND_SIZE: usize = 16 + 1;
+
+struct LegacyBondInformation {
+    ltk: LongTermKey,
+    security_level: SecurityLevel,
+}
+
+impl<'a> Value<'a> for LegacyBondInformation {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < LEGACY_BOND_SIZE {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0..16].copy_from_slice(self.ltk.to_le_bytes().as_slice());
+        buffer[16] = security_level_to_u8(self.security_level);
+        Ok(LEGACY_BOND_SIZE)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() != LEGACY_BOND_SIZE {
+            return Err(SerializationError::InvalidFormat);
+        }
+        Ok(LegacyBondInformation {
+            ltk: LongTermKey::from_le_bytes(buffer[0..16].try_into().unwrap()),
+            security_level: security_level_from_u8(buffer[16])?,
+        })
+    }
+}
+
+/// Bring the storage to the current format, the bond of the single bond
+/// format becomes the bond of profile 0, anything unreadable is erased
+pub async fn migrate_storage<S: NorFlash>(
     storage: &mut S,
-    bond_informaton: &BondInformation,
 ) -> Result<(), sequential_storage::Error<S::Error>> {
-    // start address
-    let start_addr = 0xA0000_u32;
-    let storage_range = start_addr..(start_addr + NUM_OF_SECTORS * S::ERASE_SIZE as u32);
+    let mut buffer = [0; 64];
+
+    let version = fetch_item::<StorageKey, u8, _>(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::Version,
+    )
+    .await
+    .ok()
+    .flatten();
+
+    if version == Some(STORAGE_VERSION) {
+        return Ok(());
+    }
+
+    // the single bond format only ever stored one item
+    let mut legacy_bond = None;
+    let mut cache = NoCache::new();
+    if version.is_none()
+        && let Ok(mut iter) = fetch_all_items::<LegacyAddr, _, _>(
+            storage,
+            storage_range::<S>(),
+            &mut cache,
+            &mut buffer,
+        )
+        .await
+        && let Ok(Some((key, value))) = iter.next::<LegacyBondInformation>(&mut buffer).await
+    {
+        legacy_bond = Some(BondInformation {
+            ltk: value.ltk,
+            identity: Identity {
+                bd_addr: key.0,
+                irk: None,
+            },
+            is_bonded: true,
+            security_level: value.security_level,
+        });
+    }
 
     #[cfg(feature = "defmt")]
     info!(
-        "[store_bonding_info] storage: {}kb, start_address: {}, storage_range: {}",
-        storage.capacity(),
-        start_addr,
-        storage_range,
+        "[migrate_storage] from version {:?}, legacy bond found: {}",
+        version,
+        legacy_bond.is_some()
     );
 
-    sequential_storage::erase_all(storage, storage_range.clone()).await?;
+    clear_storage(storage).await?;
 
-    #[cfg(feature = "defmt")]
-    info!("[store_bonding_info] erased");
+    if let Some(bond) = legacy_bond {
+        store_bonding_info(storage, 0, &bond).await?;
+    }
+
+    Ok(())
+}
 
-    let mut buffer = [0; 32];
-    let key = StoredAddr(bond_informaton.identity.bd_addr);
+/// Store a bond under the given key, replacing the previous one
+async fn store_bond<S: NorFlash>(
+    storage: &mut S,
+    key: &StorageKey,
+    bond_informaton: &BondInformation,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 64];
     let value = StoredBondInformation {
+        bd_addr: bond_informaton.identity.bd_addr,
         ltk: bond_informaton.ltk,
         security_level: bond_informaton.security_level,
+        irk: bond_informaton.identity.irk,
     };
 
-    sequential_storage::map::store_item(
+    store_item(
         storage,
-        storage_range,
+        storage_range::<S>(),
         &mut NoCache::new(),
         &mut buffer,
-        &key,
+        key,
         &value,
     )
+    .await
+}
+
+/// Load the bond stored under the given key
+async fn load_bond<S: NorFlash>(storage: &mut S, key: &StorageKey) -> Option<BondInformation> {
+    let mut buffer = [0; 64];
+
+    let value = fetch_item::<StorageKey, StoredBondInformation, _>(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        key,
+    )
+    .await
+    .ok()??;
+
+    Some(BondInformation {
+        ltk: value.ltk,
+        identity: Identity {
+            bd_addr: value.bd_addr,
+            irk: value.irk,
+        },
+        is_bonded: true,
+        security_level: value.security_level,
+    })
+}
+
+/// Store the bond of a host profile, replacing the previous one
+pub async fn store_bonding_info<S: NorFlash>(
+    storage: &mut S,
+    profile: u8,
+    bond_informaton: &BondInformation,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    store_bond(storage, &StorageKey::Profile(profile), bond_informaton).await?;
+
+    #[cfg(feature = "defmt")]
+    info!("[store_bonding_info] stored bond of profile {}", profile);
+
+    Ok(())
+}
+
+/// Load the bond of a host profile
+pub async fn load_bonding_info<S: NorFlash>(
+    storage: &mut S,
+    profile: u8,
+) -> Option<BondInformation> {
+    load_bond(storage, &StorageKey::Profile(profile)).await
+}
+
+/// Store the bond with the other half of the split keyboard, the dongle keeps one per half
+/// in the slot of its role
+pub async fn store_split_bond<S: NorFlash>(
+    storage: &mut S,
+    slot: u8,
+    bond_informaton: &BondInformation,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    store_bond(storage, &StorageKey::SplitBond(slot), bond_informaton).await?;
+
+    #[cfg(feature = "defmt")]
+    info!(
+        "[store_split_bond] stored bond of the split half in slot {}",
+        slot
+    );
+
+    Ok(())
+}
+
+/// Load the bond with the other half of the split keyboard
+pub async fn load_split_bond<S: NorFlash>(storage: &mut S, slot: u8) -> Option<BondInformation> {
+    load_bond(storage, &StorageKey::SplitBond(slot)).await
+}
+
+/// Store the address of the other half found in pairing mode
+pub async fn store_split_peer<S: NorFlash>(
+    storage: &mut S,
+    slot: u8,
+    addr: &BdAddr,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 64];
+
+    store_item(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::SplitPeer(slot),
+        &addr.raw(),
+    )
+    .await
+}
+
+/// Load the address of the other half
+pub async fn load_split_peer<S: NorFlash>(storage: &mut S, slot: u8) -> Option<BdAddr> {
+    let mut buffer = [0; 64];
+
+    let value = fetch_item::<StorageKey, &[u8], _>(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::SplitPeer(slot),
+    )
+    .await
+    .ok()??;
+
+    Some(BdAddr::new(value.try_into().ok()?))
+}
+
+/// Store the bond with the other half paired over the esb split link
+#[cfg(split_esb)]
+pub async fn store_esb_bond<S: NorFlash>(
+    storage: &mut S,
+    slot: u8,
+    bond: &EsbBond,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 64];
+
+    store_item(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::EsbBond(slot),
+        bond,
+    )
     .await?;
 
     #[cfg(feature = "defmt")]
-    info!("[store_bonding_info] stored key-value");
+    info!("[store_esb_bond] stored esb bond in slot {}", slot);
 
     Ok(())
 }
 
-pub async fn load_bonding_info<S: NorFlash>(storage: &mut S) -> Option<BondInformation> {
-    let start_addr = 0xA0000_u32;
-    let storage_range = start_addr..(start_addr + NUM_OF_SECTORS * S::ERASE_SIZE as u32);
+/// Load the bond with the other half paired over the esb split link
+#[cfg(split_esb)]
+pub async fn load_esb_bond<S: NorFlash>(storage: &mut S, slot: u8) -> Option<EsbBond> {
+    let mut buffer = [0; 64];
 
-    let mut buffer = [0; 32];
-    let mut cache = NoCache::new();
+    fetch_item::<StorageKey, EsbBond, _>(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::EsbBond(slot),
+    )
+    .await
+    .ok()?
+}
 
-    let mut iter =
-        fetch_all_items::<StoredAddr, _, _>(storage, storage_range, &mut cache, &mut buffer)
-            .await
-            .ok()?;
+/// Forget the other half: its address and the bonds with it
+pub async fn forget_split_peer<S: NorFlash>(
+    storage: &mut S,
+    slot: u8,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 64];
 
-    if let Some((key, value)) = iter.next::<StoredBondInformation>(&mut buffer).await.ok()? {
-        return Some(BondInformation {
-            ltk: value.ltk,
-            identity: Identity {
-                bd_addr: key.0,
-                irk: None,
-            },
-            is_bonded: true,
-            security_level: value.security_level,
-        });
+    for key in [
+        StorageKey::SplitPeer(slot),
+        StorageKey::SplitBond(slot),
+        StorageKey::EsbBond(slot),
+    ] {
+        remove_item(
+            storage,
+            storage_range::<S>(),
+            &mut NoCache::new(),
+            &mut buffer,
+            &key,
+        )
+        .await?;
     }
-    None
+
+    #[cfg(feature = "defmt")]
+    info!("[forget_split_peer] forgot the split half of slot {}", slot);
+
+    Ok(())
+}
+
+/// Remove the bond of a host profile
+pub async fn remove_bonding_info<S: NorFlash>(
+    storage: &mut S,
+    profile: u8,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 64];
+
+    remove_item(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::Profile(profile),
+    )
+    .await
+}
+
+/// Erase every stored item, only the format version is written back
+pub async fn clear_storage<S: NorFlash>(
+    storage: &mut S,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    #[cfg(feature = "defmt")]
+    info!(
+        "[clear_storage] storage: {}kb, storage_range: {}",
+        storage.capacity(),
+        storage_range::<S>(),
+    );
+
+    sequential_storage::erase_all(storage, storage_range::<S>()).await?;
+
+    let mut buffer = [0; 64];
+
+    store_item(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::Version,
+        &STORAGE_VERSION,
+    )
+    .await
+}
+
+/// Erase every stored item and restart, the half starts over as on its first power-up
+pub async fn factory_reset<S: NorFlash>(storage: &mut S) -> ! {
+    #[cfg(feature = "defmt")]
+    info!("[storage] factory reset");
+
+    clear_storage(storage)
+        .await
+        .expect("[storage] error erasing storage");
+
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// Remember the last used host profile
+pub async fn store_active_profile<S: NorFlash>(
+    storage: &mut S,
+    profile: u8,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 64];
+
+    store_item(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::ActiveProfile,
+        &profile,
+    )
+    .await
+}
+
+/// Load the last used host profile
+pub async fn load_active_profile<S: NorFlash>(storage: &mut S) -> Option<u8> {
+    let mut buffer = [0; 64];
+
+    fetch_item::<StorageKey, u8, _>(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::ActiveProfile,
+    )
+    .await
+    .ok()?
+}
+
+/// Remember the role of the half selected at power-up
+pub async fn store_role<S: NorFlash>(
+    storage: &mut S,
+    role: u8,
+) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut buffer = [0; 64];
+
+    store_item(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::Role,
+        &role,
+    )
+    .await
+}
+
+/// Load the role of the half
+pub async fn load_role<S: NorFlash>(storage: &mut S) -> Option<u8> {
+    let mut buffer = [0; 64];
+
+    fetch_item::<StorageKey, u8, _>(
+        storage,
+        storage_range::<S>(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &StorageKey::Role,
+    )
+    .await
+    .ok()?
 }
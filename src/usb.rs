@@ -0,0 +1,347 @@
+#[cfg(feature = "defmt")]
+use defmt::{info, warn};
+#[cfg(feature = "console")]
+use embassy_futures::join::join;
+use embassy_futures::join::join4;
+use embassy_futures::select::{Either3, select3};
+use embassy_nrf::pac::POWER;
+use embassy_nrf::usb::{self, Driver, vbus_detect::SoftwareVbusDetect};
+use embassy_nrf::{Peri, bind_interrupts, peripherals::USBD};
+use embassy_time::Timer;
+use embassy_usb::class::hid::{
+    self, HidBootProtocol, HidReaderWriter, HidSubclass, HidWriter, ReportId, RequestHandler, State,
+};
+use embassy_usb::control::OutResponse;
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, Config, Handler};
+use nrf_mpsl::raw::{mpsl_clock_hfclk_release, mpsl_clock_hfclk_request};
+use ssmarshal::serialize;
+use static_cell::StaticCell;
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::ble::services::{
+    CONSUMER_REPORT_ID, KEYBOARD_REPORT_ID, MOUSE_REPORT_ID, NKRO_REPORT_ID, REPORT_MAP,
+};
+use crate::config::{USB_VBUS_POLL_INTERVAL, USB_VID};
+#[cfg(feature = "console")]
+use crate::console::{console_class, console_run};
+use crate::leds::KeyboardLeds;
+use crate::mouse::MouseReport;
+use crate::nkro::{NKRO_REPORT_SIZE, NkroReport};
+use crate::output::{Output, active_output};
+use crate::{
+    CONSUMER_REPORT, KEY_REPORT, KEYBOARD_LEDS, MOUSE_REPORT, NAME, NKRO, NKRO_ENABLED,
+    NKRO_REPORT, ROLE, USB_PID, USB_UP,
+};
+
+bind_interrupts!(struct Irqs {
+    USBD => usb::InterruptHandler<USBD>;
+});
+
+/// Usb driver, the mpsl owns the power interrupt so the usb power is polled and reported by
+/// hand
+pub(crate) type UsbDriver = Driver<'static, &'static SoftwareVbusDetect>;
+
+/// Size of the hid endpoints, the n-key rollover report is the largest one
+const HID_PACKET_SIZE: usize = 64;
+
+/// Run the usb hid keyboard, next to the ble one, on the side running the keymap
+pub async fn usb_run(usbd: Peri<'static, USBD>) {
+    // the mpsl the usb clock is requested from is up once ble has picked the role
+    let role = ROLE
+        .receiver()
+        .expect("[usb] unable to create role receiver")
+        .get()
+        .await;
+
+    // only the side running the keymap sends reports to the hosts
+    if !role.runs_keymap() {
+        return;
+    }
+
+    let vbus: &'static SoftwareVbusDetect = {
+        static VBUS: StaticCell<SoftwareVbusDetect> = StaticCell::new();
+        VBUS.init(SoftwareVbusDetect::new(false, false))
+    };
+    let driver = Driver::new(usbd, Irqs, vbus);
+
+    let mut config = Config::new(USB_VID, USB_PID);
+    config.manufacturer = Some("nrf_rustboard");
+    config.product = Some(NAME);
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    let mut builder = {
+        static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static MSOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+        Builder::new(
+            driver,
+            config,
+            CONFIG_DESCRIPTOR.init([0; 256]),
+            BOS_DESCRIPTOR.init([0; 256]),
+            MSOS_DESCRIPTOR.init([0; 256]),
+            CONTROL_BUF.init([0; 64]),
+        )
+    };
+
+    // lock leds may come through the control pipe or the output endpoint
+    let control_leds = {
+        static CONTROL_LEDS: StaticCell<LedsHandler> = StaticCell::new();
+        CONTROL_LEDS.init(LedsHandler)
+    };
+    let mut endpoint_leds = LedsHandler;
+
+    let hid_config = hid::Config {
+        report_descriptor: &REPORT_MAP,
+        request_handler: Some(control_leds),
+        poll_ms: 1,
+        max_packet_size: HID_PACKET_SIZE as u16,
+        // the reports carry report ids, the boot protocol is left to the ble hid service
+        hid_subclass: HidSubclass::No,
+        hid_boot_protocol: HidBootProtocol::None,
+    };
+
+    let state = {
+        static STATE: StaticCell<State> = StaticCell::new();
+        STATE.init(State::new())
+    };
+    let hid = HidReaderWriter::<_, 8, HID_PACKET_SIZE>::new(&mut builder, state, hid_config);
+
+    let usb_state = {
+        static USB_STATE: StaticCell<UsbState> = StaticCell::new();
+        USB_STATE.init(UsbState::default())
+    };
+    builder.handler(usb_state);
+
+    #[cfg(feature = "console")]
+    let console = console_class(&mut builder);
+
+    let mut usb = builder.build();
+    let (reader, mut writer) = hid.split();
+
+    let hid = join4(
+        usb.run(),
+        vbus_task(vbus),
+        reader.run(true, &mut endpoint_leds),
+        hid_report_task(&mut writer),
+    );
+
+    #[cfg(feature = "console")]
+    join(hid, console_run(console)).await;
+    #[cfg(not(feature = "console"))]
+    hid.await;
+}
+
+/// Report the usb power to the driver, the usb runs from the high frequency crystal which is
+/// only kept running while the usb is powered
+async fn vbus_task(vbus: &SoftwareVbusDetect) {
+    let mut detected = false;
+    let mut ready = false;
+
+    loop {
+        let status = POWER.usbregstatus().read();
+
+        if status.vbusdetect() != detected {
+            detected = status.vbusdetect();
+            ready = false;
+
+            #[cfg(feature = "defmt")]
+            info!("[usb] vbus detected: {}", detected);
+
+            unsafe {
+                if detected {
+                    mpsl_clock_hfclk_request(None);
+                } else {
+                    mpsl_clock_hfclk_release();
+                }
+            }
+            vbus.detected(detected);
+        }
+
+        if detected && !ready && status.outputrdy() {
+            ready = true;
+            vbus.ready();
+        }
+
+        Timer::after_millis(USB_VBUS_POLL_INTERVAL).await;
+    }
+}
+
+/// State of the usb device, the reports go out over usb while a host has it configured and
+/// awake
+#[derive(Default)]
+struct UsbState {
+    configured: bool,
+    suspended: bool,
+}
+
+impl UsbState {
+    fn update(&self) {
+        let usb_up = self.configured && !self.suspended;
+        let usb_up_sender = USB_UP.sender();
+        if usb_up_sender.try_get() != Some(usb_up) {
+            #[cfg(feature = "defmt")]
+            info!("[usb] host connected: {}", usb_up);
+
+            usb_up_sender.send(usb_up);
+        }
+    }
+}
+
+impl Handler for UsbState {
+    fn enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.configured = false;
+            self.update();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.configured = false;
+        self.update();
+    }
+
+    fn configured(&mut self, configured: bool) {
+        self.configured = configured;
+        self.update();
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        self.suspended = suspended;
+        self.update();
+    }
+}
+
+/// Lock leds set by the host through the keyboard output report
+struct LedsHandler;
+
+impl RequestHandler for LedsHandler {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        if let (ReportId::Out(KEYBOARD_REPORT_ID), Some(&leds)) = (id, data.first()) {
+            let leds = KeyboardLeds(leds);
+            let keyboard_leds_sender = KEYBOARD_LEDS.sender();
+            if keyboard_leds_sender.try_get() != Some(leds) {
+                keyboard_leds_sender.send(leds);
+            }
+        }
+        OutResponse::Accepted
+    }
+}
+
+/// Write the keyboard, consumer and mouse reports to the usb host
+async fn hid_report_task(writer: &mut HidWriter<'static, UsbDriver, HID_PACKET_SIZE>) {
+    let mut key_report = KEY_REPORT
+        .receiver()
+        .expect(" [usb] maximum number of receivers has been reached");
+    let mut nkro_report = NKRO_REPORT
+        .receiver()
+        .expect(" [usb] maximum number of receivers has been reached");
+    let mut nkro_enabled = NKRO_ENABLED
+        .receiver()
+        .expect(" [usb] maximum number of receivers has been reached");
+    let mut consumer_report = CONSUMER_REPORT
+        .receiver()
+        .expect(" [usb] maximum number of receivers has been reached");
+    let mut mouse_report = MOUSE_REPORT
+        .receiver()
+        .expect(" [usb] maximum number of receivers has been reached");
+
+    let mut nkro_used = NKRO;
+    let mut usb_used = false;
+
+    loop {
+        let changed = select3(
+            key_report.changed(),
+            consumer_report.changed(),
+            mouse_report.changed(),
+        )
+        .await;
+
+        // the reports go out over ble, the keys held over usb are released once
+        if active_output() != Output::Usb {
+            if usb_used {
+                let _ = release_reports(writer, nkro_used).await;
+                usb_used = false;
+            }
+            continue;
+        }
+        usb_used = true;
+
+        let written = match changed {
+            Either3::First(key_report) => {
+                let nkro = nkro_enabled.try_get().unwrap_or(NKRO);
+
+                // release the keys held in the report which is no longer used
+                if nkro != nkro_used {
+                    #[cfg(feature = "defmt")]
+                    info!("[usb] keyboard report changed");
+
+                    let _ = write_keyboard(
+                        writer,
+                        nkro_used,
+                        &KeyboardReport::default(),
+                        &NkroReport::default(),
+                    )
+                    .await;
+                    nkro_used = nkro;
+                }
+
+                let nkro_report = nkro_report.try_get().unwrap_or_default();
+                write_keyboard(writer, nkro, &key_report, &nkro_report).await
+            }
+            Either3::Second(usage) => {
+                write_report(writer, CONSUMER_REPORT_ID, &usage.to_le_bytes()).await
+            }
+            Either3::Third(report) => {
+                write_report(writer, MOUSE_REPORT_ID, &report.to_bytes()).await
+            }
+        };
+
+        // reports are dropped while the host is not listening, the next one carries the state
+        if let Err(_e) = written {
+            #[cfg(feature = "defmt")]
+            warn!("[usb] report error: {:?}", _e);
+        }
+    }
+}
+
+/// Release the keys, the media key and the mouse buttons held over usb
+async fn release_reports(
+    writer: &mut HidWriter<'static, UsbDriver, HID_PACKET_SIZE>,
+    nkro: bool,
+) -> Result<(), EndpointError> {
+    let key_report = KeyboardReport::default();
+    write_keyboard(writer, nkro, &key_report, &NkroReport::default()).await?;
+    write_report(writer, CONSUMER_REPORT_ID, &0u16.to_le_bytes()).await?;
+    write_report(writer, MOUSE_REPORT_ID, &MouseReport::default().to_bytes()).await
+}
+
+/// Write the 6 keys or the n-key rollover keyboard report
+async fn write_keyboard(
+    writer: &mut HidWriter<'static, UsbDriver, HID_PACKET_SIZE>,
+    nkro: bool,
+    key_report: &KeyboardReport,
+    nkro_report: &NkroReport,
+) -> Result<(), EndpointError> {
+    if nkro {
+        write_report(writer, NKRO_REPORT_ID, &nkro_report.to_bytes()).await
+    } else {
+        let mut buff = [0u8; 8];
+        let _n = serialize(&mut buff, key_report).unwrap();
+        write_report(writer, KEYBOARD_REPORT_ID, &buff).await
+    }
+}
+
+/// Write an input report, preceded by its report id
+async fn write_report(
+    writer: &mut HidWriter<'static, UsbDriver, HID_PACKET_SIZE>,
+    id: u8,
+    report: &[u8],
+) -> Result<(), EndpointError> {
+    let mut buff = [0u8; 1 + NKRO_REPORT_SIZE];
+    buff[0] = id;
+    buff[1..=report.len()].copy_from_slice(report);
+    writer.write(&buff[..=report.len()]).await
+}